@@ -1,9 +1,12 @@
 use anstyle::{AnsiColor, Color::Ansi, Style};
 use clap::builder::styling::Styles;
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
+use serde::Deserialize;
 
-use std::io::{Error, ErrorKind};
 use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::{util, Error};
 
 const CMD_STYLE: Style = Style::new()
     .bold()
@@ -62,6 +65,107 @@ Print the graveyard path
 
 {header}Usage{rheader}: {rip_s}rip graveyard{rrip_s} [{place}OPTIONS{rplace}]
 
+{header}Options{rheader}:
+{OPTIONS_PLACEHOLDER}
+"
+        ),
+        "config" => format!(
+            "\
+Inspect the configuration
+
+{header}Usage{rheader}: {rip_s}rip config{rrip_s} <{place}COMMAND{rplace}>
+
+{header}Commands{rheader}:
+{SUBCOMMANDS_PLACEHOLDER}
+"
+        ),
+        "record" => format!(
+            "\
+Maintain the record of graves
+
+{header}Usage{rheader}: {rip_s}rip record{rrip_s} <{place}COMMAND{rplace}>
+
+{header}Commands{rheader}:
+{SUBCOMMANDS_PLACEHOLDER}
+"
+        ),
+        "record compact" => format!(
+            "\
+Drop unburied and purged graves from the record
+
+{header}Usage{rheader}: {rip_s}rip record compact{rrip_s}
+
+{header}Options{rheader}:
+{OPTIONS_PLACEHOLDER}
+"
+        ),
+        "clean" => format!(
+            "\
+Bury the untracked and ignored files in a git repository, as one batch
+that {rip_s}rip -u{rrip_s} restores
+
+{header}Usage{rheader}: {rip_s}rip clean{rrip_s} [{place}OPTIONS{rplace}] [{place}DIR{rplace}]
+
+{header}Arguments{rheader}:
+    [{place}DIR{rplace}]  The directory to clean [default: .]
+
+{header}Options{rheader}:
+{OPTIONS_PLACEHOLDER}
+"
+        ),
+        "sweep" => format!(
+            "\
+Bury the files that match every given criterion, and empty directories, as
+one batch that {rip_s}rip -u{rrip_s} restores
+
+{header}Usage{rheader}: {rip_s}rip sweep{rrip_s} [{place}OPTIONS{rplace}] [{place}DIR{rplace}]
+
+{header}Arguments{rheader}:
+    [{place}DIR{rplace}]  The directory to sweep [default: .]
+
+{header}Options{rheader}:
+{OPTIONS_PLACEHOLDER}
+"
+        ),
+        "cat" => format!(
+            "\
+Print a buried file, whether or not its grave is compressed
+
+{header}Usage{rheader}: {rip_s}rip cat{rrip_s} [{place}OPTIONS{rplace}] <{place}PATH{rplace}>
+
+{header}Arguments{rheader}:
+    <{place}PATH{rplace}>  Where the file was buried from, or its grave
+
+{header}Options{rheader}:
+{OPTIONS_PLACEHOLDER}
+"
+        ),
+        "log" => format!(
+            "\
+Print everything that was buried, unburied or purged, oldest first
+
+{header}Usage{rheader}: {rip_s}rip log{rrip_s} [{place}OPTIONS{rplace}]
+
+{header}Options{rheader}:
+{OPTIONS_PLACEHOLDER}
+"
+        ),
+        "fsck" => format!(
+            "\
+Check the record against the contents of the graveyard
+
+{header}Usage{rheader}: {rip_s}rip fsck{rrip_s} [{place}OPTIONS{rplace}]
+
+{header}Options{rheader}:
+{OPTIONS_PLACEHOLDER}
+"
+        ),
+        "config show" => format!(
+            "\
+Print the effective configuration and where each value came from
+
+{header}Usage{rheader}: {rip_s}rip config show{rrip_s}
+
 {header}Options{rheader}:
 {OPTIONS_PLACEHOLDER}
 "
@@ -107,10 +211,116 @@ pub struct Args {
     #[arg(short, long)]
     pub inspect: bool,
 
+    /// Size in bytes above which a target
+    /// that must be copied counts as big
+    /// [default: 500000000]
+    #[arg(long, value_name = "BYTES")]
+    pub big_file_threshold: Option<u64>,
+
+    /// What to do with big targets that
+    /// must be copied [default: prompt]
+    #[arg(long, value_enum, value_name = "POLICY")]
+    pub big_file_policy: Option<BigFilePolicy>,
+
+    /// Allow removing protected paths such
+    /// as / or your home directory
+    #[arg(long)]
+    pub force_protected: bool,
+
+    /// Put every target back if any of
+    /// them can't be removed
+    #[arg(long)]
+    pub atomic: bool,
+
+    /// Refuse to remove directories that
+    /// contain other file systems
+    #[arg(long)]
+    pub one_file_system: bool,
+
+    /// Print every target as it is buried
+    #[arg(short, long)]
+    pub verbose: bool,
+
+    /// How many threads copy directories
+    /// across devices [default: CPUs, up to 8]
+    #[arg(short, long, value_name = "N")]
+    pub jobs: Option<usize>,
+
+    /// Don't show progress while copying
+    #[arg(short, long)]
+    pub quiet: bool,
+
+    /// Leave paths matching GLOB in place,
+    /// even inside targets
+    #[arg(long, value_name = "GLOB")]
+    pub exclude: Vec<String>,
+
+    /// Bury paths matching GLOB, even if
+    /// excluded, and nothing else if no
+    /// --exclude is given
+    #[arg(long, value_name = "GLOB")]
+    pub include: Vec<String>,
+
+    /// Also read targets from stdin,
+    /// one per line
+    #[arg(long, conflicts_with = "files_from")]
+    pub stdin: bool,
+
+    /// Also read targets from FILE, one
+    /// per line (- for stdin)
+    #[arg(long, value_name = "FILE")]
+    pub files_from: Option<PathBuf>,
+
+    /// Separate targets read with --stdin
+    /// or --files-from by NUL instead
+    #[arg(short = '0', long)]
+    pub null: bool,
+
+    /// Take rm's options instead of rip's
+    /// (implied when rip is run as rm)
+    // The command line is handed to `rm::parse` before clap sees this
+    #[arg(long)]
+    pub rm_compat: bool,
+
+    /// How to print output [default: text]
+    #[arg(long, value_enum, value_name = "FORMAT")]
+    pub format: Option<OutputFormat>,
+
+    /// Bury all targets as one batch, which `rip -u` restores together
+    #[arg(skip)]
+    pub batch: bool,
+
     #[command(subcommand)]
     pub command: Option<Commands>,
 }
 
+/// What to do with a target that is above the big file threshold
+/// and can't simply be renamed into the graveyard
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum BigFilePolicy {
+    /// Ask whether to permanently delete it instead
+    #[default]
+    Prompt,
+    /// Copy it into the graveyard anyway
+    AlwaysCopy,
+    /// Permanently delete it instead of copying
+    AlwaysDelete,
+    /// Leave it in place and report an error
+    Refuse,
+}
+
+/// How events and listings are printed
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputFormat {
+    /// Human-readable text
+    #[default]
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
 #[derive(Subcommand, Debug)]
 pub enum Commands {
     /// Generate shell completions file
@@ -129,6 +339,112 @@ pub enum Commands {
         #[arg(short, long)]
         seance: bool,
     },
+
+    /// Inspect the configuration
+    #[command(styles=STYLES, help_template=help_template("config"))]
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+
+    /// Bury the files git doesn't track
+    #[command(styles=STYLES, help_template=help_template("clean"))]
+    Clean {
+        /// The directory to clean [default: .]
+        #[arg(value_name = "DIR")]
+        dir: Option<PathBuf>,
+
+        /// Only bury ignored files
+        /// (like git clean -X)
+        #[arg(short = 'X', long, conflicts_with = "untracked")]
+        ignored: bool,
+
+        /// Only bury untracked files that
+        /// aren't ignored (like git clean -d)
+        #[arg(long)]
+        untracked: bool,
+
+        /// Print what would be buried
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+    },
+
+    /// Bury stale files and empty directories
+    #[command(styles=STYLES, help_template=help_template("sweep"))]
+    Sweep {
+        /// The directory to sweep [default: .]
+        #[arg(value_name = "DIR")]
+        dir: Option<PathBuf>,
+
+        /// Bury empty directories, including
+        /// those left empty by the sweep,
+        /// however old or new they are
+        #[arg(long)]
+        empty_dirs: bool,
+
+        /// Only bury files last modified
+        /// longer ago than AGE (e.g., 30d)
+        #[arg(long, value_name = "AGE", value_parser = util::parse_duration)]
+        older_than: Option<Duration>,
+
+        /// Only bury files bigger than SIZE
+        /// (e.g., 100M)
+        #[arg(long, value_name = "SIZE", value_parser = util::parse_size)]
+        larger_than: Option<u64>,
+
+        /// Only bury files matching GLOB
+        #[arg(long, value_name = "GLOB")]
+        pattern: Vec<String>,
+
+        /// Print what would be buried
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+    },
+
+    /// Print a buried file
+    #[command(styles=STYLES, help_template=help_template("cat"))]
+    Cat {
+        /// Where the file was buried from,
+        /// or its grave
+        #[arg(value_name = "PATH")]
+        path: PathBuf,
+    },
+
+    /// Print the history of the graveyard
+    #[command(styles=STYLES, help_template=help_template("log"))]
+    Log,
+
+    /// Check the record against the graveyard
+    #[command(styles=STYLES, help_template=help_template("fsck"))]
+    Fsck {
+        /// Add orphaned graves to the record
+        /// and drop entries whose grave is gone
+        #[arg(long)]
+        repair: bool,
+    },
+
+    /// Maintain the record of graves
+    #[command(styles=STYLES, help_template=help_template("record"))]
+    Record {
+        #[command(subcommand)]
+        command: RecordCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum RecordCommand {
+    /// Drop unburied and purged graves
+    /// from the record
+    #[command(styles=STYLES, help_template=help_template("record compact"))]
+    Compact,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ConfigCommand {
+    /// Print the effective configuration
+    /// and where each value came from
+    #[command(styles=STYLES, help_template=help_template("config show"))]
+    Show,
 }
 
 struct IsDefault {
@@ -166,15 +482,19 @@ pub fn validate_args(cli: &Args) -> Result<(), Error> {
             && defaults.unbury
             && defaults.inspect)
     {
-        return Err(Error::new(
-            ErrorKind::InvalidInput,
-            "--completions can only be used by itself",
+        return Err(Error::InvalidInput(
+            "--completions can only be used by itself".to_string(),
         ));
     }
     if !defaults.decompose && !(defaults.seance && defaults.unbury && defaults.inspect) {
-        return Err(Error::new(
-            ErrorKind::InvalidInput,
-            "-d,--decompose can only be used with --graveyard",
+        return Err(Error::InvalidInput(
+            "-d,--decompose can only be used with --graveyard".to_string(),
+        ));
+    }
+
+    if cli.null && !cli.stdin && cli.files_from.is_none() {
+        return Err(Error::InvalidInput(
+            "-0,--null can only be used with --stdin or --files-from".to_string(),
         ));
     }
 
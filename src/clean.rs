@@ -0,0 +1,303 @@
+//! `rip clean`: bury the files that git doesn't track, like `git clean`
+//! but undoable. The index and `.gitignore` files are read directly, so git
+//! doesn't have to be installed.
+
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::{env, iter};
+
+use crate::args::Args;
+use crate::error::IoContext;
+use crate::prompt::Prompter;
+use crate::{util, Error};
+
+/// Which files `rip clean` buries
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CleanOptions {
+    /// Files that are neither tracked nor ignored
+    pub untracked: bool,
+    /// Files that match a `.gitignore`
+    pub ignored: bool,
+}
+
+impl Default for CleanOptions {
+    fn default() -> Self {
+        CleanOptions {
+            untracked: true,
+            ignored: true,
+        }
+    }
+}
+
+/// Bury the untracked and/or ignored files under `dir` in a single batch,
+/// so that `rip -u` brings all of them back. With `dry_run`, only print
+/// what would be buried.
+pub fn run(
+    cli: &Args,
+    dir: &Path,
+    opts: CleanOptions,
+    dry_run: bool,
+    prompter: impl Prompter,
+    stream: &mut impl Write,
+) -> Result<(), Error> {
+    let cwd = &env::current_dir()?;
+    let targets = find(&cwd.join(dir), opts)?;
+    let shown = |target: &Path| target.strip_prefix(cwd).unwrap_or(target).to_path_buf();
+
+    if targets.is_empty() {
+        writeln!(stream, "Nothing to clean")?;
+        return Ok(());
+    }
+    if dry_run {
+        for target in &targets {
+            writeln!(stream, "Would bury {}", shown(target).display())?;
+        }
+        return Ok(());
+    }
+    let targets: Vec<PathBuf> = targets.iter().map(|target| shown(target)).collect();
+    crate::bury_batch(cli, &targets, prompter, stream)
+}
+
+/// The largest untracked and/or ignored files and directories under `dir`,
+/// which has to be in a git repository. Nested repositories are left alone.
+pub fn find(dir: &Path, opts: CleanOptions) -> Result<Vec<PathBuf>, Error> {
+    let dir = &dunce::canonicalize(dir).context(|| format!("Failed to read {}", dir.display()))?;
+    let root = dir
+        .ancestors()
+        .find(|ancestor| ancestor.join(".git").exists())
+        .ok_or_else(|| {
+            Error::InvalidInput(format!("{} is not in a git repository", dir.display()))
+        })?;
+    let git_dir = git_dir(root)?;
+    let repo = Repository {
+        root,
+        tracked: read_index(&git_dir)?,
+        global: Gitignore::global().0,
+    };
+
+    // Rules from the repository root down to `dir`
+    let mut rules = vec![matcher(root, &[git_dir.join("info").join("exclude")])];
+    let mut ignored = false;
+    let relative = dir.strip_prefix(root).unwrap_or(Path::new(""));
+    let mut current = root.to_path_buf();
+    rules.push(matcher(&current, &[current.join(".gitignore")]));
+    for component in relative {
+        current.push(component);
+        ignored = ignored || repo.is_ignored(&rules, &current, true);
+        rules.push(matcher(&current, &[current.join(".gitignore")]));
+    }
+
+    let mut targets = Vec::new();
+    if repo.visit(dir, &mut rules, ignored, opts, &mut targets)? {
+        targets.push(dir.to_path_buf());
+    }
+    Ok(targets)
+}
+
+struct Repository<'a> {
+    root: &'a Path,
+    /// Tracked files, and the directories holding them, relative to `root`
+    tracked: HashSet<PathBuf>,
+    global: Gitignore,
+}
+
+impl Repository<'_> {
+    /// Push the selected parts of `dir` onto `targets`, and return whether
+    /// all of it is selected
+    fn visit(
+        &self,
+        dir: &Path,
+        rules: &mut Vec<Gitignore>,
+        ignored: bool,
+        opts: CleanOptions,
+        targets: &mut Vec<PathBuf>,
+    ) -> Result<bool, Error> {
+        let context = || format!("Failed to read {}", dir.display());
+        let mut children = fs::read_dir(dir)
+            .context(context)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, _>>()
+            .context(context)?;
+        children.sort();
+
+        let start = targets.len();
+        let mut whole = true;
+        for child in children {
+            let is_dir = fs::symlink_metadata(&child)
+                .context(|| format!("Failed to read {}", child.display()))?
+                .is_dir();
+            let relative = child.strip_prefix(self.root).unwrap_or(&child);
+            let selected = if child.file_name().is_some_and(|name| name == ".git")
+                || (is_dir && child.join(".git").exists())
+            {
+                // Leave nested repositories alone
+                false
+            } else if self.tracked.contains(relative) {
+                if is_dir {
+                    rules.push(matcher(&child, &[child.join(".gitignore")]));
+                    self.visit(&child, rules, false, opts, targets)?;
+                    rules.pop();
+                }
+                false
+            } else if ignored || self.is_ignored(rules, &child, is_dir) {
+                opts.ignored
+            } else if is_dir {
+                rules.push(matcher(&child, &[child.join(".gitignore")]));
+                let all = self.visit(&child, rules, false, opts, targets)?;
+                rules.pop();
+                opts.untracked && all
+            } else {
+                opts.untracked
+            };
+            if selected {
+                targets.push(child);
+            } else {
+                whole = false;
+            }
+        }
+        if whole {
+            targets.truncate(start);
+        }
+        Ok(whole)
+    }
+
+    /// The deepest `.gitignore` with a rule for `path` decides, then
+    /// `.git/info/exclude`, then the global excludes file
+    fn is_ignored(&self, rules: &[Gitignore], path: &Path, is_dir: bool) -> bool {
+        let relative = path.strip_prefix(self.root).unwrap_or(path);
+        let decision = rules
+            .iter()
+            .rev()
+            .map(|rules| rules.matched(path, is_dir))
+            .chain(iter::once(self.global.matched(relative, is_dir)))
+            .find(|decision| !decision.is_none());
+        matches!(decision, Some(Match::Ignore(_)))
+    }
+}
+
+/// The rules in `files` that exist, for paths under `dir`
+fn matcher(dir: &Path, files: &[PathBuf]) -> Gitignore {
+    let mut builder = GitignoreBuilder::new(dir);
+    for file in files.iter().filter(|file| file.is_file()) {
+        // Like git, skip rules that can't be parsed
+        let _ = builder.add(file);
+    }
+    builder.build().unwrap_or_else(|_| Gitignore::empty())
+}
+
+/// The git directory of the repository at `root`, following the `gitdir:`
+/// pointer of worktrees and submodules
+fn git_dir(root: &Path) -> Result<PathBuf, Error> {
+    let dot_git = root.join(".git");
+    if dot_git.is_dir() {
+        return Ok(dot_git);
+    }
+    let pointer =
+        fs::read_to_string(&dot_git).context(|| format!("Failed to read {}", dot_git.display()))?;
+    pointer
+        .trim()
+        .strip_prefix("gitdir:")
+        .map(|git_dir| root.join(git_dir.trim()))
+        .ok_or_else(|| Error::InvalidInput(format!("{} is not a git directory", dot_git.display())))
+}
+
+/// The tracked paths in the index, and every directory holding one
+fn read_index(git_dir: &Path) -> Result<HashSet<PathBuf>, Error> {
+    let path = git_dir.join("index");
+    let data = match fs::read(&path) {
+        Ok(data) => data,
+        // A repository without commits has no index yet
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashSet::new()),
+        Err(e) => return Err(e).context(|| format!("Failed to read {}", path.display())),
+    };
+    let sha256 = fs::read_to_string(git_dir.join("config")).is_ok_and(|config| {
+        config.lines().any(|line| {
+            line.replace([' ', '\t'], "")
+                .eq_ignore_ascii_case("objectformat=sha256")
+        })
+    });
+    let names = parse_index(&data, if sha256 { 32 } else { 20 }).ok_or_else(|| Error::Io {
+        context: format!("Failed to read {}", path.display()),
+        source: io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unsupported or corrupt git index",
+        ),
+    })?;
+
+    let mut tracked = HashSet::new();
+    for name in names {
+        // Directories of a sparse index end with a slash
+        let path = util::path_from_bytes(name.strip_suffix(b"/").unwrap_or(&name).to_vec())?;
+        for ancestor in path.ancestors().filter(|a| !a.as_os_str().is_empty()) {
+            if !tracked.insert(ancestor.to_path_buf()) {
+                break;
+            }
+        }
+    }
+    Ok(tracked)
+}
+
+/// The paths of the entries in a git index of version 2, 3 or 4
+fn parse_index(data: &[u8], hash_len: usize) -> Option<Vec<Vec<u8>>> {
+    let u32_at = |at: usize| Some(u32::from_be_bytes(data.get(at..at + 4)?.try_into().ok()?));
+    if data.get(..4)? != b"DIRC" {
+        return None;
+    }
+    let version = u32_at(4)?;
+    if !(2..=4).contains(&version) {
+        return None;
+    }
+    let count = u32_at(8)?;
+
+    // Times, device, inode, mode, ids and size, then the hash and flags
+    let flags_at = 40 + hash_len;
+    let mut names: Vec<Vec<u8>> = Vec::with_capacity(count as usize);
+    let mut at = 12;
+    for _ in 0..count {
+        let flags = u16::from_be_bytes(
+            data.get(at + flags_at..at + flags_at + 2)?
+                .try_into()
+                .ok()?,
+        );
+        let mut header = flags_at + 2;
+        if version >= 3 && flags & 0x4000 != 0 {
+            header += 2;
+        }
+        let start = at + header;
+        if version == 4 {
+            // The name is compressed against the previous one
+            let (strip, len) = varint(data.get(start..)?)?;
+            let suffix = start + len;
+            let end = suffix + data.get(suffix..)?.iter().position(|b| *b == 0)?;
+            let previous = names.last().map(Vec::as_slice).unwrap_or_default();
+            let mut name = previous.get(..previous.len().checked_sub(strip)?)?.to_vec();
+            name.extend_from_slice(&data[suffix..end]);
+            names.push(name);
+            at = end + 1;
+        } else {
+            let end = start + data.get(start..)?.iter().position(|b| *b == 0)?;
+            names.push(data[start..end].to_vec());
+            // Entries are padded with NULs to a multiple of eight bytes
+            at += (header + end - start + 8) & !7;
+        }
+    }
+    Some(names)
+}
+
+/// Git's variable-length offset encoding
+fn varint(data: &[u8]) -> Option<(usize, usize)> {
+    let mut bytes = data.iter();
+    let mut byte = *bytes.next()?;
+    let mut value = usize::from(byte & 0x7f);
+    let mut len = 1;
+    while byte & 0x80 != 0 {
+        byte = *bytes.next()?;
+        value = ((value + 1) << 7) | usize::from(byte & 0x7f);
+        len += 1;
+    }
+    Some((value, len))
+}
@@ -0,0 +1,198 @@
+//! Graves compressed at rest. Each grave is packed into its own
+//! zstd-compressed tar next to where it was, and unpacked again when it is
+//! unburied. Single files can be read straight from the archive.
+
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use tar::{Archive, Builder};
+use walkdir::WalkDir;
+
+use crate::error::IoContext;
+use crate::{util, Error};
+
+/// Ends the name of every compressed grave
+pub const SUFFIX: &str = ".rip.tar.zst";
+/// Added to a packed grave's name for the directory it is unpacked into
+pub(crate) const SCRATCH_SUFFIX: &str = ".unpack";
+/// Fast enough not to hold up a bury, and still most of the savings
+const LEVEL: i32 = 3;
+
+/// Whether `grave` is a compressed grave
+pub fn is_archive(grave: &Path) -> bool {
+    grave
+        .file_name()
+        .is_some_and(|name| name.to_string_lossy().ends_with(SUFFIX))
+}
+
+/// Whether `grave` only holds files, directories and symlinks, which are
+/// all that an archive can hold
+pub fn can_pack(grave: &Path) -> bool {
+    WalkDir::new(grave).into_iter().all(|entry| {
+        entry.is_ok_and(|entry| {
+            let file_type = entry.file_type();
+            file_type.is_file() || file_type.is_dir() || file_type.is_symlink()
+        })
+    })
+}
+
+/// Pack `grave` into an archive next to it, and return the archive. The
+/// grave itself is left in place.
+pub fn pack(grave: &Path) -> Result<PathBuf, Error> {
+    let name = grave
+        .file_name()
+        .ok_or_else(|| Error::InvalidInput(format!("Can't compress {}", grave.display())))?;
+    let archive = util::repacked_name(grave, SUFFIX);
+    write_archive(grave, name, &archive)
+        .inspect_err(|_| {
+            fs::remove_file(&archive).ok();
+        })
+        .context(|| format!("Failed to compress {}", grave.display()))?;
+    Ok(archive)
+}
+
+fn write_archive(grave: &Path, name: &OsStr, archive: &Path) -> io::Result<()> {
+    write_tar(grave, name, fs::File::create_new(archive)?)?.sync_all()
+}
+
+/// Write `grave` to `writer` as a compressed tar, under `name`
+pub(crate) fn write_tar<W: Write>(grave: &Path, name: &OsStr, writer: W) -> io::Result<W> {
+    let mut builder = Builder::new(zstd::Encoder::new(writer, LEVEL)?);
+    builder.follow_symlinks(false);
+    if fs::symlink_metadata(grave)?.is_dir() {
+        builder.append_dir_all(name, grave)?;
+    } else {
+        builder.append_path_with_name(grave, name)?;
+    }
+    builder.into_inner()?.finish()
+}
+
+/// A grave unpacked into a scratch directory, which is removed again when
+/// this is dropped
+#[derive(Debug)]
+pub struct Unpacked {
+    pub(crate) dir: PathBuf,
+    /// The grave, as it was before it was packed
+    pub grave: PathBuf,
+}
+
+impl Unpacked {
+    /// An empty scratch directory next to the packed grave `packed`
+    pub(crate) fn scratch(packed: &Path) -> io::Result<Unpacked> {
+        let mut dir = OsString::from(packed.as_os_str());
+        dir.push(SCRATCH_SUFFIX);
+        let dir = PathBuf::from(dir);
+        // Left over from an interrupted unbury
+        if dir.exists() {
+            fs::remove_dir_all(&dir)?;
+        }
+        fs::create_dir(&dir)?;
+        Ok(Unpacked {
+            grave: dir.clone(),
+            dir,
+        })
+    }
+}
+
+impl Drop for Unpacked {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.dir).ok();
+    }
+}
+
+/// Unpack `archive` next to it. The archive itself is left in place.
+pub fn unpack(archive: &Path) -> Result<Unpacked, Error> {
+    let context = || format!("Failed to decompress {}", archive.display());
+    unpack_from(archive, fs::File::open(archive).context(context)?)
+}
+
+/// Unpack the compressed tar read from `reader` next to `packed`
+pub(crate) fn unpack_from(packed: &Path, reader: impl Read) -> Result<Unpacked, Error> {
+    let context = || format!("Failed to decompress {}", packed.display());
+    let mut unpacked = Unpacked::scratch(packed).context(context)?;
+
+    let mut tar = open(reader).context(context)?;
+    tar.set_preserve_permissions(true);
+    tar.set_preserve_mtime(true);
+    tar.unpack(&unpacked.dir).context(context)?;
+    let grave = fs::read_dir(&unpacked.dir)
+        .context(context)?
+        .next()
+        .ok_or_else(|| Error::Io {
+            context: context(),
+            source: io::Error::new(io::ErrorKind::InvalidData, "empty archive"),
+        })?
+        .context(context)?;
+    unpacked.grave = grave.path();
+    Ok(unpacked)
+}
+
+/// Copy the file at `path` in the grave packed into `archive` to `stream`.
+/// An empty `path` is the grave itself.
+pub fn cat(archive: &Path, path: &Path, stream: &mut impl Write) -> Result<(), Error> {
+    let context = || format!("Failed to read {}", archive.display());
+    cat_from(
+        archive,
+        fs::File::open(archive).context(context)?,
+        path,
+        stream,
+    )
+}
+
+/// Like [`cat`], for the compressed tar read from `reader`, which holds the
+/// grave `packed`
+pub(crate) fn cat_from(
+    packed: &Path,
+    reader: impl Read,
+    path: &Path,
+    stream: &mut impl Write,
+) -> Result<(), Error> {
+    let context = || format!("Failed to read {}", packed.display());
+    let wanted: PathBuf = path.components().collect();
+    let shown = || match wanted.as_os_str().is_empty() {
+        true => packed.to_path_buf(),
+        false => packed.join(&wanted),
+    };
+    let mut tar = open(reader).context(context)?;
+    for entry in tar.entries().context(context)? {
+        let mut entry = entry.context(context)?;
+        // Entries are named after the grave, which is left out of `path`
+        let inner: PathBuf = entry
+            .path()
+            .context(context)?
+            .components()
+            .skip(1)
+            .collect();
+        if inner != wanted {
+            continue;
+        }
+        if !entry.header().entry_type().is_file() {
+            return Err(Error::InvalidInput(format!(
+                "{} is not a file",
+                shown().display()
+            )));
+        }
+        io::copy(&mut entry, stream).context(context)?;
+        return Ok(());
+    }
+    Err(Error::NotBuried { path: shown() })
+}
+
+/// The total size of the files packed into `archive`
+pub fn unpacked_size(archive: &Path) -> Result<u64, Error> {
+    let context = || format!("Failed to read {}", archive.display());
+    let mut tar = open(fs::File::open(archive).context(context)?).context(context)?;
+    let mut size = 0;
+    for entry in tar.entries().context(context)? {
+        let entry = entry.context(context)?;
+        if entry.header().entry_type().is_file() {
+            size += entry.size();
+        }
+    }
+    Ok(size)
+}
+
+fn open(reader: impl Read) -> io::Result<Archive<impl Read>> {
+    Ok(Archive::new(zstd::Decoder::new(reader)?))
+}
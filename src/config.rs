@@ -0,0 +1,486 @@
+use serde::Deserialize;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use crate::args::{Args, BigFilePolicy, OutputFormat};
+use crate::crypt::Key;
+use crate::project::Project;
+use crate::record::DEFAULT_LOCK_TIMEOUT;
+use crate::{util, BIG_FILE_THRESHOLD};
+
+use crate::Error;
+
+/// Environment variable pointing at a config file to use instead of the default one
+pub const CONFIG_ENV: &str = "RIP_CONFIG";
+/// Environment variable overriding how long to wait for a busy graveyard
+pub const LOCK_TIMEOUT_ENV: &str = "RIP_LOCK_TIMEOUT";
+
+/// The contents of `config.toml`. Every key is optional.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ConfigFile {
+    pub graveyard: Option<PathBuf>,
+    pub big_file_threshold: Option<Size>,
+    pub big_file_policy: Option<BigFilePolicy>,
+    /// How long graves are kept, e.g., `30d`
+    pub retention: Option<String>,
+    /// The most the graveyard may hold, e.g., `10GiB`
+    pub quota: Option<Size>,
+    pub format: Option<OutputFormat>,
+    pub protected: Option<Vec<String>>,
+    pub inspect: Option<bool>,
+    /// How long to wait for other rips to release the record, e.g., `30s`
+    pub lock_timeout: Option<String>,
+    /// How many threads copy directories across devices
+    pub copy_jobs: Option<usize>,
+    /// How long graves stay uncompressed, e.g., `48h`
+    pub compress_after: Option<String>,
+    /// Graves holding more than this are compressed, e.g., `100MiB`
+    pub compress_above: Option<Size>,
+    /// Keep the contents of buried files once, however often they are buried
+    pub dedup: Option<bool>,
+    /// Encrypt graves at rest
+    pub encrypt: Option<bool>,
+    /// Holds the secret that graves are encrypted with
+    pub key_file: Option<PathBuf>,
+    /// Directories whose `.riprc` may set the graveyard and hard-delete paths
+    pub trusted_projects: Option<Vec<PathBuf>>,
+}
+
+/// A size in bytes, either as a number or a string like `10GiB`
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Size {
+    Bytes(u64),
+    Human(String),
+}
+
+impl Size {
+    fn bytes(&self) -> Result<u64, Error> {
+        match self {
+            Size::Bytes(bytes) => Ok(*bytes),
+            Size::Human(size) => util::parse_size(size),
+        }
+    }
+}
+
+/// Where a configuration value came from
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Source {
+    Default,
+    ConfigFile(PathBuf),
+    Env(&'static str),
+    Flag(&'static str),
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Default => write!(f, "default"),
+            Source::ConfigFile(path) => write!(f, "config file {}", path.display()),
+            Source::Env(var) => write!(f, "environment variable ${}", var),
+            Source::Flag(flag) => write!(f, "flag --{}", flag),
+        }
+    }
+}
+
+/// A configuration value together with where it came from
+#[derive(Clone, Debug)]
+pub struct Setting<T> {
+    pub value: T,
+    pub source: Source,
+}
+
+impl<T> Setting<T> {
+    fn new(value: T, source: Source) -> Setting<T> {
+        Setting { value, source }
+    }
+}
+
+/// The effective configuration. Each value is taken from the first of:
+///
+/// 1. the command line flag
+/// 2. the environment variable
+/// 3. the `.riprc` of the project, for the graveyard
+/// 4. the config file
+/// 5. the built-in default
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub graveyard: Setting<PathBuf>,
+    pub big_file_threshold: Setting<u64>,
+    pub big_file_policy: Setting<BigFilePolicy>,
+    pub retention: Setting<Option<Duration>>,
+    pub quota: Setting<Option<u64>>,
+    pub format: Setting<OutputFormat>,
+    /// Protected globs, on top of the built-in deny list
+    pub protected: Setting<Vec<String>>,
+    pub inspect: Setting<bool>,
+    pub lock_timeout: Setting<Duration>,
+    pub copy_jobs: Setting<usize>,
+    pub compress_after: Setting<Option<Duration>>,
+    pub compress_above: Setting<Option<u64>>,
+    pub dedup: Setting<bool>,
+    pub encrypt: Setting<bool>,
+    pub key_file: Setting<Option<PathBuf>>,
+    pub trusted_projects: Setting<Vec<PathBuf>>,
+    /// The `.riprc` that applies, if any
+    pub project: Option<Project>,
+}
+
+/// One copy thread per CPU, up to 8, beyond which disks rarely keep up
+pub fn default_copy_jobs() -> usize {
+    thread::available_parallelism().map_or(1, |cpus| cpus.get().min(8))
+}
+
+/// The default location of the config file,
+/// `$XDG_CONFIG_HOME/rip/config.toml` or `~/.config/rip/config.toml`
+pub fn default_path() -> Option<PathBuf> {
+    #[cfg(target_os = "windows")]
+    let config_home = env::var_os("APPDATA").map(PathBuf::from);
+    #[cfg(not(target_os = "windows"))]
+    let config_home = env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| util::home_dir().map(|home| home.join(".config")));
+    config_home.map(|dir| dir.join("rip").join("config.toml"))
+}
+
+/// The config file in use, which may not exist
+pub fn path() -> Option<PathBuf> {
+    match env::var_os(CONFIG_ENV) {
+        Some(path) if !path.is_empty() => Some(PathBuf::from(path)),
+        _ => default_path(),
+    }
+}
+
+impl ConfigFile {
+    /// Read a config file, treating a missing file as empty
+    pub fn read(path: &Path) -> Result<ConfigFile, Error> {
+        match fs::read_to_string(path) {
+            Ok(contents) => toml::from_str(&contents).map_err(|e| {
+                Error::InvalidInput(format!("Invalid config file {}: {}", path.display(), e))
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(ConfigFile::default()),
+            Err(e) => Err(Error::Io {
+                context: format!("Failed to read config file {}", path.display()),
+                source: e,
+            }),
+        }
+    }
+}
+
+impl Config {
+    /// Work out the effective configuration for the command line,
+    /// including the `.riprc` of the current directory
+    pub fn load(cli: &Args) -> Result<Config, Error> {
+        let project = Project::discover(&env::current_dir()?)?;
+        Ok(Config::load_global(cli)?.with_project(project))
+    }
+
+    /// Work out the configuration without looking for a `.riprc`
+    pub fn load_global(cli: &Args) -> Result<Config, Error> {
+        let (file, file_source) = match path() {
+            Some(path) => (ConfigFile::read(&path)?, Source::ConfigFile(path)),
+            None => (ConfigFile::default(), Source::Default),
+        };
+        Config::resolve(cli, file, file_source)
+    }
+
+    /// Apply the rules of a `.riprc` on top of this configuration.
+    /// The project graveyard only yields to `--graveyard` and `$RIP_GRAVEYARD`,
+    /// and, like the hard-deleted paths, needs the project to be trusted.
+    pub fn with_project(mut self, mut project: Option<Project>) -> Config {
+        if let Some(project) = &mut project {
+            project.trusted = self
+                .trusted_projects
+                .value
+                .iter()
+                .any(|dir| dunce::canonicalize(dir).is_ok_and(|dir| dir == project.root));
+            let overridden = matches!(
+                self.graveyard.source,
+                Source::Flag(_) | Source::Env("RIP_GRAVEYARD")
+            );
+            if let (Some(graveyard), true, false) =
+                (&project.graveyard, project.trusted, overridden)
+            {
+                self.graveyard =
+                    Setting::new(graveyard.clone(), Source::ConfigFile(project.path.clone()));
+            }
+        }
+        self.project = project;
+        self
+    }
+
+    fn resolve(cli: &Args, file: ConfigFile, file_source: Source) -> Result<Config, Error> {
+        let from_file = |present: bool| {
+            if present {
+                file_source.clone()
+            } else {
+                Source::Default
+            }
+        };
+
+        let graveyard = if let Some(graveyard) = &cli.graveyard {
+            Setting::new(graveyard.clone(), Source::Flag("graveyard"))
+        } else if let Some(graveyard) = env::var_os("RIP_GRAVEYARD") {
+            Setting::new(PathBuf::from(graveyard), Source::Env("RIP_GRAVEYARD"))
+        } else if let Some(graveyard) = &file.graveyard {
+            Setting::new(expand_home(graveyard), file_source.clone())
+        } else if env::var_os("XDG_DATA_HOME").is_some() {
+            Setting::new(crate::get_graveyard(None), Source::Env("XDG_DATA_HOME"))
+        } else {
+            Setting::new(crate::get_graveyard(None), Source::Default)
+        };
+
+        let big_file_threshold = match (cli.big_file_threshold, &file.big_file_threshold) {
+            (Some(threshold), _) => Setting::new(threshold, Source::Flag("big-file-threshold")),
+            (None, Some(threshold)) => Setting::new(threshold.bytes()?, file_source.clone()),
+            (None, None) => Setting::new(BIG_FILE_THRESHOLD, Source::Default),
+        };
+
+        let big_file_policy = match (cli.big_file_policy, file.big_file_policy) {
+            (Some(policy), _) => Setting::new(policy, Source::Flag("big-file-policy")),
+            (None, policy) => Setting::new(policy.unwrap_or_default(), from_file(policy.is_some())),
+        };
+
+        let retention = Setting::new(
+            file.retention
+                .as_deref()
+                .map(util::parse_duration)
+                .transpose()?,
+            from_file(file.retention.is_some()),
+        );
+
+        let quota = Setting::new(
+            file.quota.as_ref().map(Size::bytes).transpose()?,
+            from_file(file.quota.is_some()),
+        );
+
+        let format = match (cli.format, file.format) {
+            (Some(format), _) => Setting::new(format, Source::Flag("format")),
+            (None, format) => Setting::new(format.unwrap_or_default(), from_file(format.is_some())),
+        };
+
+        let protected = match (env::var_os(crate::protect::PROTECTED_ENV), file.protected) {
+            (Some(globs), _) => Setting::new(
+                env::split_paths(&globs)
+                    .filter(|glob| !glob.as_os_str().is_empty())
+                    .map(|glob| glob.to_string_lossy().into_owned())
+                    .collect(),
+                Source::Env(crate::protect::PROTECTED_ENV),
+            ),
+            (None, Some(protected)) => Setting::new(protected, file_source.clone()),
+            (None, None) => Setting::new(Vec::new(), Source::Default),
+        };
+
+        let inspect = match (cli.inspect, file.inspect) {
+            (true, _) => Setting::new(true, Source::Flag("inspect")),
+            (false, inspect) => {
+                Setting::new(inspect.unwrap_or_default(), from_file(inspect.is_some()))
+            }
+        };
+
+        let lock_timeout = match (env::var(LOCK_TIMEOUT_ENV), &file.lock_timeout) {
+            (Ok(timeout), _) => Setting::new(
+                util::parse_duration(&timeout)?,
+                Source::Env(LOCK_TIMEOUT_ENV),
+            ),
+            (Err(_), Some(timeout)) => {
+                Setting::new(util::parse_duration(timeout)?, file_source.clone())
+            }
+            (Err(_), None) => Setting::new(DEFAULT_LOCK_TIMEOUT, Source::Default),
+        };
+
+        let copy_jobs = match (cli.jobs, file.copy_jobs) {
+            (Some(jobs), _) => Setting::new(jobs, Source::Flag("jobs")),
+            (None, Some(jobs)) => Setting::new(jobs, file_source.clone()),
+            (None, None) => Setting::new(default_copy_jobs(), Source::Default),
+        };
+
+        let compress_after = Setting::new(
+            file.compress_after
+                .as_deref()
+                .map(util::parse_duration)
+                .transpose()?,
+            from_file(file.compress_after.is_some()),
+        );
+
+        let compress_above = Setting::new(
+            file.compress_above.as_ref().map(Size::bytes).transpose()?,
+            from_file(file.compress_above.is_some()),
+        );
+
+        let dedup = Setting::new(
+            file.dedup.unwrap_or_default(),
+            from_file(file.dedup.is_some()),
+        );
+
+        let encrypt = Setting::new(
+            file.encrypt.unwrap_or_default(),
+            from_file(file.encrypt.is_some()),
+        );
+
+        let key_file = Setting::new(
+            file.key_file.as_deref().map(expand_home),
+            from_file(file.key_file.is_some()),
+        );
+
+        let trusted_projects = match file.trusted_projects {
+            Some(dirs) => Setting::new(
+                dirs.iter().map(|dir| expand_home(dir)).collect(),
+                file_source.clone(),
+            ),
+            None => Setting::new(Vec::new(), Source::Default),
+        };
+
+        Ok(Config {
+            graveyard,
+            big_file_threshold,
+            big_file_policy,
+            retention,
+            quota,
+            format,
+            protected,
+            inspect,
+            lock_timeout,
+            copy_jobs,
+            compress_after,
+            compress_above,
+            dedup,
+            encrypt,
+            key_file,
+            trusted_projects,
+            project: None,
+        })
+    }
+
+    /// The key that graves are encrypted with: the passphrase in
+    /// `RIP_PASSPHRASE`, or else the contents of the key file
+    pub fn key(&self) -> Result<Option<Key>, Error> {
+        Key::load(self.key_file.value.as_deref())
+    }
+
+    /// Print every value, and where it came from
+    pub fn show(&self, stream: &mut impl Write) -> io::Result<()> {
+        writeln!(
+            stream,
+            "# Config file: {}",
+            path().map_or("none".to_string(), |path| path.display().to_string())
+        )?;
+        if let Some(project) = &self.project {
+            writeln!(
+                stream,
+                "# Project file: {}{}",
+                project.path.display(),
+                if project.trusted {
+                    ""
+                } else {
+                    " (not trusted, so only refuse applies)"
+                }
+            )?;
+        }
+        show(stream, "graveyard", &self.graveyard, |graveyard| {
+            format!("{:?}", graveyard.display().to_string())
+        })?;
+        show(
+            stream,
+            "big-file-threshold",
+            &self.big_file_threshold,
+            |threshold| threshold.to_string(),
+        )?;
+        show(stream, "big-file-policy", &self.big_file_policy, |policy| {
+            format!("{:?}", value_name(*policy))
+        })?;
+        show(stream, "retention", &self.retention, |retention| {
+            retention.map_or("none".to_string(), |retention| {
+                format!("{:?}", format!("{}s", retention.as_secs()))
+            })
+        })?;
+        show(stream, "quota", &self.quota, |quota| {
+            quota.map_or("none".to_string(), |quota| quota.to_string())
+        })?;
+        show(stream, "format", &self.format, |format| {
+            format!("{:?}", value_name(*format))
+        })?;
+        show(stream, "protected", &self.protected, |protected| {
+            format!("{:?}", protected)
+        })?;
+        show(stream, "inspect", &self.inspect, |inspect| {
+            inspect.to_string()
+        })?;
+        show(stream, "lock-timeout", &self.lock_timeout, |timeout| {
+            format!("{:?}", format!("{}s", timeout.as_secs()))
+        })?;
+        show(stream, "copy-jobs", &self.copy_jobs, |jobs| {
+            jobs.to_string()
+        })?;
+        show(stream, "compress-after", &self.compress_after, |age| {
+            age.map_or("none".to_string(), |age| {
+                format!("{:?}", format!("{}s", age.as_secs()))
+            })
+        })?;
+        show(stream, "compress-above", &self.compress_above, |size| {
+            size.map_or("none".to_string(), |size| size.to_string())
+        })?;
+        show(stream, "dedup", &self.dedup, |dedup| dedup.to_string())?;
+        show(stream, "encrypt", &self.encrypt, |encrypt| {
+            encrypt.to_string()
+        })?;
+        show(stream, "key-file", &self.key_file, |key_file| {
+            key_file.as_ref().map_or("none".to_string(), |key_file| {
+                format!("{:?}", key_file.display().to_string())
+            })
+        })?;
+        show(stream, "trusted-projects", &self.trusted_projects, |dirs| {
+            format!("{:?}", dirs)
+        })?;
+        if let Some(project) = &self.project {
+            let source = Source::ConfigFile(project.path.clone());
+            let hard_delete: Vec<&str> = project.hard_delete().collect();
+            let refuse: Vec<&str> = project.refuse().collect();
+            show(
+                stream,
+                "hard-delete",
+                &Setting::new(hard_delete, source.clone()),
+                |globs| format!("{:?}", globs),
+            )?;
+            show(stream, "refuse", &Setting::new(refuse, source), |globs| {
+                format!("{:?}", globs)
+            })?;
+        }
+        Ok(())
+    }
+}
+
+fn show<T>(
+    stream: &mut impl Write,
+    key: &str,
+    setting: &Setting<T>,
+    value: impl FnOnce(&T) -> String,
+) -> io::Result<()> {
+    writeln!(
+        stream,
+        "{} = {}  # {}",
+        key,
+        value(&setting.value),
+        setting.source
+    )
+}
+
+fn value_name(value: impl clap::ValueEnum) -> String {
+    value
+        .to_possible_value()
+        .map_or(String::new(), |value| value.get_name().to_string())
+}
+
+fn expand_home(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), util::home_dir()) {
+        (Ok(rest), Some(home)) => home.join(rest),
+        _ => path.to_path_buf(),
+    }
+}
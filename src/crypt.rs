@@ -0,0 +1,313 @@
+//! Graves encrypted at rest. Each grave is packed like a compressed one, and
+//! the archive is encrypted with XChaCha20-Poly1305 under a key derived
+//! from a passphrase or a key file with Argon2id. The record is left as it
+//! is, so graves can still be listed and found without the key.
+
+use argon2::Argon2;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::stream::{DecryptorBE32, EncryptorBE32};
+use chacha20poly1305::aead::{generic_array::GenericArray, OsRng, Payload};
+use chacha20poly1305::XChaCha20Poly1305;
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::compress::{self, Unpacked};
+use crate::error::IoContext;
+use crate::{util, Error};
+
+/// Ends the name of every encrypted grave
+pub const SUFFIX: &str = ".rip.enc";
+/// Holds the passphrase, which takes precedence over the key file
+pub const PASSPHRASE_ENV: &str = "RIP_PASSPHRASE";
+
+const MAGIC: &[u8; 8] = b"RIPENC1\n";
+const SALT_LEN: usize = 16;
+/// The nonce of XChaCha20 less the 5 bytes of the STREAM counter
+const NONCE_LEN: usize = 19;
+/// The magic, the salt, the nonce and the size of what the grave holds
+const HEADER_LEN: usize = MAGIC.len() + SALT_LEN + NONCE_LEN + 8;
+const CHUNK: usize = 64 * 1024;
+const TAG_LEN: usize = 16;
+
+/// The secret that graves are encrypted with
+#[derive(Clone)]
+pub struct Key {
+    secret: Vec<u8>,
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Key(..)")
+    }
+}
+
+impl Key {
+    pub fn from_passphrase(passphrase: &str) -> Key {
+        Key {
+            secret: passphrase.as_bytes().to_vec(),
+        }
+    }
+
+    /// Use the contents of `path` as the secret. A trailing newline is
+    /// dropped, so that a key file written with `echo` works.
+    pub fn from_file(path: &Path) -> Result<Key, Error> {
+        let mut secret =
+            fs::read(path).context(|| format!("Failed to read key file {}", path.display()))?;
+        if secret.last() == Some(&b'\n') {
+            secret.pop();
+        }
+        if secret.is_empty() {
+            return Err(Error::InvalidInput(format!(
+                "Key file {} is empty",
+                path.display()
+            )));
+        }
+        Ok(Key { secret })
+    }
+
+    /// The passphrase in `RIP_PASSPHRASE`, or else the contents of
+    /// `key_file`, if either is set
+    pub fn load(key_file: Option<&Path>) -> Result<Option<Key>, Error> {
+        match (std::env::var(PASSPHRASE_ENV), key_file) {
+            (Ok(passphrase), _) if !passphrase.is_empty() => {
+                Ok(Some(Key::from_passphrase(&passphrase)))
+            }
+            (_, Some(path)) => Key::from_file(path).map(Some),
+            (_, None) => Ok(None),
+        }
+    }
+
+    fn derive(&self, salt: &[u8]) -> io::Result<chacha20poly1305::Key> {
+        let mut key = chacha20poly1305::Key::default();
+        Argon2::default()
+            .hash_password_into(&self.secret, salt, &mut key)
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        Ok(key)
+    }
+}
+
+/// Whether `grave` is an encrypted grave
+pub fn is_sealed(grave: &Path) -> bool {
+    grave
+        .file_name()
+        .is_some_and(|name| name.to_string_lossy().ends_with(SUFFIX))
+}
+
+/// Pack and encrypt `grave` under `name`, which holds `size` bytes, into a
+/// new grave next to `next_to`, and return it. The grave itself is left
+/// in place.
+pub fn seal(
+    grave: &Path,
+    name: &OsStr,
+    size: u64,
+    next_to: &Path,
+    key: &Key,
+) -> Result<PathBuf, Error> {
+    let sealed = util::repacked_name(next_to, SUFFIX);
+    write_sealed(grave, name, size, &sealed, key)
+        .inspect_err(|_| {
+            fs::remove_file(&sealed).ok();
+        })
+        .context(|| format!("Failed to encrypt {}", grave.display()))?;
+    Ok(sealed)
+}
+
+fn write_sealed(grave: &Path, name: &OsStr, size: u64, sealed: &Path, key: &Key) -> io::Result<()> {
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    header.extend_from_slice(MAGIC);
+    let mut salt = [0; SALT_LEN];
+    let mut nonce = [0; NONCE_LEN];
+    OsRng.fill_bytes(&mut salt);
+    OsRng.fill_bytes(&mut nonce);
+    header.extend_from_slice(&salt);
+    header.extend_from_slice(&nonce);
+    header.extend_from_slice(&size.to_le_bytes());
+
+    let mut file = fs::File::create_new(sealed)?;
+    file.write_all(&header)?;
+    let writer = Encryptor {
+        stream: EncryptorBE32::new(&key.derive(&salt)?, GenericArray::from_slice(&nonce)),
+        header,
+        buffer: Vec::with_capacity(CHUNK),
+        writer: file,
+    };
+    compress::write_tar(grave, name, writer)?
+        .finish()?
+        .sync_all()
+}
+
+/// Decrypt and unpack `sealed` next to it. The encrypted grave itself is
+/// left in place.
+pub fn unseal(sealed: &Path, key: &Key) -> Result<Unpacked, Error> {
+    compress::unpack_from(sealed, open(sealed, key)?)
+}
+
+/// Copy the file at `path` in the encrypted grave `sealed` to `stream`. An
+/// empty `path` is the grave itself.
+pub fn cat(sealed: &Path, key: &Key, path: &Path, stream: &mut impl Write) -> Result<(), Error> {
+    compress::cat_from(sealed, open(sealed, key)?, path, stream)
+}
+
+/// The size of what `sealed` holds, which is readable without the key
+pub fn size(sealed: &Path) -> Result<u64, Error> {
+    let header = read_header(&mut fs::File::open(sealed).context(|| read_context(sealed))?)
+        .context(|| read_context(sealed))?;
+    let at = HEADER_LEN - 8;
+    Ok(u64::from_le_bytes(
+        header[at..].try_into().unwrap_or_default(),
+    ))
+}
+
+fn open(sealed: &Path, key: &Key) -> Result<Decryptor<fs::File>, Error> {
+    let context = || format!("Failed to decrypt {}", sealed.display());
+    let mut file = fs::File::open(sealed).context(context)?;
+    let header = read_header(&mut file).context(context)?;
+    let salt = &header[MAGIC.len()..MAGIC.len() + SALT_LEN];
+    let nonce = &header[MAGIC.len() + SALT_LEN..HEADER_LEN - 8];
+    Ok(Decryptor {
+        stream: Some(DecryptorBE32::new(
+            &key.derive(salt).context(context)?,
+            GenericArray::from_slice(nonce),
+        )),
+        header,
+        chunk: Vec::new(),
+        at: 0,
+        peeked: None,
+        reader: file,
+    })
+}
+
+fn read_header(reader: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut header = vec![0; HEADER_LEN];
+    reader.read_exact(&mut header)?;
+    if !header.starts_with(MAGIC) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not an encrypted grave",
+        ));
+    }
+    Ok(header)
+}
+
+fn read_context(sealed: &Path) -> String {
+    format!("Failed to read {}", sealed.display())
+}
+
+fn damaged() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "wrong passphrase or key file, or the grave is damaged",
+    )
+}
+
+/// Encrypts what is written to it in chunks, each authenticated together
+/// with the header. The last chunk is marked as such, so that a grave cut
+/// short can't pass for a whole one.
+struct Encryptor<W: Write> {
+    stream: EncryptorBE32<XChaCha20Poly1305>,
+    header: Vec<u8>,
+    buffer: Vec<u8>,
+    writer: W,
+}
+
+impl<W: Write> Encryptor<W> {
+    /// Encrypt the last chunk, and return the inner writer
+    fn finish(mut self) -> io::Result<W> {
+        let chunk = self
+            .stream
+            .encrypt_last(Payload {
+                msg: &self.buffer,
+                aad: &self.header,
+            })
+            .map_err(|_| io::Error::other("encryption failed"))?;
+        self.writer.write_all(&chunk)?;
+        Ok(self.writer)
+    }
+}
+
+impl<W: Write> Write for Encryptor<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        // Hold on to a full chunk, in case it turns out to be the last
+        while self.buffer.len() > CHUNK {
+            let chunk = self
+                .stream
+                .encrypt_next(Payload {
+                    msg: &self.buffer[..CHUNK],
+                    aad: &self.header,
+                })
+                .map_err(|_| io::Error::other("encryption failed"))?;
+            self.writer.write_all(&chunk)?;
+            self.buffer.drain(..CHUNK);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Decrypts what [`Encryptor`] wrote, failing on anything that was changed
+/// or cut short
+struct Decryptor<R: Read> {
+    stream: Option<DecryptorBE32<XChaCha20Poly1305>>,
+    header: Vec<u8>,
+    chunk: Vec<u8>,
+    /// How much of `chunk` was read already
+    at: usize,
+    /// Read past a full chunk, to tell whether it was the last one
+    peeked: Option<u8>,
+    reader: R,
+}
+
+impl<R: Read> Decryptor<R> {
+    /// Decrypt the next chunk into `chunk`, unless the last one was read
+    fn next_chunk(&mut self) -> io::Result<()> {
+        let Some(stream) = self.stream.as_mut() else {
+            return Ok(());
+        };
+        let mut sealed = Vec::with_capacity(CHUNK + TAG_LEN);
+        sealed.extend(self.peeked.take());
+        (&mut self.reader)
+            .take((CHUNK + TAG_LEN - sealed.len()) as u64)
+            .read_to_end(&mut sealed)?;
+        let last = sealed.len() < CHUNK + TAG_LEN || {
+            let mut byte = [0];
+            let read = self.reader.read(&mut byte)?;
+            self.peeked = (read == 1).then_some(byte[0]);
+            read == 0
+        };
+        let payload = Payload {
+            msg: &sealed,
+            aad: &self.header,
+        };
+        self.chunk = if last {
+            let stream = self.stream.take().ok_or_else(damaged)?;
+            stream.decrypt_last(payload)
+        } else {
+            stream.decrypt_next(payload)
+        }
+        .map_err(|_| damaged())?;
+        self.at = 0;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Decryptor<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.at == self.chunk.len() {
+            if self.stream.is_none() {
+                return Ok(0);
+            }
+            self.next_chunk()?;
+        }
+        let len = buf.len().min(self.chunk.len() - self.at);
+        buf[..len].copy_from_slice(&self.chunk[self.at..self.at + len]);
+        self.at += len;
+        Ok(len)
+    }
+}
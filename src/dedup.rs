@@ -0,0 +1,447 @@
+//! Content-addressed deduplication. The contents of buried files are kept
+//! once in a store in the graveyard, named by their SHA-256, and a
+//! deduplicated grave is a manifest of the files it held and their hashes.
+//! The store counts the references to each blob, and deletes a blob once
+//! the last manifest referring to it is unburied or purged.
+
+use fs4::fs_std::FileExt;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use walkdir::WalkDir;
+
+#[cfg(unix)]
+use std::os::unix::fs::{symlink, PermissionsExt};
+
+#[cfg(target_os = "windows")]
+use std::os::windows::fs::symlink_file as symlink;
+
+use crate::compress::Unpacked;
+use crate::error::IoContext;
+use crate::record::DEFAULT_FILE_LOCK;
+use crate::{util, Error};
+
+/// The store, at the top of the graveyard
+pub const STORE: &str = ".store";
+/// Ends the name of every deduplicated grave
+pub const SUFFIX: &str = ".rip.manifest";
+/// Added to a manifest's name while it is written
+pub(crate) const TMP_SUFFIX: &str = ".tmp";
+/// How many manifests refer to each blob, one `hash count` pair per line
+const REFS: &str = "refs";
+const LOCK: &str = ".lock";
+
+/// A line in a manifest
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Entry {
+    /// Relative to the grave, and empty for the grave itself
+    #[serde(with = "util::serde_path")]
+    path: PathBuf,
+    #[serde(flatten)]
+    kind: Kind,
+    /// Unix permission bits
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mode: Option<u32>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum Kind {
+    Dir,
+    File {
+        hash: String,
+        size: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        modified: Option<SystemTime>,
+    },
+    Symlink {
+        #[serde(with = "util::serde_path")]
+        target: PathBuf,
+    },
+}
+
+impl Entry {
+    /// Where the entry is in the grave at `grave`. Joining an empty path
+    /// would add a trailing slash, which files can't have.
+    fn under(&self, grave: &Path) -> PathBuf {
+        match self.path.as_os_str().is_empty() {
+            true => grave.to_path_buf(),
+            false => grave.join(&self.path),
+        }
+    }
+}
+
+/// A grave added to the store by [`Store::add`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Deduplicated {
+    pub manifest: PathBuf,
+    /// The size of the files in the grave
+    pub size: u64,
+    /// How much of that wasn't in the store yet
+    pub stored: u64,
+}
+
+/// Whether `grave` is a deduplicated grave
+pub fn is_manifest(grave: &Path) -> bool {
+    grave
+        .file_name()
+        .is_some_and(|name| name.to_string_lossy().ends_with(SUFFIX))
+}
+
+/// The blobs of a graveyard
+#[derive(Clone, Debug)]
+pub struct Store {
+    dir: PathBuf,
+}
+
+impl Store {
+    pub fn new(graveyard: &Path) -> Store {
+        Store {
+            dir: graveyard.join(STORE),
+        }
+    }
+
+    /// Put the contents of the files in `grave` into the store, and write a
+    /// manifest next to it. The grave itself is left in place.
+    pub fn add(&self, grave: &Path) -> Result<Deduplicated, Error> {
+        let context = || format!("Failed to deduplicate {}", grave.display());
+        let mut entries = Vec::new();
+        for found in WalkDir::new(grave) {
+            let found = found.map_err(io::Error::from).context(context)?;
+            let metadata = found.metadata().map_err(io::Error::from).context(context)?;
+            let file_type = found.file_type();
+            let kind = if file_type.is_dir() {
+                Kind::Dir
+            } else if file_type.is_symlink() {
+                Kind::Symlink {
+                    target: fs::read_link(found.path()).context(context)?,
+                }
+            } else if file_type.is_file() {
+                Kind::File {
+                    hash: hash_file(found.path()).context(context)?,
+                    size: metadata.len(),
+                    modified: metadata.modified().ok(),
+                }
+            } else {
+                return Err(Error::InvalidInput(format!(
+                    "Can't deduplicate {}, which holds a special file",
+                    grave.display()
+                )));
+            };
+            entries.push(Entry {
+                path: found
+                    .path()
+                    .strip_prefix(grave)
+                    .unwrap_or(Path::new(""))
+                    .to_path_buf(),
+                kind,
+                mode: mode(&metadata),
+            });
+        }
+
+        let (mut size, mut stored) = (0, 0);
+        let _lock = self.lock()?;
+        let mut refs = self.read_refs()?;
+        for entry in &entries {
+            let Kind::File {
+                hash, size: len, ..
+            } = &entry.kind
+            else {
+                continue;
+            };
+            size += len;
+            let blob = self.blob(hash);
+            if !blob.exists() {
+                let file = entry.under(grave);
+                store_blob(&file, &blob)
+                    .context(|| format!("Failed to store {}", file.display()))?;
+                stored += len;
+            }
+            *refs.entry(hash.clone()).or_default() += 1;
+        }
+
+        // Only count the references once the manifest holding them is there
+        let manifest = util::repacked_name(grave, SUFFIX);
+        write_manifest(&manifest, &entries).context(context)?;
+        self.write_refs(&refs).inspect_err(|_| {
+            fs::remove_file(&manifest).ok();
+        })?;
+        Ok(Deduplicated {
+            manifest,
+            size,
+            stored,
+        })
+    }
+
+    /// Rebuild the grave listed in `manifest` next to it, from copies of
+    /// the blobs. The manifest and the blobs are left in place.
+    pub fn restore(&self, manifest: &Path) -> Result<Unpacked, Error> {
+        let context = || format!("Failed to restore {}", manifest.display());
+        let entries = read_manifest(manifest)?;
+        let mut restored = Unpacked::scratch(manifest).context(context)?;
+        restored.grave = restored.dir.join("grave");
+
+        for entry in &entries {
+            let path = entry.under(&restored.grave);
+            match &entry.kind {
+                Kind::Dir => fs::create_dir(&path).context(context)?,
+                Kind::Symlink { target } => symlink(target, &path).context(context)?,
+                Kind::File { hash, modified, .. } => {
+                    // Copy the contents only, since the blob is read-only
+                    let mut file = fs::File::create_new(&path).context(context)?;
+                    io::copy(
+                        &mut fs::File::open(self.blob(hash)).context(context)?,
+                        &mut file,
+                    )
+                    .context(context)?;
+                    if let Some(modified) = modified {
+                        file.set_modified(*modified).context(context)?;
+                    }
+                    set_mode(&path, entry.mode).context(context)?;
+                }
+            }
+        }
+        // Directories last, in case they are read-only
+        for entry in entries.iter().rev() {
+            if let Kind::Dir = entry.kind {
+                set_mode(&entry.under(&restored.grave), entry.mode).context(context)?;
+            }
+        }
+        Ok(restored)
+    }
+
+    /// Copy the file at `path` in the grave listed in `manifest` to
+    /// `stream`. An empty `path` is the grave itself.
+    pub fn cat(&self, manifest: &Path, path: &Path, stream: &mut impl Write) -> Result<(), Error> {
+        let wanted: PathBuf = path.components().collect();
+        let shown = || match wanted.as_os_str().is_empty() {
+            true => manifest.to_path_buf(),
+            false => manifest.join(&wanted),
+        };
+        let entry = read_manifest(manifest)?
+            .into_iter()
+            .find(|entry| entry.path == wanted)
+            .ok_or_else(|| Error::NotBuried { path: shown() })?;
+        let Kind::File { hash, .. } = &entry.kind else {
+            return Err(Error::InvalidInput(format!(
+                "{} is not a file",
+                shown().display()
+            )));
+        };
+        let blob = self.blob(hash);
+        let context = || format!("Failed to read {}", blob.display());
+        io::copy(&mut fs::File::open(&blob).context(context)?, stream).context(context)?;
+        Ok(())
+    }
+
+    /// The size of the files listed in `manifest`
+    pub fn size(&self, manifest: &Path) -> Result<u64, Error> {
+        Ok(read_manifest(manifest)?
+            .iter()
+            .map(|entry| match entry.kind {
+                Kind::File { size, .. } => size,
+                _ => 0,
+            })
+            .sum())
+    }
+
+    /// The share of the store taken up by `manifest`: each blob counts in
+    /// full for the only manifest referring to it, and is split evenly
+    /// between several
+    pub fn share(&self, manifest: &Path) -> Result<u64, Error> {
+        let refs = self.read_refs()?;
+        Ok(read_manifest(manifest)?
+            .iter()
+            .map(|entry| match &entry.kind {
+                Kind::File { hash, size, .. } => size / refs.get(hash).copied().unwrap_or(1).max(1),
+                _ => 0,
+            })
+            .sum())
+    }
+
+    /// The blobs that `manifest` refers to, to [`release`](Store::release)
+    /// once it is gone
+    pub fn hashes(&self, manifest: &Path) -> Result<Vec<String>, Error> {
+        Ok(read_manifest(manifest)?
+            .into_iter()
+            .filter_map(|entry| match entry.kind {
+                Kind::File { hash, .. } => Some(hash),
+                _ => None,
+            })
+            .collect())
+    }
+
+    /// Drop a reference to each of the blobs, and delete the blobs that
+    /// nothing refers to anymore
+    pub fn release(&self, hashes: &[String]) -> Result<(), Error> {
+        if hashes.is_empty() {
+            return Ok(());
+        }
+        let _lock = self.lock()?;
+        let mut refs = self.read_refs()?;
+        for hash in hashes {
+            let count = refs.entry(hash.clone()).or_default();
+            *count = count.saturating_sub(1);
+            if *count == 0 {
+                refs.remove(hash);
+                let blob = self.blob(hash);
+                // Read-only files can't be removed on Windows
+                #[cfg(not(unix))]
+                if let Ok(metadata) = fs::metadata(&blob) {
+                    let mut permissions = metadata.permissions();
+                    permissions.set_readonly(false);
+                    fs::set_permissions(&blob, permissions).ok();
+                }
+                match fs::remove_file(&blob) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                        return Err(e).context(|| format!("Failed to remove {}", blob.display()))
+                    }
+                    _ => {}
+                }
+                // Only succeeds once the last blob in the directory is gone
+                if let Some(dir) = blob.parent() {
+                    fs::remove_dir(dir).ok();
+                }
+            }
+        }
+        self.write_refs(&refs)
+    }
+
+    fn blob(&self, hash: &str) -> PathBuf {
+        self.dir.join(&hash[..2]).join(&hash[2..])
+    }
+
+    /// Wait until no other process is changing the store
+    fn lock(&self) -> Result<fs::File, Error> {
+        let path = self.dir.join(LOCK);
+        let context = || format!("Failed to lock {}", path.display());
+        fs::create_dir_all(&self.dir).context(context)?;
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .context(context)?;
+        if DEFAULT_FILE_LOCK {
+            file.lock_exclusive().context(context)?;
+        }
+        Ok(file)
+    }
+
+    fn read_refs(&self) -> Result<BTreeMap<String, u64>, Error> {
+        let path = self.dir.join(REFS);
+        let context = || format!("Failed to read {}", path.display());
+        let file = match fs::File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+            Err(e) => return Err(e).context(context),
+        };
+        let mut refs = BTreeMap::new();
+        for line in BufReader::new(file).lines() {
+            let line = line.context(context)?;
+            if let Some((hash, count)) = line.split_once(' ') {
+                if let Ok(count) = count.parse() {
+                    refs.insert(hash.to_string(), count);
+                }
+            }
+        }
+        Ok(refs)
+    }
+
+    /// Replace the reference counts, so that an interruption leaves the
+    /// old ones as they were
+    fn write_refs(&self, refs: &BTreeMap<String, u64>) -> Result<(), Error> {
+        let path = self.dir.join(REFS);
+        let tmp = path.with_extension("tmp");
+        let mut contents = String::new();
+        for (hash, count) in refs {
+            contents.push_str(&format!("{} {}\n", hash, count));
+        }
+        fs::write(&tmp, contents)
+            .and_then(|_| fs::rename(&tmp, &path))
+            .context(|| format!("Failed to write {}", path.display()))
+    }
+}
+
+fn hash_file(file: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut fs::File::open(file)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Put a read-only copy of `file` at `blob`. A hard link would share the
+/// inode with any link to `file` outside the graveyard, through which the
+/// blob could then be changed.
+fn store_blob(file: &Path, blob: &Path) -> io::Result<()> {
+    fs::create_dir_all(blob.parent().unwrap_or(blob))?;
+    let tmp = blob.with_extension("tmp");
+    fs::copy(file, &tmp)?;
+    let mut permissions = fs::metadata(&tmp)?.permissions();
+    permissions.set_readonly(true);
+    fs::set_permissions(&tmp, permissions)?;
+    fs::rename(&tmp, blob)
+}
+
+/// Write the manifest next to it first, so that an interruption leaves no
+/// manifest rather than half of one
+fn write_manifest(manifest: &Path, entries: &[Entry]) -> io::Result<()> {
+    let mut tmp = manifest.as_os_str().to_owned();
+    tmp.push(TMP_SUFFIX);
+    let tmp = PathBuf::from(tmp);
+    let write = || {
+        let mut writer = io::BufWriter::new(fs::File::create_new(&tmp)?);
+        for entry in entries {
+            serde_json::to_writer(&mut writer, entry)?;
+            writeln!(writer)?;
+        }
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+        fs::rename(&tmp, manifest)
+    };
+    write().inspect_err(|_| {
+        fs::remove_file(&tmp).ok();
+    })
+}
+
+fn read_manifest(manifest: &Path) -> Result<Vec<Entry>, Error> {
+    let context = || format!("Failed to read {}", manifest.display());
+    let file = fs::File::open(manifest).context(context)?;
+    let mut entries = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line.context(context)?;
+        let entry = serde_json::from_str(&line)
+            .map_err(io::Error::from)
+            .context(context)?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+#[cfg(unix)]
+fn mode(metadata: &fs::Metadata) -> Option<u32> {
+    Some(metadata.permissions().mode())
+}
+
+#[cfg(not(unix))]
+fn mode(_metadata: &fs::Metadata) -> Option<u32> {
+    None
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: Option<u32>) -> io::Result<()> {
+    match mode {
+        Some(mode) => fs::set_permissions(path, fs::Permissions::from_mode(mode)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: Option<u32>) -> io::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,213 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+/// Errors returned by rip2
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// A target doesn't exist
+    NotFound { path: PathBuf },
+    /// A target lives inside the graveyard already
+    AlreadyInGraveyard { path: PathBuf },
+    /// A target is on the protected path list
+    Protected { path: PathBuf, reason: String },
+    /// A directory holds another file system, and `--one-file-system` was given
+    OtherFileSystem { path: PathBuf, inner: PathBuf },
+    /// A target is above the big file threshold and the policy is `refuse`
+    TooBig {
+        path: PathBuf,
+        size: u64,
+        is_dir: bool,
+    },
+    /// There is no grave left to unbury
+    NothingToUnbury,
+    /// Nothing in the graveyard was buried from a path, or lives there
+    NotBuried { path: PathBuf },
+    /// The record in the graveyard can't be read or written
+    Record { path: PathBuf, source: io::Error },
+    /// Another process kept the record locked for longer than the lock timeout
+    GraveyardBusy { path: PathBuf, pid: Option<u32> },
+    /// A line in the record can't be parsed
+    RecordCorrupt { line: String },
+    /// A target couldn't be copied to another filesystem
+    CrossDeviceCopyFailed {
+        from: PathBuf,
+        to: PathBuf,
+        source: io::Error,
+    },
+    /// A grave couldn't be returned to its original location
+    UnburyFailed {
+        grave: PathBuf,
+        dest: PathBuf,
+        source: Box<Error>,
+    },
+    /// Some of several targets couldn't be removed, while the rest were
+    TargetsFailed {
+        total: usize,
+        failures: Vec<(PathBuf, Error)>,
+    },
+    /// The user asked to quit at a prompt
+    UserAborted,
+    /// Invalid arguments or configuration
+    InvalidInput(String),
+    /// Any other I/O error, with a description of what was being done
+    Io { context: String, source: io::Error },
+}
+
+impl Error {
+    /// The process exit code for this kind of error
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Error::InvalidInput(_) => 2,
+            Error::NotFound { .. } | Error::NothingToUnbury | Error::NotBuried { .. } => 3,
+            Error::AlreadyInGraveyard { .. }
+            | Error::Protected { .. }
+            | Error::OtherFileSystem { .. }
+            | Error::TooBig { .. } => 4,
+            Error::Record { .. } | Error::RecordCorrupt { .. } => 5,
+            Error::GraveyardBusy { .. } => 7,
+            Error::CrossDeviceCopyFailed { .. } | Error::UnburyFailed { .. } => 6,
+            Error::TargetsFailed { failures, .. } => {
+                // The exit code of the failures, if they agree on one
+                let mut codes = failures.iter().map(|(_, e)| e.exit_code());
+                let first = codes.next().unwrap_or(1);
+                if codes.all(|code| code == first) {
+                    first
+                } else {
+                    1
+                }
+            }
+            Error::UserAborted => 130,
+            Error::Io { .. } => 1,
+        }
+    }
+
+    /// Sum up the targets out of `total` that couldn't be removed: nothing
+    /// if there are none, or the error itself if there was only one target
+    pub fn from_failures(total: usize, mut failures: Vec<(PathBuf, Error)>) -> Result<(), Error> {
+        match failures.len() {
+            0 => Ok(()),
+            1 if total == 1 => Err(failures.remove(0).1),
+            _ => Err(Error::TargetsFailed { total, failures }),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NotFound { path } => write!(
+                f,
+                "Cannot remove {}: no such file or directory",
+                path.display()
+            ),
+            Error::AlreadyInGraveyard { path } => write!(
+                f,
+                "{} is already in the graveyard (use --decompose to delete the graveyard)",
+                path.display()
+            ),
+            Error::Protected { path, reason } => write!(
+                f,
+                "Refusing to remove {}: {} (use --force-protected to override)",
+                path.display(),
+                reason
+            ),
+            Error::OtherFileSystem { path, inner } => write!(
+                f,
+                "Refusing to remove {}: {} is on another file system (--one-file-system)",
+                path.display(),
+                inner.display()
+            ),
+            Error::TooBig { path, size, is_dir } => write!(
+                f,
+                "Refusing to copy big {} ({} is {})",
+                if *is_dir { "directory" } else { "file" },
+                path.display(),
+                crate::util::humanize_bytes(*size)
+            ),
+            Error::NothingToUnbury => write!(f, "No files in graveyard"),
+            Error::NotBuried { path } => write!(f, "Nothing buried at {}", path.display()),
+            Error::Record { path, source } => {
+                write!(f, "Failed to access record {}: {}", path.display(), source)
+            }
+            Error::GraveyardBusy { path, pid } => {
+                write!(f, "Graveyard busy")?;
+                if let Some(pid) = pid {
+                    write!(f, " (held by PID {})", pid)?;
+                }
+                write!(f, ": gave up waiting for a lock on {}", path.display())
+            }
+            Error::RecordCorrupt { line } => write!(f, "Malformed line in record: {:?}", line),
+            Error::CrossDeviceCopyFailed { from, to, source } => write!(
+                f,
+                "Failed to copy file from {} to {}: {}",
+                from.display(),
+                to.display(),
+                source
+            ),
+            Error::UnburyFailed {
+                grave,
+                dest,
+                source,
+            } => write!(
+                f,
+                "Unbury failed: couldn't copy files from {} to {}: {}",
+                grave.display(),
+                dest.display(),
+                source
+            ),
+            Error::TargetsFailed { total, failures } => {
+                write!(
+                    f,
+                    "Failed to remove {} of {} targets:",
+                    failures.len(),
+                    total
+                )?;
+                for (_, error) in failures {
+                    write!(f, "\n  {}", error)?;
+                }
+                Ok(())
+            }
+            Error::UserAborted => write!(f, "User requested to quit"),
+            Error::InvalidInput(message) => write!(f, "{}", message),
+            Error::Io { context, source } if context.is_empty() => write!(f, "{}", source),
+            Error::Io { context, source } => write!(f, "{}: {}", context, source),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Record { source, .. }
+            | Error::CrossDeviceCopyFailed { source, .. }
+            | Error::Io { source, .. } => Some(source),
+            Error::UnburyFailed { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(source: io::Error) -> Self {
+        Error::Io {
+            context: String::new(),
+            source,
+        }
+    }
+}
+
+/// Attach a description of the failed operation to an I/O error
+pub(crate) trait IoContext<T> {
+    fn context<C: Into<String>>(self, context: impl FnOnce() -> C) -> Result<T, Error>;
+}
+
+impl<T> IoContext<T> for Result<T, io::Error> {
+    fn context<C: Into<String>>(self, context: impl FnOnce() -> C) -> Result<T, Error> {
+        self.map_err(|source| Error::Io {
+            context: context().into(),
+            source,
+        })
+    }
+}
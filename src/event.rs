@@ -0,0 +1,453 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use crate::args::OutputFormat;
+use crate::util;
+
+/// Something rip did, or is about to do
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum Event {
+    /// A target was moved into the graveyard
+    Buried { source: PathBuf, grave: PathBuf },
+    /// A grave was returned to its original location
+    Unburied { grave: PathBuf, dest: PathBuf },
+    /// A target is about to be copied, because it couldn't simply be
+    /// renamed. `files` and `bytes` count the regular files in it, and are
+    /// only counted for an observer that [shows progress](Observer::shows_progress).
+    CopyStarted {
+        from: PathBuf,
+        to: PathBuf,
+        files: Option<u64>,
+        bytes: Option<u64>,
+    },
+    /// `bytes` of a file being copied are done. Sent every so often while
+    /// a file takes a while to copy.
+    Copying {
+        from: PathBuf,
+        to: PathBuf,
+        bytes: u64,
+    },
+    /// A file was copied, because it couldn't simply be renamed
+    Copied {
+        from: PathBuf,
+        to: PathBuf,
+        size: u64,
+    },
+    /// A target was permanently deleted instead of being buried
+    Deleted { path: PathBuf },
+    /// A target was left in place at the user's request
+    Skipped { path: PathBuf },
+    /// A grave was permanently deleted from the graveyard
+    Purged { path: PathBuf, size: u64 },
+    /// A grave of `size` bytes was packed into an archive of
+    /// `compressed_size` bytes
+    Compressed {
+        grave: PathBuf,
+        archive: PathBuf,
+        size: u64,
+        compressed_size: u64,
+    },
+    /// A grave of `size` bytes was moved into the store, which had to make
+    /// room for `stored` bytes of it
+    Deduplicated {
+        grave: PathBuf,
+        manifest: PathBuf,
+        size: u64,
+        stored: u64,
+    },
+    /// A grave was encrypted
+    Encrypted { grave: PathBuf, sealed: PathBuf },
+    /// A target about to be buried lives inside the graveyard already
+    AlreadyInGraveyard { path: PathBuf },
+    /// A target above the big file threshold is about to be copied
+    BigTarget {
+        path: PathBuf,
+        size: u64,
+        is_dir: bool,
+    },
+    /// A special file that probably can't be copied
+    NonRegularFile { path: PathBuf },
+    /// Some info about a target, shown before asking whether to bury it.
+    /// `preview` holds the first few entries of a directory or lines of a
+    /// file, and is `None` if the file couldn't be read.
+    Inspected {
+        path: PathBuf,
+        size: u64,
+        is_dir: bool,
+        preview: Option<Vec<String>>,
+    },
+    /// A question is about to be asked
+    Prompted { question: String },
+    /// Something failed without stopping the operation
+    Warning { message: String },
+}
+
+/// Receives the events of an operation, e.g., for logging or progress bars
+///
+/// Any `FnMut(&Event)` closure is an observer.
+pub trait Observer {
+    fn notify(&mut self, event: &Event) -> io::Result<()>;
+
+    /// Whether the progress of copies is shown, which needs the size of
+    /// each target to be counted before it is copied
+    fn shows_progress(&self) -> bool {
+        false
+    }
+}
+
+impl<F: FnMut(&Event)> Observer for F {
+    fn notify(&mut self, event: &Event) -> io::Result<()> {
+        self(event);
+        Ok(())
+    }
+}
+
+/// Renders events as the text printed by the command line
+pub struct TextRenderer<W: Write> {
+    stream: W,
+    verbose: bool,
+}
+
+impl<W: Write> TextRenderer<W> {
+    pub fn new(stream: W) -> TextRenderer<W> {
+        TextRenderer {
+            stream,
+            verbose: false,
+        }
+    }
+
+    /// Also print every target that is buried or deleted, and every purged grave
+    pub fn verbose(mut self, verbose: bool) -> Self {
+        self.verbose = verbose;
+        self
+    }
+
+    pub fn into_inner(self) -> W {
+        self.stream
+    }
+}
+
+impl<W: Write> Observer for TextRenderer<W> {
+    fn notify(&mut self, event: &Event) -> io::Result<()> {
+        let stream = &mut self.stream;
+        match event {
+            Event::Unburied { grave, dest } => {
+                writeln!(stream, "Returned {} to {}", grave.display(), dest.display())
+            }
+            Event::Skipped { path } => writeln!(stream, "Skipping {}", path.display()),
+            Event::AlreadyInGraveyard { path } => {
+                writeln!(stream, "{} is already in the graveyard.", path.display())
+            }
+            Event::BigTarget { path, size, is_dir } => writeln!(
+                stream,
+                "About to copy a big {} ({} is {})",
+                if *is_dir { "directory" } else { "file" },
+                path.display(),
+                util::humanize_bytes(*size)
+            ),
+            Event::NonRegularFile { path } => {
+                writeln!(stream, "Non-regular file or directory: {}", path.display())
+            }
+            Event::Inspected {
+                path,
+                size,
+                is_dir,
+                preview,
+            } => {
+                let kind = if *is_dir { "directory" } else { "file" };
+                write!(
+                    stream,
+                    "{}: {}, {}",
+                    path.display(),
+                    kind,
+                    util::humanize_bytes(*size)
+                )?;
+                if *is_dir {
+                    writeln!(stream, " including:")?;
+                } else {
+                    writeln!(stream)?;
+                }
+                match preview {
+                    Some(preview) => {
+                        for line in preview {
+                            if *is_dir {
+                                writeln!(stream, "{}", line)?;
+                            } else {
+                                writeln!(stream, "> {}", line)?;
+                            }
+                        }
+                        Ok(())
+                    }
+                    None => writeln!(stream, "Error reading {}", path.display()),
+                }
+            }
+            Event::Prompted { question } => {
+                write!(stream, "{} (y/N) ", question)?;
+                if stream.flush().is_err() {
+                    // If stdout wasn't flushed properly, fallback to println
+                    writeln!(stream, "{} (y/N)", question)?;
+                }
+                Ok(())
+            }
+            Event::Warning { message } => writeln!(stream, "Warning: {}", message),
+            Event::Buried { source, grave } if self.verbose => {
+                writeln!(stream, "Buried {} in {}", source.display(), grave.display())
+            }
+            Event::Deleted { path } if self.verbose => {
+                writeln!(stream, "Deleted {}", path.display())
+            }
+            Event::Purged { path, size } if self.verbose => writeln!(
+                stream,
+                "Purged {} ({})",
+                path.display(),
+                util::humanize_bytes(*size)
+            ),
+            Event::Compressed {
+                grave,
+                size,
+                compressed_size,
+                ..
+            } if self.verbose => writeln!(
+                stream,
+                "Compressed {} ({} to {})",
+                grave.display(),
+                util::humanize_bytes(*size),
+                util::humanize_bytes(*compressed_size)
+            ),
+            Event::Deduplicated {
+                grave,
+                size,
+                stored,
+                ..
+            } if self.verbose => writeln!(
+                stream,
+                "Deduplicated {} ({} new of {})",
+                grave.display(),
+                util::humanize_bytes(*stored),
+                util::humanize_bytes(*size)
+            ),
+            Event::Encrypted { grave, .. } if self.verbose => {
+                writeln!(stream, "Encrypted {}", grave.display())
+            }
+            // The command line is silent about everything that went as expected
+            Event::Buried { .. }
+            | Event::CopyStarted { .. }
+            | Event::Copying { .. }
+            | Event::Copied { .. }
+            | Event::Deleted { .. }
+            | Event::Purged { .. }
+            | Event::Compressed { .. }
+            | Event::Deduplicated { .. }
+            | Event::Encrypted { .. } => Ok(()),
+        }
+    }
+}
+
+/// Renders events as one JSON object per line, tagged with an `"event"` field
+pub struct JsonRenderer<W: Write> {
+    stream: W,
+}
+
+impl<W: Write> JsonRenderer<W> {
+    pub fn new(stream: W) -> JsonRenderer<W> {
+        JsonRenderer { stream }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.stream
+    }
+}
+
+impl<W: Write> Observer for JsonRenderer<W> {
+    fn notify(&mut self, event: &Event) -> io::Result<()> {
+        serde_json::to_writer(&mut self.stream, event)?;
+        writeln!(self.stream)?;
+        self.stream.flush()
+    }
+}
+
+/// Renders events in the chosen output format
+pub enum Renderer<W: Write> {
+    Text(TextRenderer<W>),
+    Json(JsonRenderer<W>),
+}
+
+impl<W: Write> Renderer<W> {
+    pub fn new(format: OutputFormat, stream: W) -> Renderer<W> {
+        match format {
+            OutputFormat::Text => Renderer::Text(TextRenderer::new(stream)),
+            OutputFormat::Json => Renderer::Json(JsonRenderer::new(stream)),
+        }
+    }
+
+    /// See [`TextRenderer::verbose`]. JSON already includes every event.
+    pub fn verbose(self, verbose: bool) -> Self {
+        match self {
+            Renderer::Text(renderer) => Renderer::Text(renderer.verbose(verbose)),
+            renderer => renderer,
+        }
+    }
+}
+
+impl<W: Write> Observer for Renderer<W> {
+    fn notify(&mut self, event: &Event) -> io::Result<()> {
+        match self {
+            Renderer::Text(renderer) => renderer.notify(event),
+            Renderer::Json(renderer) => renderer.notify(event),
+        }
+    }
+}
+
+/// How often the progress line is redrawn, and the progress of a copy
+/// reported, at most
+pub(crate) const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Passes every event on to another observer, and meanwhile draws a line
+/// with the progress of copies on `stream`, usually a terminal's stderr
+///
+/// Without a stream, it only passes events on.
+pub struct ProgressRenderer<O: Observer, W: Write> {
+    inner: O,
+    stream: Option<W>,
+    copy: Option<CopyProgress>,
+}
+
+struct CopyProgress {
+    files: u64,
+    bytes: u64,
+    files_done: u64,
+    bytes_done: u64,
+    /// What's done of each file that is still being copied
+    in_flight: HashMap<PathBuf, u64>,
+    started: Instant,
+    drawn: Option<Instant>,
+}
+
+impl<O: Observer, W: Write> ProgressRenderer<O, W> {
+    pub fn new(inner: O, stream: Option<W>) -> ProgressRenderer<O, W> {
+        ProgressRenderer {
+            inner,
+            stream,
+            copy: None,
+        }
+    }
+
+    fn draw(&mut self) -> io::Result<()> {
+        let (Some(stream), Some(copy)) = (&mut self.stream, &mut self.copy) else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        if copy
+            .drawn
+            .is_some_and(|drawn| now.duration_since(drawn) < PROGRESS_INTERVAL)
+        {
+            return Ok(());
+        }
+        copy.drawn = Some(now);
+        let bytes_done = copy.bytes_done + copy.in_flight.values().sum::<u64>();
+        let elapsed = now.duration_since(copy.started).as_secs_f64();
+        let throughput = if elapsed > 0.0 {
+            bytes_done as f64 / elapsed
+        } else {
+            0.0
+        };
+        write!(
+            stream,
+            "\r\x1b[KCopying {}/{} files, {}/{}",
+            copy.files_done,
+            copy.files,
+            util::humanize_bytes(bytes_done),
+            util::humanize_bytes(copy.bytes)
+        )?;
+        if throughput > 0.0 {
+            let eta = copy.bytes.saturating_sub(bytes_done) as f64 / throughput;
+            write!(
+                stream,
+                ", {}/s, ETA {}",
+                util::humanize_bytes(throughput as u64),
+                format_eta(Duration::from_secs_f64(eta))
+            )?;
+        }
+        stream.flush()
+    }
+
+    /// Erase the progress line, if one is shown
+    fn clear(&mut self) -> io::Result<()> {
+        let Some(stream) = &mut self.stream else {
+            return Ok(());
+        };
+        if let Some(copy) = &mut self.copy {
+            if copy.drawn.take().is_some() {
+                write!(stream, "\r\x1b[K")?;
+                stream.flush()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<O: Observer, W: Write> Observer for ProgressRenderer<O, W> {
+    fn notify(&mut self, event: &Event) -> io::Result<()> {
+        match event {
+            Event::CopyStarted { files, bytes, .. } => {
+                self.clear()?;
+                self.copy = files.zip(*bytes).map(|(files, bytes)| CopyProgress {
+                    files,
+                    bytes,
+                    files_done: 0,
+                    bytes_done: 0,
+                    in_flight: HashMap::new(),
+                    started: Instant::now(),
+                    drawn: None,
+                });
+            }
+            Event::Copying { from, bytes, .. } => {
+                if let Some(copy) = &mut self.copy {
+                    copy.in_flight.insert(from.clone(), *bytes);
+                    self.draw()?;
+                }
+            }
+            Event::Copied { from, size, .. } => {
+                if let Some(copy) = &mut self.copy {
+                    copy.in_flight.remove(from);
+                    copy.files_done += 1;
+                    copy.bytes_done += size;
+                    if copy.files_done >= copy.files {
+                        self.clear()?;
+                        self.copy = None;
+                    } else {
+                        self.draw()?;
+                    }
+                }
+            }
+            // Make room for whatever else is printed
+            _ => self.clear()?,
+        }
+        self.inner.notify(event)
+    }
+
+    fn shows_progress(&self) -> bool {
+        self.stream.is_some() || self.inner.shows_progress()
+    }
+}
+
+impl<O: Observer, W: Write> Drop for ProgressRenderer<O, W> {
+    fn drop(&mut self) {
+        let _ = self.clear();
+    }
+}
+
+/// `m:ss`, or `h:mm:ss` for an hour or more
+fn format_eta(eta: Duration) -> String {
+    let secs = eta.as_secs();
+    if secs >= 3600 {
+        format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+    } else {
+        format!("{}:{:02}", secs / 60, secs % 60)
+    }
+}
@@ -0,0 +1,211 @@
+use serde::Serialize;
+use std::collections::HashSet;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use walkdir::{WalkDir, WalkDirIterator};
+
+use crate::args::OutputFormat;
+use crate::history::HISTORY;
+use crate::record::{RecordItem, RECORD};
+use crate::{compress, crypt, dedup, graveyard};
+use crate::{util, Error};
+
+/// A file or directory in the graveyard that no entry in the record points at
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Orphan {
+    pub grave: PathBuf,
+    /// Where it was presumably buried from, going by its place in the graveyard
+    pub orig: Option<PathBuf>,
+}
+
+/// What [`Graveyard::fsck`](crate::Graveyard::fsck) found
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct FsckReport {
+    /// Graves that are missing from the record
+    pub orphans: Vec<Orphan>,
+    /// Entries in the record whose grave is gone
+    pub dangling: Vec<RecordItem>,
+    /// Graves with more than one entry in the record
+    pub duplicates: Vec<PathBuf>,
+    /// Lines in the record that can't be parsed
+    pub malformed: Vec<String>,
+    /// Whether orphans were adopted and dangling entries pruned
+    pub repaired: bool,
+}
+
+impl FsckReport {
+    /// The number of problems found
+    pub fn problems(&self) -> usize {
+        self.orphans.len() + self.dangling.len() + self.duplicates.len() + self.malformed.len()
+    }
+
+    /// Print each problem, and what was done about it
+    pub fn show(&self, format: OutputFormat, stream: &mut impl Write) -> Result<(), Error> {
+        if format == OutputFormat::Json {
+            serde_json::to_writer(&mut *stream, self).map_err(io::Error::from)?;
+            writeln!(stream)?;
+            return Ok(());
+        }
+        for orphan in &self.orphans {
+            match &orphan.orig {
+                Some(orig) => writeln!(
+                    stream,
+                    "orphan\t{} (from {})",
+                    orphan.grave.display(),
+                    orig.display()
+                )?,
+                None => writeln!(stream, "orphan\t{}", orphan.grave.display())?,
+            }
+        }
+        for item in &self.dangling {
+            writeln!(
+                stream,
+                "dangling\t{} (from {})",
+                item.dest.display(),
+                item.orig.display()
+            )?;
+        }
+        for grave in &self.duplicates {
+            writeln!(stream, "duplicate\t{}", grave.display())?;
+        }
+        for line in &self.malformed {
+            writeln!(stream, "malformed\t{:?}", line)?;
+        }
+
+        if self.problems() == 0 {
+            writeln!(stream, "No problems found")?;
+        } else if self.repaired {
+            let adopted = self.orphans.iter().filter(|o| o.orig.is_some()).count();
+            writeln!(
+                stream,
+                "Adopted {} orphans and pruned {} dangling entries",
+                adopted,
+                self.dangling.len()
+            )?;
+        } else {
+            writeln!(
+                stream,
+                "Found {} problems (use --repair to adopt orphans and prune dangling entries)",
+                self.problems()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Graves with more than one live entry, in the order they were first buried
+pub(crate) fn find_duplicates(live: &[RecordItem]) -> Vec<PathBuf> {
+    let mut seen = HashSet::new();
+    let mut duplicates = Vec::new();
+    for item in live {
+        if !seen.insert(&item.dest) && !duplicates.contains(&item.dest) {
+            duplicates.push(item.dest.clone());
+        }
+    }
+    duplicates
+}
+
+/// Walk the graveyard for files and directories that aren't one of the
+/// `graves` and don't hold one. Directories left behind by unburied graves
+/// don't count as long as they're empty.
+pub(crate) fn find_orphans(
+    graveyard: &Path,
+    graves: &HashSet<&Path>,
+) -> Result<Vec<Orphan>, Error> {
+    let holds_graves: HashSet<&Path> = graves
+        .iter()
+        .flat_map(|grave| grave.ancestors().skip(1))
+        .filter(|dir| dir.starts_with(graveyard))
+        .collect();
+
+    let mut orphans = Vec::new();
+    let mut walker = WalkDir::new(graveyard)
+        .min_depth(1)
+        .sort_by(|a, b| a.cmp(b))
+        .into_iter();
+    while let Some(entry) = walker.next() {
+        let entry = entry.map_err(|e| Error::Io {
+            context: format!("Failed to walk {}", graveyard.display()),
+            source: io::Error::other(e),
+        })?;
+        let path = entry.path();
+        let is_dir = entry.file_type().is_dir();
+        let name = entry.file_name().to_string_lossy();
+        if entry.depth() == 1 && is_bookkeeping(&name) {
+            continue;
+        }
+        if is_scratch(&name, is_dir) {
+            if is_dir {
+                walker.skip_current_dir();
+            }
+            continue;
+        }
+        if graves.contains(path) {
+            if is_dir {
+                walker.skip_current_dir();
+            }
+            continue;
+        }
+        if is_dir {
+            if holds_graves.contains(path) {
+                continue;
+            }
+            walker.skip_current_dir();
+            if !holds_files(path) {
+                continue;
+            }
+        }
+        orphans.push(Orphan {
+            grave: path.to_path_buf(),
+            orig: util::split_absolute(graveyard, path).map(|orig| plain_orig(&orig)),
+        });
+    }
+    Ok(orphans)
+}
+
+/// The record, its index and the history, and their temporary files
+fn is_bookkeeping(name: &str) -> bool {
+    name.starts_with(RECORD) || name == HISTORY || name == dedup::STORE
+}
+
+/// What rip leaves next to graves while it works on them: the directories
+/// packed graves are unpacked into, and manifests being written
+fn is_scratch(name: &str, is_dir: bool) -> bool {
+    if is_dir {
+        name.strip_suffix(compress::SCRATCH_SUFFIX)
+            .is_some_and(|packed| graveyard::is_packed(Path::new(packed)))
+    } else {
+        name.strip_suffix(dedup::TMP_SUFFIX)
+            .is_some_and(|manifest| dedup::is_manifest(Path::new(manifest)))
+    }
+}
+
+/// `orig` without what rip adds to the names of graves: the suffixes of
+/// packed graves, and the `~N` of graves whose name was taken
+fn plain_orig(orig: &Path) -> PathBuf {
+    let Some(mut name) = orig.file_name().and_then(|name| name.to_str()) else {
+        return orig.to_path_buf();
+    };
+    while let Some(plain) = [compress::SUFFIX, dedup::SUFFIX, crypt::SUFFIX]
+        .iter()
+        .find_map(|suffix| name.strip_suffix(suffix))
+        .or_else(|| unnumbered(name))
+        .filter(|plain| !plain.is_empty())
+    {
+        name = plain;
+    }
+    orig.with_file_name(name)
+}
+
+/// `name` without a `~N` suffix, if it has one
+fn unnumbered(name: &str) -> Option<&str> {
+    let (plain, number) = name.rsplit_once('~')?;
+    (!number.is_empty() && number.bytes().all(|b| b.is_ascii_digit())).then_some(plain)
+}
+
+fn holds_files(dir: &Path) -> bool {
+    WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .any(|entry| !entry.file_type().is_dir())
+}
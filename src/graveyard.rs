@@ -0,0 +1,1138 @@
+use std::collections::HashSet;
+use std::env;
+use std::fs::{self, Metadata};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use walkdir::WalkDir;
+
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+
+use crate::compress::{self, Unpacked};
+use crate::crypt::{self, Key};
+use crate::dedup::{self, Store};
+use crate::error::IoContext;
+use crate::event::{Event, Observer};
+use crate::fsck::{self, FsckReport};
+use crate::history::{History, HistoryEntry, Operation, HISTORY};
+use crate::project::Project;
+use crate::prompt::Prompter;
+use crate::protect::ProtectedPaths;
+use crate::record::{Compaction, Record, RecordItem, DEFAULT_FILE_LOCK};
+use crate::{dir_size, move_target, util, BigFileRule, Error};
+
+const LINES_TO_INSPECT: usize = 6;
+const FILES_TO_INSPECT: usize = 6;
+
+/// A graveyard directory together with its record
+///
+/// ```no_run
+/// use rip2::{BuryOptions, Graveyard, ListFilter, TextRenderer};
+/// use rip2::prompt::StdinPrompter;
+///
+/// let graveyard = Graveyard::open("/tmp/graveyard")?;
+/// let mut renderer = TextRenderer::new(std::io::stdout());
+/// graveyard.bury(&["old.txt"], &BuryOptions::default(), &StdinPrompter, &mut renderer)?;
+/// for grave in graveyard.list(&ListFilter::default())? {
+///     println!("{} was {}", grave.dest.display(), grave.orig.display());
+/// }
+/// # Ok::<(), rip2::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct Graveyard {
+    path: PathBuf,
+    record: Record<DEFAULT_FILE_LOCK>,
+    history: History<DEFAULT_FILE_LOCK>,
+    store: Store,
+    /// Encrypts new graves and decrypts encrypted ones
+    key: Option<Key>,
+    /// How many threads copy directories that can't be renamed
+    copy_jobs: usize,
+}
+
+/// How targets are buried by [`Graveyard::bury`]
+#[derive(Clone, Debug, Default)]
+pub struct BuryOptions {
+    /// Print some info about each target and ask before burying it
+    pub inspect: bool,
+    /// What to do with big targets that have to be copied
+    pub big_file: BigFileRule,
+    /// Allow burying protected paths such as `/` or the home directory
+    pub force_protected: bool,
+    /// Globs to protect on top of the built-in deny list and `$RIP_PROTECTED`
+    pub protected: Vec<String>,
+    /// Per-project rules from a `.riprc`
+    pub project: Option<Project>,
+    /// Put every target back if any of them fails, instead of carrying on
+    pub atomic: bool,
+    /// Refuse to bury directories that contain a mount point
+    pub one_file_system: bool,
+    /// Record every grave with this time, so that they form a batch that
+    /// [`Graveyard::last_batch`] returns together
+    pub batch: Option<String>,
+}
+
+impl BuryOptions {
+    pub fn inspect(mut self, inspect: bool) -> Self {
+        self.inspect = inspect;
+        self
+    }
+
+    pub fn big_file(mut self, big_file: BigFileRule) -> Self {
+        self.big_file = big_file;
+        self
+    }
+
+    pub fn force_protected(mut self, force_protected: bool) -> Self {
+        self.force_protected = force_protected;
+        self
+    }
+
+    pub fn protected(mut self, protected: Vec<String>) -> Self {
+        self.protected = protected;
+        self
+    }
+
+    pub fn project(mut self, project: Option<Project>) -> Self {
+        self.project = project;
+        self
+    }
+
+    pub fn atomic(mut self, atomic: bool) -> Self {
+        self.atomic = atomic;
+        self
+    }
+
+    pub fn one_file_system(mut self, one_file_system: bool) -> Self {
+        self.one_file_system = one_file_system;
+        self
+    }
+
+    pub fn batch(mut self, batch: Option<String>) -> Self {
+        self.batch = batch;
+        self
+    }
+}
+
+/// What happened to a single target passed to [`Graveyard::bury`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BuryOutcome {
+    /// The target was moved into the graveyard
+    Buried { source: PathBuf, grave: PathBuf },
+    /// The target was permanently deleted instead of being buried
+    Deleted { source: PathBuf },
+    /// The target was left in place at the user's request
+    Skipped { source: PathBuf },
+}
+
+/// A grave returned to its original location by [`Graveyard::unbury`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Unburied {
+    pub grave: PathBuf,
+    pub dest: PathBuf,
+}
+
+/// Selects the graves returned by [`Graveyard::list`]
+#[derive(Clone, Debug, Default)]
+pub struct ListFilter {
+    /// Only list graves that were originally under this directory
+    pub under: Option<PathBuf>,
+}
+
+impl ListFilter {
+    pub fn under(dir: impl Into<PathBuf>) -> Self {
+        ListFilter {
+            under: Some(dir.into()),
+        }
+    }
+}
+
+/// Which graves are permanently deleted by [`Graveyard::purge`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PurgePolicy {
+    /// Delete the whole graveyard, including the record, but not the history
+    All,
+    /// Delete graves that were buried longer ago than this
+    OlderThan(Duration),
+    /// Delete the oldest graves until the graveyard holds at most this many bytes
+    OverQuota(u64),
+}
+
+/// Which graves are packed into archives by [`Graveyard::compress`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompressPolicy {
+    /// Compress graves that were buried longer ago than this
+    OlderThan(Duration),
+    /// Compress graves that hold more than this many bytes
+    LargerThan(u64),
+}
+
+impl Graveyard {
+    /// Open the graveyard at `path`, creating it if it doesn't exist yet
+    pub fn open(path: impl Into<PathBuf>) -> Result<Graveyard, Error> {
+        let path = path.into();
+        if !path.exists() {
+            fs::create_dir_all(&path)?;
+
+            #[cfg(unix)]
+            {
+                let metadata = path.metadata()?;
+                let mut permissions = metadata.permissions();
+                permissions.set_mode(0o700);
+            }
+            // TODO: Default permissions on windows should be good, but need to double-check.
+        }
+
+        // Stores the deleted files
+        let record = Record::new(&path)?;
+        let history = History::new(&path);
+        Ok(Graveyard {
+            store: Store::new(&path),
+            key: None,
+            path,
+            record,
+            history,
+            copy_jobs: 1,
+        })
+    }
+
+    /// Open the graveyard from `RIP_GRAVEYARD`, `XDG_DATA_HOME`, or the
+    /// temporary directory, in that order
+    pub fn open_default() -> Result<Graveyard, Error> {
+        Graveyard::open(crate::get_graveyard(None))
+    }
+
+    /// Give up waiting for other processes to release the record after `timeout`
+    pub fn with_lock_timeout(mut self, timeout: Duration) -> Self {
+        self.record = self.record.with_lock_timeout(timeout);
+        self
+    }
+
+    /// Copy directories that can't be renamed with `jobs` threads. With
+    /// one, the default, they are copied one file at a time.
+    pub fn with_copy_jobs(mut self, jobs: usize) -> Self {
+        self.copy_jobs = jobs;
+        self
+    }
+
+    /// Encrypt graves with `key` in [`Graveyard::encrypt`], and decrypt
+    /// them with it when they are unburied or read
+    pub fn with_key(mut self, key: Option<Key>) -> Self {
+        self.key = key;
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn record(&self) -> &Record<DEFAULT_FILE_LOCK> {
+        &self.record
+    }
+
+    pub fn history(&self) -> &History<DEFAULT_FILE_LOCK> {
+        &self.history
+    }
+
+    /// The directory in the graveyard holding the graves from `dir`
+    pub fn grave_path(&self, dir: &Path) -> Result<PathBuf, Error> {
+        let dir = dunce::canonicalize(dir)
+            .context(|| format!("Failed to canonicalize {}", dir.display()))?;
+        Ok(util::join_absolute(&self.path, dir))
+    }
+
+    /// Bury each target in turn. A target that fails doesn't stop the
+    /// others, and the failures are returned together at the end as
+    /// [`Error::TargetsFailed`]. With [`BuryOptions::atomic`], the first
+    /// failure stops the bury and the targets buried so far are put back.
+    ///
+    /// Quitting at a prompt always stops the bury.
+    pub fn bury<P: AsRef<Path>>(
+        &self,
+        targets: &[P],
+        opts: &BuryOptions,
+        prompter: &impl Prompter,
+        observer: &mut impl Observer,
+    ) -> Result<Vec<BuryOutcome>, Error> {
+        let cwd = &env::current_dir()?;
+        let allow_rename = util::allow_rename();
+        let protected = if opts.force_protected {
+            None
+        } else {
+            Some(ProtectedPaths::new(&self.path)?.with_patterns(&opts.protected)?)
+        };
+        let mut outcomes = Vec::new();
+        let mut failures = Vec::new();
+        for target in targets {
+            let target = target.as_ref();
+            match self.bury_target(
+                target,
+                cwd,
+                allow_rename,
+                opts,
+                protected.as_ref(),
+                prompter,
+                observer,
+            ) {
+                Ok(outcome) => outcomes.push(outcome),
+                Err(e) if opts.atomic => {
+                    self.rollback(&outcomes, prompter, observer)?;
+                    return Err(e);
+                }
+                Err(Error::UserAborted) => return Err(Error::UserAborted),
+                Err(e) => failures.push((target.to_path_buf(), e)),
+            }
+        }
+        Error::from_failures(targets.len(), failures)?;
+        Ok(outcomes)
+    }
+
+    /// Put back the targets buried by [`Graveyard::bury`]. Targets that
+    /// were permanently deleted are gone for good.
+    pub fn rollback(
+        &self,
+        outcomes: &[BuryOutcome],
+        prompter: &impl Prompter,
+        observer: &mut impl Observer,
+    ) -> Result<(), Error> {
+        let graves: Vec<PathBuf> = outcomes
+            .iter()
+            .rev()
+            .filter_map(|outcome| match outcome {
+                BuryOutcome::Buried { grave, .. } => Some(grave.clone()),
+                _ => None,
+            })
+            .collect();
+        if !graves.is_empty() {
+            self.unbury(&graves, prompter, observer)?;
+        }
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn bury_target(
+        &self,
+        target: &Path,
+        cwd: &Path,
+        allow_rename: bool,
+        opts: &BuryOptions,
+        protected: Option<&ProtectedPaths>,
+        prompter: &impl Prompter,
+        observer: &mut impl Observer,
+    ) -> Result<BuryOutcome, Error> {
+        let graveyard = &self.path;
+        // Check if source exists
+        let metadata = &fs::symlink_metadata(target).map_err(|_| Error::NotFound {
+            path: target.to_path_buf(),
+        })?;
+        // Canonicalize the path unless it's a symlink
+        let source = &if !metadata.file_type().is_symlink() {
+            dunce::canonicalize(cwd.join(target))
+                .context(|| format!("Failed to canonicalize {}", target.display()))?
+        } else {
+            cwd.join(target)
+        };
+
+        if let Some(protected) = protected {
+            protected.check(source)?;
+            if let Some(project) = &opts.project {
+                project.check(source)?;
+            }
+        }
+        if opts.one_file_system && metadata.is_dir() {
+            check_one_file_system(source)?;
+        }
+
+        if dunce::canonicalize(graveyard).is_ok_and(|graveyard| *source == graveyard) {
+            // Burying the graveyard itself would also destroy the record
+            return Err(Error::AlreadyInGraveyard {
+                path: source.clone(),
+            });
+        }
+
+        let hard_delete = opts
+            .project
+            .as_ref()
+            .is_some_and(|project| project.is_hard_delete(source))
+            && !source.starts_with(graveyard);
+        if opts.inspect
+            && !should_we_bury_this(target, source, metadata, hard_delete, prompter, observer)?
+        {
+            // User chose to not bury the file
+            observer.notify(&Event::Skipped {
+                path: source.clone(),
+            })?;
+            Ok(BuryOutcome::Skipped {
+                source: source.clone(),
+            })
+        } else if hard_delete {
+            if metadata.is_dir() {
+                fs::remove_dir_all(source)
+            } else {
+                fs::remove_file(source)
+            }
+            .context(|| format!("Couldn't unlink {}", source.display()))?;
+            self.log(Operation::Delete, Some(source), None, observer)?;
+            observer.notify(&Event::Deleted {
+                path: source.clone(),
+            })?;
+            Ok(BuryOutcome::Deleted {
+                source: source.clone(),
+            })
+        } else if source.starts_with(graveyard) {
+            // If rip is called on a file already in the graveyard, prompt
+            // to permanently delete it instead.
+            observer.notify(&Event::AlreadyInGraveyard {
+                path: source.clone(),
+            })?;
+            if util::prompt_yes("Permanently unlink it?", prompter, observer)? {
+                if fs::remove_dir_all(source).is_err() {
+                    fs::remove_file(source)
+                        .context(|| format!("Couldn't unlink {}", source.display()))?;
+                }
+                self.log(Operation::Delete, None, Some(source), observer)?;
+                observer.notify(&Event::Deleted {
+                    path: source.clone(),
+                })?;
+                Ok(BuryOutcome::Deleted {
+                    source: source.clone(),
+                })
+            } else {
+                observer.notify(&Event::Skipped {
+                    path: source.clone(),
+                })?;
+                // TODO: In the original code, this was a hard return from the entire
+                // method (i.e., `run`). I think it should just be a return from the bury
+                // (meaning a `continue` in the original code's loop). But I'm not sure.
+                Ok(BuryOutcome::Skipped {
+                    source: source.clone(),
+                })
+            }
+        } else {
+            let dest: &Path = &{
+                let dest = util::join_absolute(graveyard, source);
+                // Resolve a name conflict if necessary, including with
+                // the name of a compressed, deduplicated or encrypted grave
+                if util::symlink_exists(&dest) || is_packed(&dest) {
+                    util::rename_grave(dest)
+                } else {
+                    dest
+                }
+            };
+
+            let moved = move_target(
+                source,
+                dest,
+                allow_rename,
+                Some(opts.big_file),
+                self.copy_jobs,
+                prompter,
+                observer,
+            )
+            .inspect_err(|_| {
+                // Clean up any partial buries due to permission error
+                fs::remove_dir_all(dest).ok();
+            })?;
+
+            if moved {
+                match &opts.batch {
+                    Some(time) => self.record.write_log_at(time, source, dest)?,
+                    None => self.record.write_log(source, dest)?,
+                }
+                self.log(Operation::Bury, Some(source), Some(dest), observer)?;
+                observer.notify(&Event::Buried {
+                    source: source.clone(),
+                    grave: dest.to_path_buf(),
+                })?;
+                Ok(BuryOutcome::Buried {
+                    source: source.clone(),
+                    grave: dest.to_path_buf(),
+                })
+            } else {
+                self.log(Operation::Delete, Some(source), None, observer)?;
+                observer.notify(&Event::Deleted {
+                    path: source.clone(),
+                })?;
+                Ok(BuryOutcome::Deleted {
+                    source: source.clone(),
+                })
+            }
+        }
+    }
+
+    fn log(
+        &self,
+        operation: Operation,
+        orig: Option<&Path>,
+        grave: Option<&Path>,
+        observer: &mut impl Observer,
+    ) -> Result<(), Error> {
+        self.append_history(&[HistoryEntry::new(operation, orig, grave)], observer)
+    }
+
+    /// Add `entries` to the history. What they record is done by then, so
+    /// failing to write them is only a warning.
+    fn append_history(
+        &self,
+        entries: &[HistoryEntry],
+        observer: &mut impl Observer,
+    ) -> Result<(), Error> {
+        if let Err(e) = self.history.append(entries) {
+            observer.notify(&Event::Warning {
+                message: e.to_string(),
+            })?;
+        }
+        Ok(())
+    }
+
+    /// The grave of the most recently buried target that is still in the graveyard
+    pub fn last_bury(&self) -> Result<PathBuf, Error> {
+        self.record.get_last_bury()
+    }
+
+    /// The graves buried in the same batch as the most recent one, newest
+    /// first. Without [`BuryOptions::batch`], that's just the most recent one.
+    pub fn last_batch(&self) -> Result<Vec<PathBuf>, Error> {
+        self.record.get_last_batch()
+    }
+
+    /// Return the given graves to where they were buried from, and remove
+    /// them from the record
+    pub fn unbury(
+        &self,
+        graves: &[PathBuf],
+        prompter: &impl Prompter,
+        observer: &mut impl Observer,
+    ) -> Result<Vec<Unburied>, Error> {
+        let allow_rename = util::allow_rename();
+        let mut unburied = Vec::new();
+
+        // Go through the graveyard and exhume all the graves
+        for entry in self.record.lines_of_graves(graves)? {
+            let orig: PathBuf = match util::symlink_exists(&entry.orig) {
+                true => util::rename_grave(&entry.orig),
+                false => PathBuf::from(&entry.orig),
+            };
+            self.exhume(&entry.dest, &orig, allow_rename, prompter, observer)
+                .map_err(|e| Error::UnburyFailed {
+                    grave: entry.dest.clone(),
+                    dest: orig.clone(),
+                    source: Box::new(e),
+                })?;
+            observer.notify(&Event::Unburied {
+                grave: entry.dest.clone(),
+                dest: orig.clone(),
+            })?;
+            unburied.push(Unburied {
+                grave: entry.dest,
+                dest: orig,
+            });
+        }
+        self.record.log_exhumed_graves(graves)?;
+        let history: Vec<HistoryEntry> = unburied
+            .iter()
+            .map(|item| HistoryEntry::new(Operation::Unbury, Some(&item.dest), Some(&item.grave)))
+            .collect();
+        self.append_history(&history, observer)?;
+        Ok(unburied)
+    }
+
+    /// Move `grave` to `dest`, unpacking it first if it is compressed,
+    /// deduplicated or encrypted
+    fn exhume(
+        &self,
+        grave: &Path,
+        dest: &Path,
+        allow_rename: bool,
+        prompter: &impl Prompter,
+        observer: &mut impl Observer,
+    ) -> Result<(), Error> {
+        let unpacked = self.unpack(grave)?;
+        move_target(
+            unpacked.as_ref().map_or(grave, |unpacked| &unpacked.grave),
+            dest,
+            allow_rename,
+            None,
+            self.copy_jobs,
+            prompter,
+            observer,
+        )?;
+        if unpacked.is_some() {
+            self.remove_grave(grave)?;
+        }
+        Ok(())
+    }
+
+    /// Rebuild `grave` in a scratch directory if it is compressed,
+    /// deduplicated or encrypted
+    fn unpack(&self, grave: &Path) -> Result<Option<Unpacked>, Error> {
+        if compress::is_archive(grave) {
+            Ok(Some(compress::unpack(grave)?))
+        } else if dedup::is_manifest(grave) {
+            Ok(Some(self.store.restore(grave)?))
+        } else if crypt::is_sealed(grave) {
+            Ok(Some(crypt::unseal(grave, self.key(grave)?)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// The key to encrypt graves with
+    pub(crate) fn encryption_key(&self) -> Result<&Key, Error> {
+        self.key.as_ref().ok_or_else(|| {
+            Error::InvalidInput(format!(
+                "encrypt is set, but neither ${} nor key-file is",
+                crypt::PASSPHRASE_ENV
+            ))
+        })
+    }
+
+    /// The key to decrypt `grave` with
+    fn key(&self, grave: &Path) -> Result<&Key, Error> {
+        self.key.as_ref().ok_or_else(|| {
+            Error::InvalidInput(format!(
+                "{} is encrypted: set ${} or key-file to decrypt it",
+                grave.display(),
+                crypt::PASSPHRASE_ENV
+            ))
+        })
+    }
+
+    /// Copy a buried file to `stream`, reading it from the archive or the
+    /// store if its grave is compressed, deduplicated or encrypted. `path`
+    /// is either where the file was buried from or its grave, and may lie
+    /// inside a buried directory.
+    pub fn cat(&self, path: &Path, stream: &mut impl Write) -> Result<(), Error> {
+        let (grave, inner) = self.find_buried(path)?;
+        if compress::is_archive(&grave) {
+            return compress::cat(&grave, &inner, stream);
+        }
+        if dedup::is_manifest(&grave) {
+            return self.store.cat(&grave, &inner, stream);
+        }
+        if crypt::is_sealed(&grave) {
+            return crypt::cat(&grave, self.key(&grave)?, &inner, stream);
+        }
+        let file = if inner.as_os_str().is_empty() {
+            grave
+        } else {
+            grave.join(inner)
+        };
+        let context = || format!("Failed to read {}", file.display());
+        let metadata = match fs::symlink_metadata(&file) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(Error::NotBuried { path: file });
+            }
+            Err(e) => return Err(e).context(context),
+        };
+        if metadata.is_dir() {
+            return Err(Error::InvalidInput(format!(
+                "{} is not a file",
+                file.display()
+            )));
+        }
+        io::copy(&mut fs::File::open(&file).context(context)?, stream).context(context)?;
+        Ok(())
+    }
+
+    /// The newest grave holding `path`, and where `path` is inside it
+    fn find_buried(&self, path: &Path) -> Result<(PathBuf, PathBuf), Error> {
+        let path = env::current_dir()?.join(path);
+        let in_graveyard = path.starts_with(&self.path);
+        // The file itself is gone, but the directories above it may not be
+        let path = match in_graveyard {
+            true => path,
+            false => path
+                .ancestors()
+                .find_map(|ancestor| {
+                    let canonical = dunce::canonicalize(ancestor).ok()?;
+                    Some(canonical.join(path.strip_prefix(ancestor).ok()?))
+                })
+                .unwrap_or(path),
+        };
+
+        for ancestor in path.ancestors() {
+            let graves: Vec<RecordItem> = if in_graveyard {
+                self.record
+                    .lines_of_graves(&[ancestor.to_path_buf()])?
+                    .collect()
+            } else {
+                self.record
+                    .buried_from(ancestor)?
+                    .filter(|item| item.orig == ancestor)
+                    .collect()
+            };
+            if let Some(grave) = graves
+                .into_iter()
+                .rev()
+                .find(|item| util::symlink_exists(&item.dest))
+            {
+                let inner = path.strip_prefix(ancestor).unwrap_or(Path::new(""));
+                return Ok((grave.dest, inner.to_path_buf()));
+            }
+            if ancestor == self.path {
+                break;
+            }
+        }
+        Err(Error::NotBuried { path })
+    }
+
+    /// How much `grave` holds. Compressed, deduplicated and encrypted
+    /// graves count what they restore to, and graves that are gone count
+    /// as empty.
+    pub fn size(&self, grave: &Path) -> Result<u64, Error> {
+        if !util::symlink_exists(grave) {
+            Ok(0)
+        } else if compress::is_archive(grave) {
+            compress::unpacked_size(grave)
+        } else if dedup::is_manifest(grave) {
+            self.store.size(grave)
+        } else if crypt::is_sealed(grave) {
+            crypt::size(grave)
+        } else {
+            grave_size(grave)
+        }
+    }
+
+    /// How much of the disk `grave` takes up. Deduplicated graves count
+    /// their share of the store.
+    fn disk_size(&self, grave: &Path) -> Result<u64, Error> {
+        if dedup::is_manifest(grave) && util::symlink_exists(grave) {
+            Ok(grave_size(grave)? + self.store.share(grave)?)
+        } else {
+            grave_size(grave)
+        }
+    }
+
+    /// Record `replacement` in place of the grave of `item`, marking the
+    /// grave with `tombstone`, and remove the grave. The replacement keeps
+    /// the time of the bury, so that retention still counts from it.
+    fn replace_grave(
+        &self,
+        item: &RecordItem,
+        replacement: &Path,
+        tombstone: fn(&Record<DEFAULT_FILE_LOCK>, &[PathBuf]) -> Result<(), Error>,
+        operation: Operation,
+        observer: &mut impl Observer,
+    ) -> Result<(), Error> {
+        self.record
+            .write_log_at(&item.time, &item.orig, replacement)?;
+        tombstone(&self.record, std::slice::from_ref(&item.dest))?;
+        self.remove_grave(&item.dest)?;
+        self.log(operation, Some(&item.orig), Some(replacement), observer)
+    }
+
+    /// Delete `grave`, and release the blobs of a deduplicated one
+    fn remove_grave(&self, grave: &Path) -> Result<(), Error> {
+        let context = || format!("Failed to remove {}", grave.display());
+        let hashes = match dedup::is_manifest(grave) {
+            true => self.store.hashes(grave)?,
+            false => Vec::new(),
+        };
+        if fs::symlink_metadata(grave).context(context)?.is_dir() {
+            fs::remove_dir_all(grave)
+        } else {
+            fs::remove_file(grave)
+        }
+        .context(context)?;
+        self.store.release(&hashes)
+    }
+
+    /// Return the entries in the record matching `filter`, oldest first
+    pub fn list(&self, filter: &ListFilter) -> Result<Vec<RecordItem>, Error> {
+        let gravepath = match &filter.under {
+            Some(dir) => self.grave_path(dir)?,
+            None => self.path.clone(),
+        };
+        let graves = self.record.seance(&gravepath)?.collect();
+        Ok(graves)
+    }
+
+    /// Drop unburied and purged graves from the record
+    pub fn compact(&self) -> Result<Compaction, Error> {
+        self.record.compact()
+    }
+
+    /// Check the record against the contents of the graveyard. With `repair`,
+    /// orphaned graves are added to the record and entries whose grave is
+    /// gone are dropped from it; duplicate and malformed lines are only
+    /// reported.
+    pub fn fsck(&self, repair: bool) -> Result<FsckReport, Error> {
+        let scan = self.record.scan()?;
+        let graves: HashSet<&Path> = scan.live.iter().map(|item| item.dest.as_path()).collect();
+        let orphans = fsck::find_orphans(&self.path, &graves)?;
+        let duplicates = fsck::find_duplicates(&scan.live);
+        let mut seen = HashSet::new();
+        let dangling: Vec<RecordItem> = scan
+            .live
+            .iter()
+            .filter(|item| !util::symlink_exists(&item.dest) && seen.insert(&item.dest))
+            .cloned()
+            .collect();
+
+        if repair {
+            let mut history = Vec::new();
+            for orphan in &orphans {
+                if let Some(orig) = &orphan.orig {
+                    self.record.write_log(orig, &orphan.grave)?;
+                    history.push(HistoryEntry::new(
+                        Operation::Adopt,
+                        Some(orig),
+                        Some(&orphan.grave),
+                    ));
+                }
+            }
+            let pruned: Vec<PathBuf> = dangling.iter().map(|item| item.dest.clone()).collect();
+            if !pruned.is_empty() {
+                self.record.log_missing_graves(&pruned)?;
+            }
+            self.history.append(&history)?;
+        }
+
+        Ok(FsckReport {
+            orphans,
+            dangling,
+            duplicates,
+            malformed: scan.malformed,
+            repaired: repair,
+        })
+    }
+
+    /// Permanently delete the graves selected by `policy`, returning their paths.
+    /// After [`PurgePolicy::All`] only the history is left in the graveyard,
+    /// which has to be opened again before further use.
+    pub fn purge(
+        &self,
+        policy: PurgePolicy,
+        observer: &mut impl Observer,
+    ) -> Result<Vec<PathBuf>, Error> {
+        let graves: Vec<RecordItem> = match policy {
+            PurgePolicy::All => self.record.seance(&self.path)?.collect(),
+            PurgePolicy::OverQuota(quota) => {
+                // Oldest graves go first
+                let sizes = self
+                    .record
+                    .seance(&self.path)?
+                    .map(|item| Ok((self.disk_size(&item.dest)?, item)))
+                    .collect::<Result<Vec<_>, Error>>()?;
+                let mut total: u64 = sizes.iter().map(|(size, _)| size).sum();
+                let mut graves = Vec::new();
+                for (size, grave) in sizes {
+                    if total <= quota {
+                        break;
+                    }
+                    total -= size;
+                    graves.push(grave);
+                }
+                graves
+            }
+            PurgePolicy::OlderThan(age) => match cutoff(age) {
+                // Graves with an unparseable time are kept
+                Some(cutoff) => self.record.buried_before(cutoff)?.collect(),
+                None => Vec::new(),
+            },
+        };
+
+        // Graves that are already gone are just dropped from the record
+        let mut purged = Vec::new();
+        for grave in graves.iter().map(|item| &item.dest) {
+            if util::symlink_exists(grave) {
+                let size = self.disk_size(grave)?;
+                purged.push(Event::Purged {
+                    path: grave.clone(),
+                    size,
+                });
+                if policy == PurgePolicy::All {
+                    continue;
+                }
+                self.remove_grave(grave)?;
+            }
+        }
+
+        let mut history: Vec<HistoryEntry> = graves
+            .iter()
+            .map(|item| HistoryEntry::new(Operation::Purge, Some(&item.orig), Some(&item.dest)))
+            .collect();
+        let graves: Vec<PathBuf> = graves.into_iter().map(|item| item.dest).collect();
+        if policy == PurgePolicy::All {
+            self.decompose()?;
+            history.push(HistoryEntry::new(
+                Operation::Decompose,
+                None,
+                Some(&self.path),
+            ));
+        } else {
+            self.record.log_purged_graves(&graves)?;
+        }
+        self.append_history(&history, observer)?;
+        for event in &purged {
+            observer.notify(event)?;
+        }
+        Ok(graves)
+    }
+
+    /// Pack the graves selected by `policy` into archives, returning the
+    /// archives. Graves that are compressed, deduplicated or encrypted
+    /// already, and those holding anything but files, directories and
+    /// symlinks, are left alone. Only graves that the last pass with the
+    /// same policy didn't look at are considered.
+    pub fn compress(
+        &self,
+        policy: CompressPolicy,
+        observer: &mut impl Observer,
+    ) -> Result<Vec<PathBuf>, Error> {
+        let (pass, (graves, cursor)) = match policy {
+            CompressPolicy::OlderThan(age) => {
+                let pass = format!("compress-after {}", age.as_secs());
+                let Some(cutoff) = cutoff(age) else {
+                    // Nothing was buried that long ago
+                    return Ok(Vec::new());
+                };
+                let found = self.record.buried_before_since(&pass, cutoff)?;
+                (pass, found)
+            }
+            CompressPolicy::LargerThan(limit) => {
+                let pass = format!("compress-above {}", limit);
+                let (items, cursor) = self.record.recorded_since(&pass)?;
+                let mut graves = Vec::new();
+                for item in items {
+                    if !is_packed(&item.dest) && grave_size(&item.dest)? > limit {
+                        graves.push(item);
+                    }
+                }
+                (pass, (graves, cursor))
+            }
+        };
+
+        let mut archives = Vec::new();
+        for item in graves {
+            let grave = &item.dest;
+            if !can_repack(grave) {
+                continue;
+            }
+            let size = grave_size(grave)?;
+            let archive = compress::pack(grave)?;
+            self.replace_grave(
+                &item,
+                &archive,
+                Record::log_compressed_graves,
+                Operation::Compress,
+                observer,
+            )?;
+            observer.notify(&Event::Compressed {
+                grave: grave.clone(),
+                archive: archive.clone(),
+                size,
+                compressed_size: grave_size(&archive)?,
+            })?;
+            archives.push(archive);
+        }
+        self.record.advance(&pass, cursor)?;
+        Ok(archives)
+    }
+
+    /// Move the contents of every grave buried since the last pass into the
+    /// store, so that files that were buried several times are kept once,
+    /// and return the manifests. Graves that are compressed, deduplicated or
+    /// encrypted already, and those holding anything but files, directories
+    /// and symlinks, are left alone.
+    pub fn dedup(&self, observer: &mut impl Observer) -> Result<Vec<PathBuf>, Error> {
+        let (graves, cursor) = self.record.recorded_since("dedup")?;
+        let mut manifests = Vec::new();
+        for item in graves {
+            let grave = &item.dest;
+            if !can_repack(grave) {
+                continue;
+            }
+            let added = self.store.add(grave)?;
+            self.replace_grave(
+                &item,
+                &added.manifest,
+                Record::log_deduplicated_graves,
+                Operation::Dedup,
+                observer,
+            )?;
+            observer.notify(&Event::Deduplicated {
+                grave: grave.clone(),
+                manifest: added.manifest.clone(),
+                size: added.size,
+                stored: added.stored,
+            })?;
+            manifests.push(added.manifest);
+        }
+        self.record.advance("dedup", cursor)?;
+        Ok(manifests)
+    }
+
+    /// Encrypt every grave buried since the last pass that isn't yet,
+    /// returning the encrypted graves. Compressed and deduplicated graves
+    /// are unpacked first, so that nothing is left readable. Graves holding
+    /// anything but files, directories and symlinks are left alone.
+    pub fn encrypt(&self, observer: &mut impl Observer) -> Result<Vec<PathBuf>, Error> {
+        let key = self.encryption_key()?;
+        let (graves, cursor) = self.record.recorded_since("encrypt")?;
+        let mut sealed = Vec::new();
+        for item in graves {
+            let grave = &item.dest;
+            if !util::symlink_exists(grave) || crypt::is_sealed(grave) {
+                continue;
+            }
+            let unpacked = self.unpack(grave)?;
+            let contents = unpacked.as_ref().map_or(grave.as_path(), |u| &u.grave);
+            if !compress::can_pack(contents) {
+                continue;
+            }
+            // Named after the grave, without the suffix of an archive or
+            // manifest
+            let plain = plain_grave(grave);
+            let name = plain
+                .file_name()
+                .ok_or_else(|| Error::InvalidInput(format!("Can't encrypt {}", grave.display())))?;
+            let encrypted = crypt::seal(contents, name, self.size(grave)?, &plain, key)?;
+            drop(unpacked);
+            self.replace_grave(
+                &item,
+                &encrypted,
+                Record::log_encrypted_graves,
+                Operation::Encrypt,
+                observer,
+            )?;
+            observer.notify(&Event::Encrypted {
+                grave: grave.clone(),
+                sealed: encrypted.clone(),
+            })?;
+            sealed.push(encrypted);
+        }
+        self.record.advance("encrypt", cursor)?;
+        Ok(sealed)
+    }
+
+    /// Delete everything in the graveyard except the history
+    fn decompose(&self) -> Result<(), Error> {
+        let context = || format!("Failed to remove {}", self.path.display());
+        for entry in fs::read_dir(&self.path).context(context)? {
+            let entry = entry.context(context)?;
+            if entry.file_name() == HISTORY {
+                continue;
+            }
+            let path = entry.path();
+            if entry.file_type().context(context)?.is_dir() {
+                fs::remove_dir_all(&path)
+            } else {
+                fs::remove_file(&path)
+            }
+            .context(|| format!("Failed to remove {}", path.display()))?;
+        }
+        Ok(())
+    }
+}
+
+/// The time `age` ago, or `None` if that's before any time we can represent
+fn cutoff(age: Duration) -> Option<chrono::DateTime<chrono::FixedOffset>> {
+    let age = chrono::Duration::from_std(age).ok()?;
+    chrono::Local::now().fixed_offset().checked_sub_signed(age)
+}
+
+/// Whether `grave` is compressed, deduplicated or encrypted
+pub(crate) fn is_packed(grave: &Path) -> bool {
+    compress::is_archive(grave) || dedup::is_manifest(grave) || crypt::is_sealed(grave)
+}
+
+/// Whether `grave` is still there and can be compressed or deduplicated:
+/// it isn't packed yet, and holds only files, directories and symlinks
+fn can_repack(grave: &Path) -> bool {
+    util::symlink_exists(grave) && !is_packed(grave) && compress::can_pack(grave)
+}
+
+/// Return an error if anything under `dir` is on another file system
+#[cfg(unix)]
+fn check_one_file_system(dir: &Path) -> Result<(), Error> {
+    use std::os::unix::fs::MetadataExt;
+    let dev = fs::symlink_metadata(dir)
+        .context(|| format!("Failed to read metadata of {}", dir.display()))?
+        .dev();
+    for entry in WalkDir::new(dir).min_depth(1) {
+        let Ok(entry) = entry else { continue };
+        if entry.metadata().is_ok_and(|metadata| metadata.dev() != dev) {
+            return Err(Error::OtherFileSystem {
+                path: dir.to_path_buf(),
+                inner: entry.path().to_path_buf(),
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_one_file_system(_dir: &Path) -> Result<(), Error> {
+    Ok(())
+}
+
+/// `grave` without the suffix of a compressed or deduplicated grave
+fn plain_grave(grave: &Path) -> PathBuf {
+    let name = grave.file_name().and_then(|name| name.to_str());
+    [compress::SUFFIX, dedup::SUFFIX]
+        .iter()
+        .find_map(|suffix| name?.strip_suffix(suffix))
+        .map_or_else(|| grave.to_path_buf(), |plain| grave.with_file_name(plain))
+}
+
+/// The size of a grave on disk, or 0 if it no longer exists
+fn grave_size(grave: &Path) -> Result<u64, Error> {
+    match fs::symlink_metadata(grave) {
+        Ok(metadata) if metadata.is_dir() => dir_size(grave),
+        Ok(metadata) => Ok(metadata.len()),
+        Err(_) => Ok(0),
+    }
+}
+
+fn should_we_bury_this(
+    target: &Path,
+    source: &PathBuf,
+    metadata: &Metadata,
+    hard_delete: bool,
+    prompter: &impl Prompter,
+    observer: &mut impl Observer,
+) -> Result<bool, Error> {
+    let (size, preview) = if metadata.is_dir() {
+        // Get the size of the directory and all its contents,
+        // and the first few top-level files in it
+        let preview = WalkDir::new(source)
+            .sort_by(|a, b| a.cmp(b))
+            .min_depth(1)
+            .max_depth(1)
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .take(FILES_TO_INSPECT)
+            .map(|entry| entry.path().display().to_string())
+            .collect();
+        (dir_size(source)?, Some(preview))
+    } else {
+        // Read the file and get the first few lines
+        let preview = fs::File::open(source).ok().map(|source_file| {
+            BufReader::new(source_file)
+                .lines()
+                .take(LINES_TO_INSPECT)
+                .filter_map(|line| line.ok())
+                .collect()
+        });
+        (metadata.len(), preview)
+    };
+    observer.notify(&Event::Inspected {
+        path: target.to_path_buf(),
+        size,
+        is_dir: metadata.is_dir(),
+        preview,
+    })?;
+    util::prompt_yes(
+        if hard_delete {
+            format!("Permanently delete {}?", target.display())
+        } else {
+            format!("Send {} to the graveyard?", target.to_str().unwrap())
+        },
+        prompter,
+        observer,
+    )
+}
@@ -0,0 +1,188 @@
+use chrono::Local;
+use fs4::fs_std::FileExt;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use crate::args::OutputFormat;
+use crate::error::IoContext;
+use crate::{util, Error};
+
+pub const HISTORY: &str = ".history";
+
+/// Something that happened in the graveyard
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Operation {
+    /// A target was moved into the graveyard
+    Bury,
+    /// A grave was returned to where it came from
+    Unbury,
+    /// A target was permanently deleted instead of being buried
+    Delete,
+    /// A grave was permanently deleted from the graveyard
+    Purge,
+    /// The whole graveyard was deleted
+    Decompose,
+    /// A grave that was missing from the record was added back by `rip fsck`
+    Adopt,
+    /// A grave was packed into an archive
+    Compress,
+    /// A grave was moved into the store
+    Dedup,
+    /// A grave was encrypted
+    Encrypt,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operation::Bury => write!(f, "bury"),
+            Operation::Unbury => write!(f, "unbury"),
+            Operation::Delete => write!(f, "delete"),
+            Operation::Purge => write!(f, "purge"),
+            Operation::Decompose => write!(f, "decompose"),
+            Operation::Adopt => write!(f, "adopt"),
+            Operation::Compress => write!(f, "compress"),
+            Operation::Dedup => write!(f, "dedup"),
+            Operation::Encrypt => write!(f, "encrypt"),
+        }
+    }
+}
+
+/// A line in the history
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub time: String,
+    pub operation: Operation,
+    pub user: String,
+    pub host: String,
+    /// Where the target lived outside the graveyard
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "util::serde_path::option"
+    )]
+    pub orig: Option<PathBuf>,
+    /// The grave, or the graveyard itself for [`Operation::Decompose`]
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "util::serde_path::option"
+    )]
+    pub grave: Option<PathBuf>,
+}
+
+impl HistoryEntry {
+    /// An entry for something the current user is doing right now
+    pub fn new(operation: Operation, orig: Option<&Path>, grave: Option<&Path>) -> HistoryEntry {
+        HistoryEntry {
+            time: Local::now().to_rfc3339(),
+            operation,
+            user: util::get_user(),
+            host: util::get_host(),
+            orig: orig.map(Path::to_path_buf),
+            grave: grave.map(Path::to_path_buf),
+        }
+    }
+}
+
+/// An audit trail of everything done to a graveyard, kept next to the record
+///
+/// Unlike the record, the history is never compacted and survives
+/// `--decompose`, so it also covers graves that are long gone. It holds
+/// one JSON object per line.
+#[derive(Clone, Debug)]
+pub struct History<const FILE_LOCK: bool> {
+    path: PathBuf,
+}
+
+impl<const FILE_LOCK: bool> History<FILE_LOCK> {
+    pub fn new(graveyard: &Path) -> History<FILE_LOCK> {
+        History {
+            path: graveyard.join(HISTORY),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Add entries to the end of the history
+    pub fn append(&self, entries: &[HistoryEntry]) -> Result<(), Error> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        let context = || format!("Failed to write history {}", self.path.display());
+        let mut buffer = Vec::new();
+        for entry in entries {
+            serde_json::to_writer(&mut buffer, entry)
+                .map_err(io::Error::from)
+                .context(context)?;
+            buffer.push(b'\n');
+        }
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .context(context)?;
+        if FILE_LOCK {
+            file.lock_exclusive().context(context)?;
+        }
+        // Write all entries at once, so that they can't be torn apart
+        file.write_all(&buffer).context(context)
+    }
+
+    /// Every entry in the history, oldest first. Lines that can't be
+    /// parsed are skipped.
+    pub fn read(&self) -> Result<Vec<HistoryEntry>, Error> {
+        let context = || format!("Failed to read history {}", self.path.display());
+        let file = match fs::File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).context(context),
+        };
+        if FILE_LOCK {
+            file.lock_shared().context(context)?;
+        }
+        let mut entries = Vec::new();
+        for line in BufReader::new(&file).lines() {
+            if let Ok(entry) = serde_json::from_str(&line.context(context)?) {
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Print the history, oldest first
+    pub fn show(&self, format: OutputFormat, stream: &mut impl Write) -> Result<(), Error> {
+        let entries = self.read()?;
+        if format == OutputFormat::Json {
+            for entry in entries {
+                serde_json::to_writer(&mut *stream, &entry).map_err(io::Error::from)?;
+                writeln!(stream)?;
+            }
+            return Ok(());
+        }
+        writeln!(stream, "{: <19}\toperation\tuser\tpath", "time")?;
+        for entry in entries {
+            // Show the raw time if it can't be parsed
+            let time = chrono::DateTime::parse_from_rfc3339(&entry.time)
+                .map(|time| time.format("%Y-%m-%dT%H:%M:%S").to_string())
+                .unwrap_or(entry.time);
+            let path = match (entry.orig, entry.grave) {
+                (Some(orig), Some(grave)) => format!("{} -> {}", orig.display(), grave.display()),
+                (Some(path), None) | (None, Some(path)) => path.display().to_string(),
+                (None, None) => String::new(),
+            };
+            writeln!(
+                stream,
+                "{}\t{}\t{}@{}\t{}",
+                time, entry.operation, entry.user, entry.host, path
+            )?;
+        }
+        Ok(())
+    }
+}
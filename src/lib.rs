@@ -1,9 +1,11 @@
 use clap::CommandFactory;
 use fs_extra::dir::get_size;
-use std::fs::Metadata;
-use std::io::{BufRead, BufReader, Error, ErrorKind, Write};
+use std::io::{self, ErrorKind, IsTerminal, Read, Write};
 use std::path::{Path, PathBuf};
-use std::{env, fs};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::time::Instant;
+use std::{env, fs, thread};
 use walkdir::WalkDir;
 
 // Platform-specific imports
@@ -14,41 +16,75 @@ use std::os::unix::fs::{symlink, FileTypeExt, PermissionsExt};
 use std::os::windows::fs::symlink_file as symlink;
 
 pub mod args;
+pub mod clean;
 pub mod completions;
+pub mod compress;
+pub mod config;
+pub mod crypt;
+pub mod dedup;
+pub mod error;
+pub mod event;
+pub mod fsck;
+pub mod graveyard;
+pub mod history;
+pub mod project;
+pub mod prompt;
+pub mod protect;
 pub mod record;
+pub mod rm;
+pub mod select;
+pub mod sweep;
 pub mod util;
 
-use args::Args;
-use record::{Record, RecordItem, DEFAULT_FILE_LOCK};
+use args::{Args, BigFilePolicy, OutputFormat};
+use config::Config;
+pub use error::Error;
+use error::IoContext;
+pub use event::{Event, JsonRenderer, Observer, ProgressRenderer, Renderer, TextRenderer};
+pub use graveyard::{
+    BuryOptions, BuryOutcome, CompressPolicy, Graveyard, ListFilter, PurgePolicy, Unburied,
+};
+use project::Project;
+use prompt::Prompter;
+use record::RecordItem;
+use select::Selection;
 
-const LINES_TO_INSPECT: usize = 6;
-const FILES_TO_INSPECT: usize = 6;
 pub const BIG_FILE_THRESHOLD: u64 = 500000000; // 500 MB
 
-pub fn run(cli: Args, mode: impl util::TestingMode, stream: &mut impl Write) -> Result<(), Error> {
-    args::validate_args(&cli)?;
-    let graveyard: &PathBuf = &get_graveyard(cli.graveyard);
-
-    if !graveyard.exists() {
-        fs::create_dir_all(graveyard)?;
+/// Decides what happens to a target that is bigger than `threshold`
+/// bytes when it has to be copied into the graveyard.
+#[derive(Clone, Copy, Debug)]
+pub struct BigFileRule {
+    pub threshold: u64,
+    pub policy: BigFilePolicy,
+}
 
-        #[cfg(unix)]
-        {
-            let metadata = graveyard.metadata()?;
-            let mut permissions = metadata.permissions();
-            permissions.set_mode(0o700);
+impl Default for BigFileRule {
+    fn default() -> Self {
+        BigFileRule {
+            threshold: BIG_FILE_THRESHOLD,
+            policy: BigFilePolicy::default(),
         }
-        // TODO: Default permissions on windows should be good, but need to double-check.
     }
+}
 
-    // Stores the deleted files
-    let record = Record::<DEFAULT_FILE_LOCK>::new(graveyard);
+/// Run the command line, rendering events to `stream` in the configured format
+pub fn run(cli: Args, prompter: impl Prompter, stream: &mut impl Write) -> Result<(), Error> {
+    args::validate_args(&cli)?;
     let cwd = &env::current_dir()?;
+    let global = Config::load_global(&cli)?;
+    let config = global.clone().with_project(Project::discover(cwd)?);
+    let format = config.format.value;
+    let graveyard = Graveyard::open(&config.graveyard.value)?
+        .with_lock_timeout(config.lock_timeout.value)
+        .with_copy_jobs(config.copy_jobs.value)
+        .with_key(config.key()?);
 
     // If the user wishes to restore everything
     if cli.decompose {
-        if util::prompt_yes("Really unlink the entire graveyard?", &mode, stream)? {
-            fs::remove_dir_all(graveyard)?;
+        let renderer = &mut Renderer::new(format, stream).verbose(cli.verbose);
+        if util::prompt_yes("Really unlink the entire graveyard?", &prompter, renderer)? {
+            graveyard.purge(PurgePolicy::All, renderer)?;
         }
     } else if let Some(mut graves_to_exhume) = cli.unbury {
         // Vector to hold the grave path of items we want to unbury.
@@ -58,222 +94,265 @@ pub fn run(cli: Args, mode: impl util::TestingMode, stream: &mut impl Write) ->
 
         // If -s is also passed, push all files found by seance onto
         // the graves_to_exhume.
-        if cli.seance && record.open().is_ok() {
-            let gravepath = util::join_absolute(graveyard, dunce::canonicalize(cwd)?);
-            for grave in record.seance(&gravepath)? {
+        if cli.seance && graveyard.record().open_shared().is_ok() {
+            for grave in graveyard.list(&ListFilter::under(cwd))? {
                 graves_to_exhume.push(grave.dest);
             }
         }
 
-        // Otherwise, add the last deleted file
+        // Otherwise, add the last deleted file, along with anything
+        // buried in the same batch
         if graves_to_exhume.is_empty() {
-            if let Ok(s) = record.get_last_bury() {
-                graves_to_exhume.push(s);
+            if let Ok(batch) = graveyard.last_batch() {
+                graves_to_exhume.extend(batch);
             }
         }
 
-        let allow_rename = util::allow_rename();
-
-        // Go through the graveyard and exhume all the graves
-        for line in record.lines_of_graves(&graves_to_exhume) {
-            let entry = RecordItem::new(&line);
-            let orig: PathBuf = match util::symlink_exists(&entry.orig) {
-                true => util::rename_grave(&entry.orig),
-                false => PathBuf::from(&entry.orig),
-            };
-            move_target(&entry.dest, &orig, allow_rename, &mode, stream).map_err(|e| {
-                Error::new(
-                    e.kind(),
-                    format!(
-                        "Unbury failed: couldn't copy files from {} to {}",
-                        entry.dest.display(),
-                        orig.display()
-                    ),
-                )
-            })?;
-            writeln!(
-                stream,
-                "Returned {} to {}",
-                entry.dest.display(),
-                orig.display()
-            )?;
-        }
-        record.log_exhumed_graves(&graves_to_exhume)?;
+        graveyard.unbury(
+            &graves_to_exhume,
+            &prompter,
+            &mut ProgressRenderer::new(Renderer::new(format, stream), progress_stream(cli.quiet)),
+        )?;
     } else if cli.seance {
-        let gravepath = util::join_absolute(graveyard, dunce::canonicalize(cwd)?);
-        writeln!(stream, "{: <19}\tpath", "deletion_time")?;
-        for grave in record.seance(&gravepath)? {
+        let graves = graveyard.list(&ListFilter::under(cwd))?;
+        if format == OutputFormat::Json {
+            for grave in &graves {
+                let listed = Listed {
+                    grave,
+                    size: graveyard.size(&grave.dest)?,
+                };
+                serde_json::to_writer(&mut *stream, &listed).map_err(io::Error::from)?;
+                writeln!(stream)?;
+            }
+            return Ok(());
+        }
+        writeln!(stream, "{: <19}\t{: >10}\tpath", "deletion_time", "size")?;
+        for grave in graves {
+            let size = util::humanize_bytes(graveyard.size(&grave.dest)?);
+            // Show the raw time if it can't be parsed
             let parsed_time = chrono::DateTime::parse_from_rfc3339(&grave.time)
-                .expect("Failed to parse time from RFC3339 format")
-                .format("%Y-%m-%dT%H:%M:%S")
-                .to_string();
+                .map(|time| time.format("%Y-%m-%dT%H:%M:%S").to_string())
+                .unwrap_or(grave.time);
             // Get the path separator:
-            writeln!(stream, "{}\t{}", parsed_time, grave.dest.display())?;
+            writeln!(
+                stream,
+                "{}\t{: >10}\t{}",
+                parsed_time,
+                size,
+                grave.dest.display()
+            )?;
         }
-    } else if cli.targets.is_empty() {
+    } else if cli.targets.is_empty() && !cli.stdin && cli.files_from.is_none() {
         Args::command().print_help()?;
     } else {
-        let allow_rename = util::allow_rename();
-        for target in cli.targets {
-            bury_target(
-                &target,
-                graveyard,
-                &record,
-                cwd,
-                cli.inspect,
-                allow_rename,
-                &mode,
-                stream,
-            )?;
+        let mut listed = select::expand_globs(&cli.targets);
+        if cli.stdin || cli.files_from.as_deref() == Some(Path::new("-")) {
+            listed.extend(select::read_targets(io::stdin().lock(), cli.null)?);
         }
+        if let Some(list) = cli
+            .files_from
+            .as_ref()
+            .filter(|list| *list != Path::new("-"))
+        {
+            let file =
+                fs::File::open(list).context(|| format!("Failed to open {}", list.display()))?;
+            listed.extend(select::read_targets(file, cli.null)?);
+        }
+
+        let selection = Selection::new(&cli.include, &cli.exclude, cwd)?;
+        let mut targets = Vec::new();
+        for target in listed {
+            targets.extend(selection.select(&target)?);
+        }
+
+        let batch = cli.batch.then(|| chrono::Local::now().to_rfc3339());
+        let renderer = &mut ProgressRenderer::new(
+            Renderer::new(format, stream).verbose(cli.verbose),
+            progress_stream(cli.quiet),
+        );
+        let mut buried: Vec<(Graveyard, Vec<BuryOutcome>, bool)> = Vec::new();
+        let mut failures = Vec::new();
+        let result = (|| {
+            for (config, targets) in group_by_project(&global, &targets, cwd)? {
+                let graveyard = Graveyard::open(&config.graveyard.value)?
+                    .with_lock_timeout(config.lock_timeout.value)
+                    .with_copy_jobs(config.copy_jobs.value)
+                    .with_key(config.key()?);
+                // Make room before burying, so that the new graves are never purged
+                if let Some(retention) = config.retention.value {
+                    graveyard.purge(PurgePolicy::OlderThan(retention), renderer)?;
+                }
+                if let Some(quota) = config.quota.value {
+                    graveyard.purge(PurgePolicy::OverQuota(quota), renderer)?;
+                }
+                // Encrypted graves are never deduplicated or compressed. A
+                // missing key is caught before anything is buried, but since a
+                // failed pass leaves the graves it didn't get to as they were,
+                // it doesn't stop the targets from being buried.
+                let encrypt = config.encrypt.value;
+                if encrypt {
+                    graveyard.encryption_key()?;
+                    warn_on_error(graveyard.encrypt(renderer), renderer)?;
+                }
+                if config.dedup.value {
+                    warn_on_error(graveyard.dedup(renderer), renderer)?;
+                }
+                if let Some(age) = config.compress_after.value {
+                    let compressed = graveyard.compress(CompressPolicy::OlderThan(age), renderer);
+                    warn_on_error(compressed, renderer)?;
+                }
+                if let Some(size) = config.compress_above.value {
+                    let compressed = graveyard.compress(CompressPolicy::LargerThan(size), renderer);
+                    warn_on_error(compressed, renderer)?;
+                }
+
+                let opts = BuryOptions::default()
+                    .inspect(config.inspect.value)
+                    .big_file(BigFileRule {
+                        threshold: config.big_file_threshold.value,
+                        policy: config.big_file_policy.value,
+                    })
+                    .force_protected(cli.force_protected)
+                    .protected(config.protected.value)
+                    .project(config.project)
+                    .atomic(cli.atomic)
+                    .one_file_system(cli.one_file_system)
+                    .batch(batch.clone());
+                let result = graveyard.bury(&targets, &opts, &prompter, renderer);
+                // Even if it failed, it may have buried some targets first
+                let outcomes = result.as_ref().map_or(Vec::new(), Vec::clone);
+                buried.push((graveyard, outcomes, encrypt));
+                match result {
+                    Ok(_) => {}
+                    Err(e) if cli.atomic => {
+                        // Put back what other graveyards took, too
+                        for (graveyard, outcomes, _) in buried.iter().rev() {
+                            graveyard.rollback(outcomes, &prompter, renderer)?;
+                        }
+                        return Err(e);
+                    }
+                    Err(Error::TargetsFailed {
+                        failures: failed, ..
+                    }) => failures.extend(failed),
+                    Err(e) if targets.len() == 1 && !matches!(e, Error::UserAborted) => {
+                        failures.push((targets[0].clone(), e))
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(())
+        })();
+        // Right away, rather than leave the new graves readable until the
+        // next deletion, but only once nothing has to be rolled back. This
+        // includes what was buried before a target failed or the user quit.
+        let encrypted = buried
+            .iter()
+            .filter(|(_, _, encrypt)| *encrypt)
+            .try_for_each(|(graveyard, _, _)| graveyard.encrypt(renderer).map(drop));
+        result?;
+        encrypted?;
+        Error::from_failures(targets.len(), failures)?;
     }
 
     Ok(())
 }
 
-#[allow(clippy::too_many_arguments)]
-fn bury_target<const FILE_LOCK: bool>(
-    target: &PathBuf,
-    graveyard: &PathBuf,
-    record: &Record<FILE_LOCK>,
-    cwd: &Path,
-    inspect: bool,
-    allow_rename: bool,
-    mode: &impl util::TestingMode,
-    stream: &mut impl Write,
-) -> Result<(), Error> {
-    // Check if source exists
-    let metadata = &fs::symlink_metadata(target).map_err(|_| {
-        Error::new(
-            ErrorKind::NotFound,
-            format!(
-                "Cannot remove {}: no such file or directory",
-                target.to_str().unwrap()
-            ),
-        )
-    })?;
-    // Canonicalize the path unless it's a symlink
-    let source = &if !metadata.file_type().is_symlink() {
-        dunce::canonicalize(cwd.join(target))
-            .map_err(|e| Error::new(e.kind(), "Failed to canonicalize path"))?
-    } else {
-        cwd.join(target)
-    };
-
-    if inspect && !should_we_bury_this(target, source, metadata, mode, stream)? {
-        // User chose to not bury the file
-    } else if source.starts_with(graveyard) {
-        // If rip is called on a file already in the graveyard, prompt
-        // to permanently delete it instead.
-        writeln!(stream, "{} is already in the graveyard.", source.display())?;
-        if util::prompt_yes("Permanently unlink it?", mode, stream)? {
-            if fs::remove_dir_all(source).is_err() {
-                fs::remove_file(source).map_err(|e| {
-                    Error::new(e.kind(), format!("Couldn't unlink {}", source.display()))
-                })?;
-            }
-        } else {
-            writeln!(stream, "Skipping {}", source.display())?;
-            // TODO: In the original code, this was a hard return from the entire
-            // method (i.e., `run`). I think it should just be a return from the bury
-            // (meaning a `continue` in the original code's loop). But I'm not sure.
-        }
-    } else {
-        let dest: &Path = &{
-            let dest = util::join_absolute(graveyard, source);
-            // Resolve a name conflict if necessary
-            if util::symlink_exists(&dest) {
-                util::rename_grave(dest)
-            } else {
-                dest
-            }
-        };
+/// A grave listed by `--seance --format json`, with the size of what it
+/// holds
+#[derive(serde::Serialize)]
+struct Listed<'a> {
+    #[serde(flatten)]
+    grave: &'a RecordItem,
+    size: u64,
+}
 
-        let moved = move_target(source, dest, allow_rename, mode, stream).map_err(|e| {
-            fs::remove_dir_all(dest).ok();
-            Error::new(e.kind(), "Failed to bury file")
+/// Report a failed pass over the graveyard, rather than fail what it was
+/// run before
+fn warn_on_error<T>(result: Result<T, Error>, observer: &mut impl Observer) -> Result<(), Error> {
+    if let Err(e) = result {
+        observer.notify(&Event::Warning {
+            message: e.to_string(),
         })?;
-
-        if moved {
-            // Clean up any partial buries due to permission error
-            record.write_log(source, dest)?;
-        }
     }
-
     Ok(())
 }
 
-fn should_we_bury_this(
-    target: &Path,
-    source: &PathBuf,
-    metadata: &Metadata,
-    mode: &impl util::TestingMode,
+/// Where to draw progress while copying: stderr, unless it isn't a
+/// terminal or `quiet` is set
+fn progress_stream(quiet: bool) -> Option<io::Stderr> {
+    (!quiet && io::stderr().is_terminal()).then(io::stderr)
+}
+
+/// Bury `targets` as one batch, which `rip -u` restores together, with the
+/// global options from `cli`, and print each target as it is buried
+pub(crate) fn bury_batch(
+    cli: &Args,
+    targets: &[PathBuf],
+    prompter: impl Prompter,
     stream: &mut impl Write,
-) -> Result<bool, Error> {
-    if metadata.is_dir() {
-        // Get the size of the directory and all its contents
-        {
-            let num_bytes = get_size(source).map_err(|_| {
-                Error::new(
-                    ErrorKind::Other,
-                    format!("Failed to get size of directory: {}", source.display()),
-                )
-            })?;
-            writeln!(
-                stream,
-                "{}: directory, {} including:",
-                target.to_str().unwrap(),
-                util::humanize_bytes(num_bytes)
-            )?;
-        }
+) -> Result<(), Error> {
+    // Everything but the targets and where they are read from
+    let cli = Args {
+        targets: targets.to_vec(),
+        graveyard: cli.graveyard.clone(),
+        inspect: cli.inspect,
+        big_file_threshold: cli.big_file_threshold,
+        big_file_policy: cli.big_file_policy,
+        force_protected: cli.force_protected,
+        atomic: cli.atomic,
+        one_file_system: cli.one_file_system,
+        verbose: true,
+        jobs: cli.jobs,
+        quiet: cli.quiet,
+        exclude: cli.exclude.clone(),
+        include: cli.include.clone(),
+        format: cli.format,
+        batch: true,
+        ..Args::default()
+    };
+    run(cli, prompter, stream)
+}
 
-        // Print the first few top-level files in the directory
-        for entry in WalkDir::new(source)
-            .sort_by(|a, b| a.cmp(b))
-            .min_depth(1)
-            .max_depth(1)
-            .into_iter()
-            .filter_map(|entry| entry.ok())
-            .take(FILES_TO_INSPECT)
-        {
-            writeln!(stream, "{}", entry.path().display())?;
-        }
-    } else {
-        writeln!(
-            stream,
-            "{}: file, {}",
-            &target.to_str().unwrap(),
-            util::humanize_bytes(metadata.len())
-        )?;
-        // Read the file and print the first few lines
-        if let Ok(source_file) = fs::File::open(source) {
-            for line in BufReader::new(source_file)
-                .lines()
-                .take(LINES_TO_INSPECT)
-                .filter_map(|line| line.ok())
-            {
-                writeln!(stream, "> {}", line)?;
-            }
-        } else {
-            writeln!(stream, "Error reading {}", source.display())?;
+/// Group targets by the `.riprc` that applies to them, keeping their order
+fn group_by_project(
+    global: &Config,
+    targets: &[PathBuf],
+    cwd: &Path,
+) -> Result<Vec<(Config, Vec<PathBuf>)>, Error> {
+    let mut groups: Vec<(Config, Vec<PathBuf>)> = Vec::new();
+    for target in targets {
+        let path = cwd.join(target);
+        // `..` has no file name, so its parent isn't the directory it lives in
+        let path = match path.file_name() {
+            Some(_) => path,
+            None => dunce::canonicalize(&path).unwrap_or(path),
+        };
+        let project = Project::discover(path.parent().unwrap_or(&path))?;
+        let key = project.as_ref().map(|project| project.path.clone());
+        match groups.iter_mut().find(|(config, _)| {
+            config.project.as_ref().map(|project| &project.path) == key.as_ref()
+        }) {
+            Some((_, group)) => group.push(target.clone()),
+            None => groups.push((global.clone().with_project(project), vec![target.clone()])),
         }
     }
-    util::prompt_yes(
-        format!("Send {} to the graveyard?", target.to_str().unwrap()),
-        mode,
-        stream,
-    )
+    Ok(groups)
 }
 
 /// Move a target to a given destination, copying if necessary.
 /// Returns true if the target was moved, false if it was not (due to
-/// user input)
+/// user input or the big file policy)
+///
+/// If `big_file` is given, it is applied once to the whole target
+/// before anything is copied. Directories are copied with `jobs` threads.
 pub fn move_target(
     target: &Path,
     dest: &Path,
     allow_rename: bool,
-    mode: &impl util::TestingMode,
-    stream: &mut impl Write,
+    big_file: Option<BigFileRule>,
+    jobs: usize,
+    prompter: &impl Prompter,
+    observer: &mut impl Observer,
 ) -> Result<bool, Error> {
     // Try a simple rename, which will only work within the same mount point.
     // Trying to rename across filesystems will throw errno 18.
@@ -281,110 +360,223 @@ pub fn move_target(
         return Ok(true);
     }
 
+    if let Some(big_file) = big_file {
+        if !should_copy_big_target(target, big_file, prompter, observer)? {
+            if fs::symlink_metadata(target)?.is_dir() {
+                fs::remove_dir_all(target)?;
+            } else {
+                fs::remove_file(target)?;
+            }
+            return Ok(false);
+        }
+    }
+
     // If that didn't work, then we need to copy and rm.
-    fs::create_dir_all(
-        dest.parent()
-            .ok_or_else(|| Error::new(ErrorKind::NotFound, "Could not get parent of dest!"))?,
-    )?;
+    let size = observer.shows_progress().then(|| copy_size(target));
+    observer.notify(&Event::CopyStarted {
+        from: target.to_path_buf(),
+        to: dest.to_path_buf(),
+        files: size.map(|(files, _)| files),
+        bytes: size.map(|(_, bytes)| bytes),
+    })?;
+    let parent = dest.parent().ok_or_else(|| Error::Io {
+        context: format!("Could not get parent of {}", dest.display()),
+        source: io::Error::from(ErrorKind::NotFound),
+    })?;
+    fs::create_dir_all(parent).context(|| format!("Failed to create dir: {}", parent.display()))?;
 
     if fs::symlink_metadata(target)?.is_dir() {
-        move_dir(target, dest, mode, stream)
+        move_dir(target, dest, jobs, prompter, observer)
     } else {
-        let moved = copy_file(target, dest, mode, stream).map_err(|e| {
-            Error::new(
-                e.kind(),
-                format!(
-                    "Failed to copy file from {} to {}",
-                    target.display(),
-                    dest.display()
-                ),
-            )
-        })?;
-        fs::remove_file(target).map_err(|e| {
-            Error::new(
-                e.kind(),
-                format!("Failed to remove file: {}", target.display()),
-            )
-        })?;
+        let moved = copy_file(target, dest, prompter, observer)?;
+        fs::remove_file(target)
+            .context(|| format!("Failed to remove file: {}", target.display()))?;
         Ok(moved)
     }
 }
 
+/// Check a target against the big file rule before copying it.
+/// Returns false if the target should be permanently deleted instead.
+fn should_copy_big_target(
+    target: &Path,
+    big_file: BigFileRule,
+    prompter: &impl Prompter,
+    observer: &mut impl Observer,
+) -> Result<bool, Error> {
+    let metadata = fs::symlink_metadata(target)?;
+    let (kind, size) = if metadata.is_dir() {
+        ("directory", dir_size(target)?)
+    } else {
+        ("file", metadata.len())
+    };
+
+    if size <= big_file.threshold {
+        return Ok(true);
+    }
+
+    match big_file.policy {
+        BigFilePolicy::AlwaysCopy => Ok(true),
+        BigFilePolicy::AlwaysDelete => Ok(false),
+        BigFilePolicy::Refuse => Err(Error::TooBig {
+            path: target.to_path_buf(),
+            size,
+            is_dir: metadata.is_dir(),
+        }),
+        BigFilePolicy::Prompt => {
+            observer.notify(&Event::BigTarget {
+                path: target.to_path_buf(),
+                size,
+                is_dir: metadata.is_dir(),
+            })?;
+            let delete = util::prompt_yes(
+                format!("Permanently delete this {} instead?", kind),
+                prompter,
+                observer,
+            )?;
+            Ok(!delete)
+        }
+    }
+}
+
 /// Move a target which is a directory to a given destination, copying if necessary.
 /// Returns true *always*, as the creation of the directory is enough to mark it as successful.
+///
+/// With more than one job, regular files are copied by that many threads,
+/// with the same result.
 pub fn move_dir(
     target: &Path,
     dest: &Path,
-    mode: &impl util::TestingMode,
-    stream: &mut impl Write,
+    jobs: usize,
+    prompter: &impl Prompter,
+    observer: &mut impl Observer,
 ) -> Result<bool, Error> {
-    // Walk the source, creating directories and copying files as needed
+    // Walk the source, creating directories and copying files as needed.
+    // Walking visits every directory before its contents, so each one
+    // exists before anything is copied into it.
+    let mut files = Vec::new();
     for entry in WalkDir::new(target).into_iter().filter_map(|e| e.ok()) {
         // Path without the top-level directory
-        let orphan = entry.path().strip_prefix(target).map_err(|_| {
-            Error::new(
-                ErrorKind::Other,
-                "Parent directory isn't a prefix of child directories?",
-            )
+        let orphan = entry.path().strip_prefix(target).map_err(|e| Error::Io {
+            context: "Parent directory isn't a prefix of child directories?".to_string(),
+            source: io::Error::other(e),
         })?;
 
         if entry.file_type().is_dir() {
-            fs::create_dir_all(dest.join(orphan)).map_err(|e| {
-                Error::new(
-                    e.kind(),
-                    format!(
-                        "Failed to create dir: {} in {}",
-                        entry.path().display(),
-                        dest.join(orphan).display()
-                    ),
+            fs::create_dir_all(dest.join(orphan)).context(|| {
+                format!(
+                    "Failed to create dir: {} in {}",
+                    entry.path().display(),
+                    dest.join(orphan).display()
                 )
             })?;
+        } else if jobs > 1 && entry.file_type().is_file() {
+            files.push((entry.path().to_path_buf(), dest.join(orphan)));
         } else {
-            copy_file(entry.path(), &dest.join(orphan), mode, stream).map_err(|e| {
-                Error::new(
-                    e.kind(),
-                    format!(
-                        "Failed to copy file from {} to {}",
-                        entry.path().display(),
-                        dest.join(orphan).display()
-                    ),
-                )
-            })?;
+            copy_file(entry.path(), &dest.join(orphan), prompter, observer)?;
         }
     }
-    fs::remove_dir_all(target).map_err(|e| {
-        Error::new(
-            e.kind(),
-            format!("Failed to remove dir: {}", target.display()),
-        )
-    })?;
+    copy_files_parallel(&files, jobs, observer)?;
+    fs::remove_dir_all(target).context(|| format!("Failed to remove dir: {}", target.display()))?;
 
     Ok(true)
 }
 
+/// What a copying thread has to say about a file
+enum CopyReport {
+    Progress(u64),
+    Done(Result<u64, Error>),
+}
+
+/// Copy regular files from the first path of each pair to the second with
+/// `jobs` threads, reporting the progress of each copy and when it
+/// completes. The first failure stops the others.
+fn copy_files_parallel(
+    files: &[(PathBuf, PathBuf)],
+    jobs: usize,
+    observer: &mut impl Observer,
+) -> Result<(), Error> {
+    let next = &AtomicUsize::new(0);
+    let failed = &AtomicBool::new(false);
+    thread::scope(|scope| {
+        let (sender, receiver) = mpsc::channel();
+        for _ in 0..jobs.min(files.len()) {
+            let sender = sender.clone();
+            scope.spawn(move || {
+                while !failed.load(Ordering::Relaxed) {
+                    let Some((from, to)) = files.get(next.fetch_add(1, Ordering::Relaxed)) else {
+                        break;
+                    };
+                    let copied = copy_contents(from, to, |bytes| {
+                        let _ = sender.send((from, to, CopyReport::Progress(bytes)));
+                        Ok(())
+                    });
+                    if copied.is_err() {
+                        failed.store(true, Ordering::Relaxed);
+                    }
+                    if sender.send((from, to, CopyReport::Done(copied))).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(sender);
+
+        for (from, to, report) in receiver {
+            let result = match report {
+                CopyReport::Progress(bytes) => observer
+                    .notify(&Event::Copying {
+                        from: from.clone(),
+                        to: to.clone(),
+                        bytes,
+                    })
+                    .map_err(Error::from),
+                CopyReport::Done(Ok(size)) => observer
+                    .notify(&Event::Copied {
+                        from: from.clone(),
+                        to: to.clone(),
+                        size,
+                    })
+                    .map_err(Error::from),
+                CopyReport::Done(Err(e)) => Err(e),
+            };
+            if result.is_err() {
+                // Let the other threads finish what they're copying, and stop
+                failed.store(true, Ordering::Relaxed);
+                return result;
+            }
+        }
+        Ok(())
+    })
+}
+
 pub fn copy_file(
     source: &Path,
     dest: &Path,
-    mode: &impl util::TestingMode,
-    stream: &mut impl Write,
+    prompter: &impl Prompter,
+    observer: &mut impl Observer,
 ) -> Result<bool, Error> {
-    let metadata = fs::symlink_metadata(source)?;
+    let copy_failed = |e: io::Error| Error::CrossDeviceCopyFailed {
+        from: source.to_path_buf(),
+        to: dest.to_path_buf(),
+        source: e,
+    };
+    let metadata = fs::symlink_metadata(source).map_err(copy_failed)?;
     let filetype = metadata.file_type();
 
-    if metadata.len() > BIG_FILE_THRESHOLD {
-        writeln!(
-            stream,
-            "About to copy a big file ({} is {})",
-            source.display(),
-            util::humanize_bytes(metadata.len())
-        )?;
-        if util::prompt_yes("Permanently delete this file instead?", mode, stream)? {
-            return Ok(false);
-        }
-    }
-
     if filetype.is_file() {
-        fs::copy(source, dest)?;
+        let size = copy_contents(source, dest, |bytes| {
+            observer.notify(&Event::Copying {
+                from: source.to_path_buf(),
+                to: dest.to_path_buf(),
+                bytes,
+            })?;
+            Ok(())
+        })?;
+        observer.notify(&Event::Copied {
+            from: source.to_path_buf(),
+            to: dest.to_path_buf(),
+            size,
+        })?;
         return Ok(true);
     }
 
@@ -395,35 +587,101 @@ pub fn copy_file(
             .arg(dest)
             .arg("-m")
             .arg(metadata_mode.to_string())
-            .output()?;
+            .output()
+            .map_err(copy_failed)?;
         return Ok(true);
     }
 
     if filetype.is_symlink() {
-        let target = fs::read_link(source)?;
-        symlink(target, dest)?;
+        let target = fs::read_link(source).map_err(copy_failed)?;
+        symlink(target, dest).map_err(copy_failed)?;
         return Ok(true);
     }
 
     match fs::copy(source, dest) {
         Err(e) => {
             // Special file: Try copying it as normal, but this probably won't work
-            writeln!(
-                stream,
-                "Non-regular file or directory: {}",
-                source.display()
-            )?;
+            observer.notify(&Event::NonRegularFile {
+                path: source.to_path_buf(),
+            })?;
 
-            if util::prompt_yes("Permanently delete the file?", mode, stream)? {
+            if util::prompt_yes("Permanently delete the file?", prompter, observer)? {
                 Ok(false)
             } else {
-                Err(e)
+                Err(copy_failed(e))
             }
         }
-        Ok(_) => Ok(true),
+        Ok(size) => {
+            observer.notify(&Event::Copied {
+                from: source.to_path_buf(),
+                to: dest.to_path_buf(),
+                size,
+            })?;
+            Ok(true)
+        }
     }
 }
 
+/// How much of a file is read and written at a time
+const COPY_CHUNK: usize = 1 << 20;
+
+/// Copy the regular file `source` to `dest` like `fs::copy`, a chunk at a
+/// time, and pass the number of bytes done to `progress` every so often
+fn copy_contents(
+    source: &Path,
+    dest: &Path,
+    mut progress: impl FnMut(u64) -> Result<(), Error>,
+) -> Result<u64, Error> {
+    let copy_failed = |e: io::Error| Error::CrossDeviceCopyFailed {
+        from: source.to_path_buf(),
+        to: dest.to_path_buf(),
+        source: e,
+    };
+    let mut reader = fs::File::open(source).map_err(copy_failed)?;
+    let permissions = reader.metadata().map_err(copy_failed)?.permissions();
+    let mut writer = fs::File::create(dest).map_err(copy_failed)?;
+    let mut buffer = vec![0; COPY_CHUNK];
+    let mut copied = 0;
+    let mut reported = Instant::now();
+    loop {
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(copy_failed(e)),
+        };
+        writer.write_all(&buffer[..read]).map_err(copy_failed)?;
+        copied += read as u64;
+        if reported.elapsed() >= event::PROGRESS_INTERVAL {
+            progress(copied)?;
+            reported = Instant::now();
+        }
+    }
+    fs::set_permissions(dest, permissions).map_err(copy_failed)?;
+    Ok(copied)
+}
+
+/// The number of regular files in `target` and their total size. Entries
+/// that can't be read are left out, as the copy skips them too.
+fn copy_size(target: &Path) -> (u64, u64) {
+    let (mut files, mut bytes) = (0, 0);
+    let entries = WalkDir::new(target).into_iter().filter_map(|e| e.ok());
+    for metadata in entries.filter_map(|entry| entry.metadata().ok()) {
+        if metadata.is_file() {
+            files += 1;
+            bytes += metadata.len();
+        }
+    }
+    (files, bytes)
+}
+
+pub(crate) fn dir_size(path: &Path) -> Result<u64, Error> {
+    get_size(path).map_err(|e| Error::Io {
+        context: format!("Failed to get size of directory: {}", path.display()),
+        source: io::Error::other(e),
+    })
+}
+
 pub fn get_graveyard(graveyard: Option<PathBuf>) -> PathBuf {
     if let Some(flag) = graveyard {
         flag
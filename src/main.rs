@@ -1,12 +1,30 @@
 use clap::{Args as _, Command, FromArgMatches as _};
 use std::env;
+use std::ffi::OsString;
 use std::io;
+use std::path::Path;
 use std::process::ExitCode;
 
-use rip2::args::Commands;
-use rip2::{args, completions, util};
+use rip2::args::{Commands, ConfigCommand, RecordCommand};
+use rip2::clean::{self, CleanOptions};
+use rip2::config::Config;
+use rip2::history::History;
+use rip2::record::DEFAULT_FILE_LOCK;
+use rip2::sweep::{self, SweepOptions};
+use rip2::{args, completions, prompt, rm, util, Graveyard};
 
 fn main() -> ExitCode {
+    let argv: Vec<OsString> = env::args_os().collect();
+    if rm::requested(&argv) {
+        let result = rm::parse(&argv[1..])
+            .and_then(|rm_args| rm::run(rm_args, prompt::StdinPrompter, &mut io::stdout()));
+        if let Err(e) = result {
+            eprintln!("rip: {}", e);
+            return ExitCode::from(e.exit_code());
+        }
+        return ExitCode::SUCCESS;
+    }
+
     let base_cmd = Command::new("rip");
     let cmd = args::Args::augment_args(base_cmd);
     let cli = args::Args::from_arg_matches(&cmd.get_matches()).unwrap();
@@ -20,7 +38,13 @@ fn main() -> ExitCode {
             }
         }
         Some(Commands::Graveyard { seance }) => {
-            let graveyard = rip2::get_graveyard(None);
+            let graveyard = match Config::load(&cli) {
+                Ok(config) => config.graveyard.value,
+                Err(e) => {
+                    eprintln!("rip: {}", e);
+                    return ExitCode::from(e.exit_code());
+                }
+            };
             if *seance {
                 let cwd = env::current_dir().expect("Failed to get current directory");
                 let gravepath = util::join_absolute(
@@ -32,18 +56,139 @@ fn main() -> ExitCode {
                 print!("{}", graveyard.display());
             }
         }
+        Some(Commands::Config {
+            command: ConfigCommand::Show,
+        }) => {
+            let result = Config::load(&cli).and_then(|config| Ok(config.show(&mut io::stdout())?));
+            if let Err(e) = result {
+                eprintln!("rip: {}", e);
+                return ExitCode::from(e.exit_code());
+            }
+        }
+        Some(Commands::Clean {
+            dir,
+            ignored,
+            untracked,
+            dry_run,
+        }) => {
+            let opts = CleanOptions {
+                untracked: !ignored,
+                ignored: !untracked,
+            };
+            let dir = dir.as_deref().unwrap_or(Path::new("."));
+            let result = clean::run(
+                &cli,
+                dir,
+                opts,
+                *dry_run,
+                prompt::StdinPrompter,
+                &mut io::stdout(),
+            );
+            if let Err(e) = result {
+                eprintln!("rip: {}", e);
+                return ExitCode::from(e.exit_code());
+            }
+        }
+        Some(Commands::Sweep {
+            dir,
+            empty_dirs,
+            older_than,
+            larger_than,
+            pattern,
+            dry_run,
+        }) => {
+            let opts = SweepOptions {
+                empty_dirs: *empty_dirs,
+                older_than: *older_than,
+                larger_than: *larger_than,
+                patterns: pattern.clone(),
+            };
+            let dir = dir.as_deref().unwrap_or(Path::new("."));
+            let result = sweep::run(
+                &cli,
+                dir,
+                &opts,
+                *dry_run,
+                prompt::StdinPrompter,
+                &mut io::stdout(),
+            );
+            if let Err(e) = result {
+                eprintln!("rip: {}", e);
+                return ExitCode::from(e.exit_code());
+            }
+        }
+        Some(Commands::Cat { path }) => {
+            let result = Config::load(&cli).and_then(|config| {
+                Graveyard::open(&config.graveyard.value)?
+                    .with_lock_timeout(config.lock_timeout.value)
+                    .with_key(config.key()?)
+                    .cat(path, &mut io::stdout().lock())
+            });
+            if let Err(e) = result {
+                eprintln!("rip: {}", e);
+                return ExitCode::from(e.exit_code());
+            }
+        }
+        Some(Commands::Log) => {
+            let result = Config::load(&cli).and_then(|config| {
+                History::<DEFAULT_FILE_LOCK>::new(&config.graveyard.value)
+                    .show(config.format.value, &mut io::stdout())
+            });
+            if let Err(e) = result {
+                eprintln!("rip: {}", e);
+                return ExitCode::from(e.exit_code());
+            }
+        }
+        Some(Commands::Fsck { repair }) => {
+            let result = Config::load(&cli).and_then(|config| {
+                let report = Graveyard::open(&config.graveyard.value)?
+                    .with_lock_timeout(config.lock_timeout.value)
+                    .fsck(*repair)?;
+                report.show(config.format.value, &mut io::stdout())?;
+                Ok(report)
+            });
+            match result {
+                // Problems that were left alone are a failure
+                Ok(report) if report.problems() > 0 && !repair => return ExitCode::FAILURE,
+                Ok(_) => {}
+                Err(e) => {
+                    eprintln!("rip: {}", e);
+                    return ExitCode::from(e.exit_code());
+                }
+            }
+        }
+        Some(Commands::Record {
+            command: RecordCommand::Compact,
+        }) => {
+            let result = Config::load(&cli)
+                .and_then(|config| {
+                    Ok(Graveyard::open(config.graveyard.value)?
+                        .with_lock_timeout(config.lock_timeout.value))
+                })
+                .and_then(|graveyard| graveyard.compact());
+            match result {
+                Ok(compaction) => println!(
+                    "Compacted the record from {} to {} lines",
+                    compaction.lines_before, compaction.lines_after
+                ),
+                Err(e) => {
+                    eprintln!("rip: {}", e);
+                    return ExitCode::from(e.exit_code());
+                }
+            }
+        }
         None => {
             let mut stream = io::stdout();
-            let mode = util::ProductionMode;
+            let prompter = prompt::StdinPrompter;
 
             ////////////////////////////////////////////////////////////
             // Main code ///////////////////////////////////////////////
-            let result = rip2::run(cli, mode, &mut stream);
+            let result = rip2::run(cli, prompter, &mut stream);
             ////////////////////////////////////////////////////////////
 
             if let Err(ref e) = result {
-                println!("Exception: {}", e);
-                return ExitCode::FAILURE;
+                eprintln!("rip: {}", e);
+                return ExitCode::from(e.exit_code());
             }
         }
     }
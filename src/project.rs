@@ -0,0 +1,178 @@
+use glob::Pattern;
+use serde::Deserialize;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::util;
+use crate::Error;
+
+/// The name of the per-project rules file
+pub const PROJECT_FILE: &str = ".riprc";
+
+/// The contents of a `.riprc`. Every key is optional.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ProjectFile {
+    /// A graveyard for the project, relative to the `.riprc`
+    pub graveyard: Option<PathBuf>,
+    /// Paths that are permanently deleted instead of being buried
+    #[serde(default)]
+    pub hard_delete: Vec<String>,
+    /// Paths that rip refuses to remove
+    #[serde(default)]
+    pub refuse: Vec<String>,
+}
+
+/// The rules from a `.riprc`, which apply to everything under its directory
+///
+/// Paths are matched like in `.gitignore`: a pattern without a slash
+/// matches a file name at any depth, other patterns are matched against
+/// the path relative to the `.riprc`. Anything inside a matching
+/// directory matches too.
+///
+/// Any checkout can ship a `.riprc`, so only the refused paths apply
+/// unless the project is trusted in the global config.
+#[derive(Clone, Debug)]
+pub struct Project {
+    /// The directory holding the `.riprc`
+    pub root: PathBuf,
+    /// The `.riprc` itself
+    pub path: PathBuf,
+    pub graveyard: Option<PathBuf>,
+    /// Whether the graveyard and the hard-deleted paths apply
+    pub trusted: bool,
+    hard_delete: Vec<Rule>,
+    refuse: Vec<Rule>,
+}
+
+#[derive(Clone, Debug)]
+struct Rule {
+    glob: String,
+    pattern: Pattern,
+    anchored: bool,
+}
+
+impl Project {
+    /// Find the nearest `.riprc` in `dir` or one of its ancestors
+    pub fn discover(dir: &Path) -> Result<Option<Project>, Error> {
+        let dir = dunce::canonicalize(dir).unwrap_or_else(|_| dir.to_path_buf());
+        for ancestor in dir.ancestors() {
+            let path = ancestor.join(PROJECT_FILE);
+            if path.is_file() {
+                return Project::read(&path).map(Some);
+            }
+        }
+        Ok(None)
+    }
+
+    /// Read the rules from a `.riprc`
+    pub fn read(path: &Path) -> Result<Project, Error> {
+        let contents = fs::read_to_string(path).map_err(|e| Error::Io {
+            context: format!("Failed to read project file {}", path.display()),
+            source: e,
+        })?;
+        let file: ProjectFile = toml::from_str(&contents).map_err(|e| {
+            Error::InvalidInput(format!("Invalid project file {}: {}", path.display(), e))
+        })?;
+        let root = path
+            .parent()
+            .ok_or_else(|| Error::Io {
+                context: format!("Could not get parent of {}", path.display()),
+                source: io::Error::from(io::ErrorKind::NotFound),
+            })?
+            .to_path_buf();
+        Project::new(root, path.to_path_buf(), file)
+    }
+
+    fn new(root: PathBuf, path: PathBuf, file: ProjectFile) -> Result<Project, Error> {
+        let parse = |globs: Vec<String>| {
+            globs
+                .into_iter()
+                .map(|glob| Rule::parse(glob, &path))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let graveyard =
+            file.graveyard.map(
+                |graveyard| match (graveyard.strip_prefix("~"), util::home_dir()) {
+                    (Ok(rest), Some(home)) => home.join(rest),
+                    _ => root.join(graveyard),
+                },
+            );
+        Ok(Project {
+            graveyard,
+            trusted: false,
+            hard_delete: parse(file.hard_delete)?,
+            refuse: parse(file.refuse)?,
+            root,
+            path,
+        })
+    }
+
+    /// The globs of paths that are permanently deleted instead of being buried
+    pub fn hard_delete(&self) -> impl Iterator<Item = &str> {
+        self.hard_delete.iter().map(|rule| rule.glob.as_str())
+    }
+
+    /// The globs of paths that rip refuses to remove
+    pub fn refuse(&self) -> impl Iterator<Item = &str> {
+        self.refuse.iter().map(|rule| rule.glob.as_str())
+    }
+
+    /// Return an error if `source` (an absolute path) must not be removed
+    pub fn check(&self, source: &Path) -> Result<(), Error> {
+        match self.find(&self.refuse, source) {
+            Some(rule) => Err(Error::Protected {
+                path: source.to_path_buf(),
+                reason: format!(
+                    "it matches the refused pattern {} in {}",
+                    rule.glob,
+                    self.path.display()
+                ),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether `source` (an absolute path) should be permanently deleted
+    pub fn is_hard_delete(&self, source: &Path) -> bool {
+        self.trusted && self.find(&self.hard_delete, source).is_some()
+    }
+
+    fn find<'a>(&self, rules: &'a [Rule], source: &Path) -> Option<&'a Rule> {
+        let relative = source.strip_prefix(&self.root).ok()?;
+        rules.iter().find(|rule| {
+            relative
+                .ancestors()
+                .filter(|path| !path.as_os_str().is_empty())
+                .any(|path| rule.matches(path))
+        })
+    }
+}
+
+impl Rule {
+    fn parse(glob: String, path: &Path) -> Result<Rule, Error> {
+        let trimmed = glob.trim_end_matches('/');
+        let (trimmed, anchored) = match trimmed.strip_prefix('/') {
+            Some(rest) => (rest, true),
+            None => (trimmed, trimmed.contains('/')),
+        };
+        let pattern = Pattern::new(trimmed).map_err(|e| {
+            Error::InvalidInput(format!(
+                "Invalid pattern {} in {}: {}",
+                glob,
+                path.display(),
+                e
+            ))
+        })?;
+        Ok(Rule {
+            glob,
+            pattern,
+            anchored,
+        })
+    }
+
+    fn matches(&self, relative: &Path) -> bool {
+        util::glob_matches(&self.pattern, relative, self.anchored)
+    }
+}
@@ -0,0 +1,111 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io;
+
+use crate::{util, Error};
+
+/// Answers the yes/no questions rip asks before doing something drastic
+///
+/// Returning `Err(Error::UserAborted)` stops the whole operation, like
+/// answering 'q' on the command line.
+pub trait Prompter {
+    fn ask(&self, question: &str) -> Result<bool, Error>;
+}
+
+impl<P: Prompter + ?Sized> Prompter for &P {
+    fn ask(&self, question: &str) -> Result<bool, Error> {
+        (**self).ask(question)
+    }
+}
+
+impl<P: Prompter + ?Sized> Prompter for Box<P> {
+    fn ask(&self, question: &str) -> Result<bool, Error> {
+        (**self).ask(question)
+    }
+}
+
+/// Reads the answer from the first character typed on stdin
+pub struct StdinPrompter;
+
+impl Prompter for StdinPrompter {
+    fn ask(&self, _question: &str) -> Result<bool, Error> {
+        util::yes_no_quit(io::stdin())
+    }
+}
+
+/// Answers yes to everything
+pub struct AlwaysYes;
+
+impl Prompter for AlwaysYes {
+    fn ask(&self, _question: &str) -> Result<bool, Error> {
+        Ok(true)
+    }
+}
+
+/// Answers no to everything
+pub struct AlwaysNo;
+
+impl Prompter for AlwaysNo {
+    fn ask(&self, _question: &str) -> Result<bool, Error> {
+        Ok(false)
+    }
+}
+
+/// Hands each question to a closure, e.g., to show a dialog
+pub struct CallbackPrompter<F>(pub F);
+
+impl<F: Fn(&str) -> Result<bool, Error>> Prompter for CallbackPrompter<F> {
+    fn ask(&self, question: &str) -> Result<bool, Error> {
+        (self.0)(question)
+    }
+}
+
+/// A single answer given by a [`ScriptedPrompter`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Answer {
+    Yes,
+    No,
+    Quit,
+}
+
+/// Gives a fixed sequence of answers, and remembers the questions asked.
+/// Running out of answers is an error.
+#[derive(Debug, Default)]
+pub struct ScriptedPrompter {
+    answers: RefCell<VecDeque<Answer>>,
+    questions: RefCell<Vec<String>>,
+}
+
+impl ScriptedPrompter {
+    pub fn new(answers: impl IntoIterator<Item = Answer>) -> ScriptedPrompter {
+        ScriptedPrompter {
+            answers: RefCell::new(answers.into_iter().collect()),
+            questions: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// The questions asked so far, in order
+    pub fn questions(&self) -> Vec<String> {
+        self.questions.borrow().clone()
+    }
+
+    /// The number of answers that haven't been used yet
+    pub fn remaining(&self) -> usize {
+        self.answers.borrow().len()
+    }
+}
+
+impl Prompter for ScriptedPrompter {
+    fn ask(&self, question: &str) -> Result<bool, Error> {
+        self.questions.borrow_mut().push(question.to_string());
+        match self.answers.borrow_mut().pop_front() {
+            Some(Answer::Yes) => Ok(true),
+            Some(Answer::No) => Ok(false),
+            Some(Answer::Quit) => Err(Error::UserAborted),
+            None => Err(Error::InvalidInput(format!(
+                "No scripted answer left for: {}",
+                question
+            ))),
+        }
+    }
+}
@@ -0,0 +1,119 @@
+use glob::Pattern;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::util::{self, home_dir};
+use crate::Error;
+
+#[cfg(unix)]
+use std::os::unix::fs::MetadataExt;
+
+/// Environment variable holding extra protected globs, separated like `$PATH`
+pub const PROTECTED_ENV: &str = "RIP_PROTECTED";
+
+/// Paths that rip refuses to bury unless forced to
+#[derive(Debug)]
+pub struct ProtectedPaths {
+    graveyard: PathBuf,
+    home: Option<PathBuf>,
+    cwd: Option<PathBuf>,
+    patterns: Vec<Pattern>,
+}
+
+impl ProtectedPaths {
+    /// Build the deny list for the given graveyard, including the
+    /// user-configured globs from `RIP_PROTECTED`.
+    pub fn new(graveyard: &Path) -> Result<ProtectedPaths, Error> {
+        let patterns = match env::var_os(PROTECTED_ENV) {
+            Some(globs) => env::split_paths(&globs)
+                .filter(|glob| !glob.as_os_str().is_empty())
+                .map(|glob| parse_pattern(&glob.to_string_lossy()))
+                .collect::<Result<Vec<_>, _>>()?,
+            None => Vec::new(),
+        };
+        Ok(ProtectedPaths {
+            graveyard: dunce::canonicalize(graveyard).unwrap_or_else(|_| graveyard.to_path_buf()),
+            home: home_dir().and_then(|home| dunce::canonicalize(home).ok()),
+            cwd: env::current_dir().and_then(dunce::canonicalize).ok(),
+            patterns,
+        })
+    }
+
+    /// Add more protected globs on top of the built-in deny list
+    pub fn with_patterns<I, S>(mut self, globs: I) -> Result<ProtectedPaths, Error>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        for glob in globs {
+            self.patterns.push(parse_pattern(glob.as_ref())?);
+        }
+        Ok(self)
+    }
+
+    /// Return an error if `source` (an absolute path) must not be buried
+    pub fn check(&self, source: &Path) -> Result<(), Error> {
+        match self.reason(source) {
+            Some(reason) => Err(Error::Protected {
+                path: source.to_path_buf(),
+                reason,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    fn reason(&self, source: &Path) -> Option<String> {
+        if source.parent().is_none() {
+            return Some("it is the filesystem root".to_string());
+        }
+        if self.home.as_deref() == Some(source) {
+            return Some("it is your home directory".to_string());
+        }
+        if source != self.graveyard && self.graveyard.starts_with(source) {
+            return Some("it contains the graveyard".to_string());
+        }
+        if is_mount_point(source) {
+            return Some("it is a mount point".to_string());
+        }
+        // `rip .` at the root of a repository, or `rip ..` below it, but not
+        // a repository elsewhere, such as an old clone
+        let holds_cwd = self
+            .cwd
+            .as_deref()
+            .is_some_and(|cwd| cwd.starts_with(source));
+        if holds_cwd && source.join(".git").exists() {
+            return Some("it is the root of the current repository".to_string());
+        }
+        self.patterns
+            .iter()
+            .find(|pattern| util::glob_matches(pattern, source, util::is_anchored(pattern)))
+            .map(|pattern| format!("it matches the protected pattern {}", pattern))
+    }
+}
+
+fn parse_pattern(glob: &str) -> Result<Pattern, Error> {
+    let expanded = match (glob.strip_prefix("~/"), home_dir()) {
+        (Some(rest), Some(home)) => home.join(rest).to_string_lossy().into_owned(),
+        _ => glob.to_string(),
+    };
+    Pattern::new(&expanded)
+        .map_err(|e| Error::InvalidInput(format!("Invalid protected pattern {}: {}", glob, e)))
+}
+
+#[cfg(unix)]
+fn is_mount_point(source: &Path) -> bool {
+    let (Ok(metadata), Some(parent)) = (fs::symlink_metadata(source), source.parent()) else {
+        return false;
+    };
+    if !metadata.is_dir() {
+        return false;
+    }
+    fs::metadata(parent).is_ok_and(|parent_metadata| parent_metadata.dev() != metadata.dev())
+}
+
+#[cfg(target_os = "windows")]
+fn is_mount_point(_source: &Path) -> bool {
+    // Drive roots are already caught as filesystem roots
+    false
+}
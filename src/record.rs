@@ -1,14 +1,38 @@
 use chrono::Local;
 use fs4::fs_std::FileExt;
-use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Write};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::iter;
+use std::ops::Deref;
 use std::path::{Path, PathBuf};
-use std::{fs, io};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
 
-use crate::util;
+use crate::{util, Error};
+
+mod index;
+
+pub use index::INDEX;
+use index::{Index, Key};
 
 pub const RECORD: &str = ".record";
+/// Where each pass over the graves left off, see [`Record::advance`]
+pub const CURSORS: &str = ".record.cursors";
+const HEADER: &str = "Time\tOriginal\tDestination";
+/// Holds the PID of the process with the exclusive lock on the record
+const LOCK_OWNER: &str = ".record.lock";
+/// How long to wait for other processes to release the record by default
+pub const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// Records with at least this many entries are compacted automatically
+/// once a quarter of them are tombstones
+const AUTO_COMPACT_ENTRIES: u64 = 1024;
 
-#[derive(Debug)]
+#[derive(Clone, Debug, Serialize)]
 pub struct RecordItem {
     pub time: String,
     pub orig: PathBuf,
@@ -17,29 +41,186 @@ pub struct RecordItem {
 
 impl RecordItem {
     /// Parse a line in the record into a `RecordItem`
-    pub fn new(line: &str) -> RecordItem {
+    pub fn new(line: &str) -> Result<RecordItem, Error> {
         let mut tokens = line.split('\t');
-        let time = tokens.next().expect("Bad format: column 1").to_string();
-        let orig = tokens.next().expect("Bad format: column 2").to_string();
-        let dest = tokens.next().expect("Bad format: column 3").to_string();
-        RecordItem {
-            time,
-            orig: PathBuf::from(orig),
-            dest: PathBuf::from(dest),
+        match (tokens.next(), tokens.next(), tokens.next()) {
+            (Some(time), Some(orig), Some(dest))
+                if !time.is_empty() && !orig.is_empty() && !dest.is_empty() =>
+            {
+                Ok(RecordItem {
+                    time: time.to_string(),
+                    orig: PathBuf::from(orig),
+                    dest: PathBuf::from(dest),
+                })
+            }
+            _ => Err(Error::RecordCorrupt {
+                line: line.to_string(),
+            }),
+        }
+    }
+}
+
+/// What happened to a grave after it was buried
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Tombstone {
+    /// The grave was returned to where it came from
+    Unburied,
+    /// The grave was permanently deleted
+    Purged,
+    /// The grave disappeared from the graveyard
+    Missing,
+    /// The grave was packed into an archive, which is recorded as a new
+    /// grave with the same time
+    Compressed,
+    /// The grave was moved into the store, and its manifest is recorded as
+    /// a new grave with the same time
+    Deduplicated,
+    /// The grave was encrypted, and the encrypted grave is recorded as a
+    /// new grave with the same time
+    Encrypted,
+}
+
+impl Tombstone {
+    fn parse(tombstone: &str) -> Option<Tombstone> {
+        match tombstone {
+            "unburied" => Some(Tombstone::Unburied),
+            "purged" => Some(Tombstone::Purged),
+            "missing" => Some(Tombstone::Missing),
+            "compressed" => Some(Tombstone::Compressed),
+            "deduplicated" => Some(Tombstone::Deduplicated),
+            "encrypted" => Some(Tombstone::Encrypted),
+            _ => None,
         }
     }
 }
 
+impl fmt::Display for Tombstone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Tombstone::Unburied => write!(f, "unburied"),
+            Tombstone::Purged => write!(f, "purged"),
+            Tombstone::Missing => write!(f, "missing"),
+            Tombstone::Compressed => write!(f, "compressed"),
+            Tombstone::Deduplicated => write!(f, "deduplicated"),
+            Tombstone::Encrypted => write!(f, "encrypted"),
+        }
+    }
+}
+
+/// A line in the record: either a grave, or a tombstone replacing an
+/// earlier grave at the same path
+///
+/// Tombstones have an empty original path, so that older versions of rip
+/// skip them as malformed: `time\t\tgrave\tunburied`
+#[derive(Clone, Debug)]
+enum Entry {
+    Bury(RecordItem),
+    Tombstone { dest: PathBuf },
+}
+
+impl Entry {
+    fn from_bytes(line: &[u8]) -> Option<Entry> {
+        let line = String::from_utf8_lossy(line);
+        let mut tokens = line.split('\t');
+        match (tokens.next(), tokens.next(), tokens.next(), tokens.next()) {
+            (Some(time), Some(""), Some(dest), Some(kind))
+                if !time.is_empty() && !dest.is_empty() =>
+            {
+                Tombstone::parse(kind)?;
+                Some(Entry::Tombstone {
+                    dest: PathBuf::from(dest),
+                })
+            }
+            _ => RecordItem::new(&line).ok().map(Entry::Bury),
+        }
+    }
+
+    fn dest(&self) -> &Path {
+        match self {
+            Entry::Bury(item) => &item.dest,
+            Entry::Tombstone { dest, .. } => dest,
+        }
+    }
+}
+
+/// Where a pass over the graves left off, so that the next one only looks
+/// at what is new
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Cursor {
+    /// The length of the record that was read, and a fingerprint of its
+    /// end, which tells whether the record was rewritten since
+    Offset { offset: u64, fingerprint: u64 },
+    /// The cutoff of a pass over graves by age
+    Time(String),
+}
+
+/// How much [`Record::compact`] shrank the record
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Compaction {
+    pub lines_before: usize,
+    pub lines_after: usize,
+}
+
+/// The graves in the record, as read by [`Record::scan`]
+#[derive(Clone, Debug, Default)]
+pub struct Scan {
+    /// Graves that haven't been unburied or purged, oldest first.
+    /// The same grave may be listed more than once.
+    pub live: Vec<RecordItem>,
+    /// Lines that are neither a grave nor a tombstone
+    pub malformed: Vec<String>,
+}
+
 /// A record of file operations maintained in the graveyard directory
 ///
+/// The record is a plain text file with one line per grave. It is only
+/// ever appended to: unburying or purging a grave appends a tombstone,
+/// and [`Record::compact`] drops them again. Lookups go through an index
+/// next to it, which is built from the text record the first time it is
+/// needed.
+///
 /// # Type Parameters
 ///
-/// * `FILE_LOCK` - When `true`, exclusive file locks are acquired when opening
-///   the record file for reading or writing. This prevents concurrent access from multiple
-///   processes. When `false`, no file locking is performed - which is used for testing.
+/// * `FILE_LOCK` - When `true`, file locks are acquired when opening the
+///   record file: shared ones for reading and exclusive ones for writing.
+///   This prevents concurrent access from multiple processes. When `false`,
+///   no file locking is performed - which is used for testing.
 #[derive(Debug)]
 pub struct Record<const FILE_LOCK: bool> {
     path: PathBuf,
+    lock_timeout: Duration,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Access {
+    Shared,
+    Exclusive,
+}
+
+/// The open record file, locked until it is dropped
+#[derive(Debug)]
+pub struct Locked {
+    file: fs::File,
+    /// The file naming us as the owner of an exclusive lock
+    owner: Option<PathBuf>,
+}
+
+impl Deref for Locked {
+    type Target = fs::File;
+
+    fn deref(&self) -> &fs::File {
+        &self.file
+    }
+}
+
+impl Drop for Locked {
+    fn drop(&mut self) {
+        // Still locked, so nobody else can have claimed it yet
+        if let Some(owner) = &self.owner {
+            fs::write(owner, "").ok();
+        }
+    }
 }
 
 #[cfg(not(target_os = "windows"))]
@@ -50,189 +231,531 @@ pub const DEFAULT_FILE_LOCK: bool = false;
 // TODO: Investigate why this is needed. Does Windows not support file locks?
 
 impl<const FILE_LOCK: bool> Record<FILE_LOCK> {
-    pub fn new(graveyard: &Path) -> Record<FILE_LOCK> {
-        let path = graveyard.join(RECORD);
+    pub fn new(graveyard: &Path) -> Result<Record<FILE_LOCK>, Error> {
+        let record = Record {
+            path: graveyard.join(RECORD),
+            lock_timeout: DEFAULT_LOCK_TIMEOUT,
+        };
         // Create the record file if it doesn't exist
-        if !path.exists() {
-            // Write a header to the record file
-            let mut record_file = fs::OpenOptions::new()
-                .truncate(true)
-                .create(true)
-                .write(true)
-                .open(&path)
-                .expect("Failed to open record file");
-            if FILE_LOCK {
-                record_file.lock_exclusive().unwrap();
-            }
-            record_file
-                .write_all(b"Time\tOriginal\tDestination\n")
-                .expect("Failed to write header to record file");
+        if !record.path.exists() {
+            record.create().map_err(|e| record.error(e))?;
         }
-        Record { path }
+        Ok(record)
     }
 
-    pub fn open(&self) -> Result<fs::File, Error> {
-        let file = fs::File::open(&self.path)
-            .map_err(|_| Error::new(ErrorKind::NotFound, "Failed to read record!"))?;
+    /// Create the record with a header, unless another process beat us to it
+    fn create(&self) -> io::Result<()> {
+        let mut record_file = match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&self.path)
+        {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => return Ok(()),
+            Err(e) => return Err(e),
+        };
         if FILE_LOCK {
-            file.lock_exclusive().unwrap();
+            record_file.lock_exclusive()?;
+        }
+        writeln!(record_file, "{}", HEADER)
+    }
+
+    /// Give up on locking the record after `timeout`, instead of after
+    /// [`DEFAULT_LOCK_TIMEOUT`]
+    pub fn with_lock_timeout(mut self, timeout: Duration) -> Self {
+        self.lock_timeout = timeout;
+        self
+    }
+
+    /// Open the record for writing, waiting until nobody else has it open
+    pub fn open(&self) -> Result<Locked, Error> {
+        self.open_with(Access::Exclusive)
+    }
+
+    /// Open the record for reading, waiting until nobody is writing to it
+    pub fn open_shared(&self) -> Result<Locked, Error> {
+        self.open_with(Access::Shared)
+    }
+
+    fn open_with(&self, access: Access) -> Result<Locked, Error> {
+        let deadline = Instant::now() + self.lock_timeout;
+        loop {
+            let file = fs::File::open(&self.path).map_err(|e| self.error(e))?;
+            if let Some(locked) = self.lock(file, access, deadline)? {
+                return Ok(locked);
+            }
         }
-        Ok(file)
+    }
+
+    /// Lock the record, returning `None` if a compaction replaced it while
+    /// we were waiting, in which case it has to be opened again
+    fn lock(
+        &self,
+        file: fs::File,
+        access: Access,
+        deadline: Instant,
+    ) -> Result<Option<Locked>, Error> {
+        if !FILE_LOCK {
+            return Ok(Some(Locked { file, owner: None }));
+        }
+        loop {
+            // Not the methods of the same name in std, which report
+            // contention as an error
+            let locked = match access {
+                Access::Shared => FileExt::try_lock_shared(&file),
+                Access::Exclusive => FileExt::try_lock_exclusive(&file),
+            }
+            .map_err(|e| self.error(e))?;
+            if locked {
+                break;
+            }
+            if Instant::now() >= deadline {
+                return Err(Error::GraveyardBusy {
+                    path: self.path.clone(),
+                    pid: self.owner(),
+                });
+            }
+            thread::sleep(LOCK_POLL_INTERVAL);
+        }
+        if !is_same_file(&file, &self.path).map_err(|e| self.error(e))? {
+            return Ok(None);
+        }
+        // Failing to name ourselves only makes a busy error less helpful
+        let owner = self.path.with_file_name(LOCK_OWNER);
+        let owner = (access == Access::Exclusive
+            && fs::write(&owner, process::id().to_string()).is_ok())
+        .then_some(owner);
+        Ok(Some(Locked { file, owner }))
+    }
+
+    /// The PID of the process holding the exclusive lock, if it's known
+    fn owner(&self) -> Option<u32> {
+        fs::read_to_string(self.path.with_file_name(LOCK_OWNER))
+            .ok()?
+            .trim()
+            .parse()
+            .ok()
+    }
+
+    fn index<'a>(&self, record_file: &'a fs::File) -> Result<Index<'a>, Error> {
+        Index::load(&self.index_path(), record_file).map_err(|e| self.error(e))
+    }
+
+    fn index_path(&self) -> PathBuf {
+        self.path.with_file_name(INDEX)
     }
 
     /// Return the path in the graveyard of the last file to be buried.
     /// As a side effect, any valid last files that are found in the record but
-    /// not on the filesystem are removed from the record.
+    /// not on the filesystem are marked as missing in the record.
     pub fn get_last_bury(&self) -> Result<PathBuf, Error> {
-        // record: impl AsRef<Path>
-        let record_file = self.open()?;
-        let mut contents = String::new();
-        BufReader::new(&record_file).read_to_string(&mut contents)?;
-
-        // This will be None if there is nothing, or Some
-        // if there is items in the vector
-        let mut graves_to_exhume: Vec<PathBuf> = Vec::new();
-        let mut lines = contents.lines();
-        lines.next();
-        for entry in lines.rev().map(RecordItem::new) {
+        let mut batch = self.get_last_batch()?;
+        Ok(batch.swap_remove(0))
+    }
+
+    /// The graves that were buried together with the most recent one and
+    /// are still in the graveyard, newest first
+    pub fn get_last_batch(&self) -> Result<Vec<PathBuf>, Error> {
+        let record_file = self.open_shared()?;
+        let index = self.index(&record_file)?;
+
+        let mut missing_graves: Vec<PathBuf> = Vec::new();
+        let mut batch: Vec<PathBuf> = Vec::new();
+        let mut batch_time = None;
+        for found in index.newest() {
+            let (offset, entry) = found.map_err(|e| self.error(e))?;
+            if batch_time.as_ref().is_some_and(|time| *time != entry.time) {
+                break;
+            }
+            if !index
+                .is_live(offset, &entry.dest)
+                .map_err(|e| self.error(e))?
+            {
+                continue;
+            }
             // Check that the file is still in the graveyard.
-            // If it is, return the corresponding line.
+            // If it is, add it to the batch.
             if util::symlink_exists(&entry.dest) {
-                if !graves_to_exhume.is_empty() {
-                    self.delete_lines(record_file, &graves_to_exhume)?;
-                }
-                return Ok(entry.dest);
+                batch_time = Some(entry.time);
+                batch.push(entry.dest);
             } else {
-                // File is gone, mark the grave to be removed from the record
-                graves_to_exhume.push(entry.dest);
+                // File is gone, mark the grave as missing in the record
+                missing_graves.push(entry.dest);
             }
         }
+        // Release our lock first, since appending takes its own
+        drop(index);
+        drop(record_file);
 
-        if !graves_to_exhume.is_empty() {
-            self.delete_lines(record_file, &graves_to_exhume)?;
+        if !missing_graves.is_empty() {
+            self.log_missing_graves(&missing_graves)?;
+        }
+        if batch.is_empty() {
+            return Err(Error::NothingToUnbury);
         }
-        Err(Error::new(ErrorKind::NotFound, "No files in graveyard"))
+        Ok(batch)
     }
 
-    /// Takes a vector of grave paths and removes the respective lines from the record
-    fn delete_lines(&self, record_file: fs::File, graves: &[PathBuf]) -> Result<(), Error> {
-        let record_path = &self.path;
-        // Get the lines to write back to the record, which is every line except
-        // the ones matching the exhumed graves. Store them in a vector
-        // since we'll be overwriting the record in-place.
-        let mut reader = BufReader::new(record_file).lines();
-        let header = reader
-            .next()
-            .unwrap_or_else(|| Ok(String::new()))
-            .unwrap_or_default(); // Capture the header
-        let lines_to_write: Vec<String> = reader
-            .map_while(Result::ok)
-            .filter(|line| !graves.iter().any(|y| *y == RecordItem::new(line).dest))
-            .collect();
-        // let mut new_record_file = fs::File::create(record_path)?;
-        let mut new_record_file = fs::OpenOptions::new()
-            .create(true)
-            .truncate(true)
-            .write(true)
-            .open(record_path)?;
-        if FILE_LOCK {
-            new_record_file.lock_exclusive().unwrap();
-        }
-        writeln!(new_record_file, "{}", header)?; // Write the header back
-        for line in lines_to_write {
-            writeln!(new_record_file, "{}", line)?;
+    /// Mark graves as returned to where they came from
+    pub fn log_exhumed_graves(&self, graves_to_exhume: &[PathBuf]) -> Result<(), Error> {
+        self.write_tombstones(graves_to_exhume, Tombstone::Unburied)
+    }
+
+    /// Mark graves as permanently deleted
+    pub fn log_purged_graves(&self, graves: &[PathBuf]) -> Result<(), Error> {
+        self.write_tombstones(graves, Tombstone::Purged)
+    }
+
+    /// Mark graves as having disappeared from the graveyard
+    pub fn log_missing_graves(&self, graves: &[PathBuf]) -> Result<(), Error> {
+        self.write_tombstones(graves, Tombstone::Missing)
+    }
+
+    /// Mark graves as packed into archives
+    pub fn log_compressed_graves(&self, graves: &[PathBuf]) -> Result<(), Error> {
+        self.write_tombstones(graves, Tombstone::Compressed)
+    }
+
+    /// Mark graves as moved into the store
+    pub fn log_deduplicated_graves(&self, graves: &[PathBuf]) -> Result<(), Error> {
+        self.write_tombstones(graves, Tombstone::Deduplicated)
+    }
+
+    /// Mark graves as encrypted
+    pub fn log_encrypted_graves(&self, graves: &[PathBuf]) -> Result<(), Error> {
+        self.write_tombstones(graves, Tombstone::Encrypted)
+    }
+
+    /// Append a tombstone for each grave, then compact the record if it
+    /// has become mostly tombstones and the graves they replace
+    fn write_tombstones(&self, graves: &[PathBuf], tombstone: Tombstone) -> Result<(), Error> {
+        let time = Local::now().to_rfc3339();
+        let lines = graves
+            .iter()
+            .map(|grave| format!("{}\t\t{}\t{}", time, grave.display(), tombstone));
+        self.append(lines)?;
+
+        let record_file = self.open()?;
+        let (entries, tombstones) = self.index(&record_file)?.counts();
+        if entries >= AUTO_COMPACT_ENTRIES && tombstones * 4 >= entries {
+            self.compact_locked(&record_file)?;
         }
         Ok(())
     }
 
-    pub fn log_exhumed_graves(&self, graves_to_exhume: &[PathBuf]) -> Result<(), Error> {
-        // Reopen the record and then delete lines corresponding to exhumed graves
+    /// Rewrite the record without tombstones and the graves they replace.
+    /// The new record is written next to the old one and moved into place,
+    /// so an interruption leaves the old record as it was.
+    pub fn compact(&self) -> Result<Compaction, Error> {
         let record_file = self.open()?;
-        self.delete_lines(record_file, graves_to_exhume)
-            .map_err(|e| {
-                Error::new(
-                    e.kind(),
-                    format!("Failed to remove unburied files from record: {}", e),
-                )
+        self.compact_locked(&record_file)
+    }
+
+    fn compact_locked(&self, record_file: &fs::File) -> Result<Compaction, Error> {
+        let lines = index::read_lines(record_file, 0).map_err(|e| self.error(e))?;
+        let lines_before = lines.len();
+
+        let mut last_tombstone: HashMap<PathBuf, u64> = HashMap::new();
+        for (offset, line) in &lines {
+            if let Some(Entry::Tombstone { dest, .. }) = Entry::from_bytes(line) {
+                last_tombstone.insert(dest, *offset);
+            }
+        }
+        // Malformed lines are kept as they are
+        let kept: Vec<&Vec<u8>> = lines
+            .iter()
+            .filter(|(offset, line)| match Entry::from_bytes(line) {
+                Some(Entry::Bury(item)) => last_tombstone
+                    .get(&item.dest)
+                    .is_none_or(|tombstone| tombstone < offset),
+                Some(Entry::Tombstone { .. }) => false,
+                None => true,
             })
+            .map(|(_, line)| line)
+            .collect();
+
+        let tmp = self.path.with_extension("tmp");
+        self.write_compacted(&tmp, &kept)
+            .inspect_err(|_| {
+                fs::remove_file(&tmp).ok();
+            })
+            .map_err(|e| self.error(e))?;
+
+        let compacted = fs::File::open(&self.path).map_err(|e| self.error(e))?;
+        Index::build(&self.index_path(), &compacted).map_err(|e| self.error(e))?;
+        Ok(Compaction {
+            lines_before,
+            lines_after: kept.len(),
+        })
     }
 
-    /// Takes a vector of grave paths and returns the respective lines in the record
+    fn write_compacted(&self, tmp: &Path, lines: &[&Vec<u8>]) -> io::Result<()> {
+        let mut writer = io::BufWriter::new(fs::File::create(tmp)?);
+        writeln!(writer, "{}", HEADER)?;
+        for line in lines {
+            writer.write_all(line)?;
+            writer.write_all(b"\n")?;
+        }
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+        // We still hold the lock on the old record, so anyone waiting for it
+        // will notice that it was replaced
+        fs::rename(tmp, &self.path)
+    }
+
+    /// Read the whole record, without going through the index
+    pub fn scan(&self) -> Result<Scan, Error> {
+        let record_file = self.open_shared()?;
+        let lines = index::read_lines(&record_file, 0).map_err(|e| self.error(e))?;
+
+        let mut entries = Vec::new();
+        let mut malformed = Vec::new();
+        let mut last_tombstone: HashMap<PathBuf, usize> = HashMap::new();
+        for (_, line) in &lines {
+            match Entry::from_bytes(line) {
+                Some(Entry::Tombstone { dest }) => {
+                    last_tombstone.insert(dest, entries.len());
+                }
+                Some(Entry::Bury(item)) => entries.push(item),
+                None if line.is_empty() => {}
+                None => malformed.push(String::from_utf8_lossy(line).into_owned()),
+            }
+        }
+        // A tombstone replaces every earlier grave at its path
+        let live = entries
+            .into_iter()
+            .enumerate()
+            .filter(|(i, item)| {
+                last_tombstone
+                    .get(&item.dest)
+                    .is_none_or(|tombstone| tombstone <= i)
+            })
+            .map(|(_, item)| item)
+            .collect();
+        Ok(Scan { live, malformed })
+    }
+
+    /// Takes a vector of grave paths and returns the respective entries in the record
     pub fn lines_of_graves<'a>(
         &'a self,
         graves: &'a [PathBuf],
-    ) -> impl Iterator<Item = String> + 'a {
-        let record_file = self.open().unwrap();
-        let mut reader = BufReader::new(record_file).lines();
-        reader.next();
-        reader
-            .map_while(Result::ok)
-            .filter(move |line| graves.iter().any(|y| *y == RecordItem::new(line).dest))
+    ) -> Result<impl Iterator<Item = RecordItem> + 'a, Error> {
+        let record_file = self.open_shared()?;
+        let index = self.index(&record_file)?;
+        let mut items = Vec::new();
+        for grave in graves {
+            let found = index.under(Key::Grave, grave).map_err(|e| self.error(e))?;
+            items.extend(found.into_iter().filter(|(_, item)| item.dest == *grave));
+        }
+        Ok(in_record_order(items).into_iter())
     }
 
     /// Returns an iterator over all graves in the record that are under gravepath
-    pub fn seance<'a>(
-        &'a self,
-        gravepath: &'a PathBuf,
-    ) -> io::Result<impl Iterator<Item = RecordItem> + 'a> {
-        let record_file = self.open()?;
-        let mut reader = BufReader::new(record_file).lines();
-        reader.next();
-        Ok(reader
-            .map_while(Result::ok)
-            .map(|line| RecordItem::new(&line))
-            .filter(move |record_item| record_item.dest.starts_with(gravepath)))
+    pub fn seance(&self, gravepath: &Path) -> Result<impl Iterator<Item = RecordItem>, Error> {
+        Ok(self
+            .query(|index| index.under(Key::Grave, gravepath))?
+            .into_iter())
     }
 
-    /// Write deletion history to record
-    pub fn write_log(&self, source: impl AsRef<Path>, dest: impl AsRef<Path>) -> io::Result<()> {
-        let (source, dest) = (source.as_ref(), dest.as_ref());
-
-        let already_existed = self.path.exists();
-
-        // TODO: The tiny amount of time between the check and the open
-        //       could allow for a race condition. But maybe I'm being overkill.
-
-        let mut record_file = if already_existed {
-            fs::OpenOptions::new().append(true).open(&self.path)?
-        } else {
-            fs::OpenOptions::new()
-                .create(true)
-                .truncate(true)
-                .write(true)
-                .open(&self.path)?
+    /// Returns an iterator over all graves that were buried from under `orig`
+    pub fn buried_from(&self, orig: &Path) -> Result<impl Iterator<Item = RecordItem>, Error> {
+        Ok(self
+            .query(|index| index.under(Key::Orig, orig))?
+            .into_iter())
+    }
+
+    /// Returns an iterator over all graves buried before `cutoff`.
+    /// Graves with an unparseable time are left out.
+    pub fn buried_before(
+        &self,
+        cutoff: chrono::DateTime<chrono::FixedOffset>,
+    ) -> Result<impl Iterator<Item = RecordItem>, Error> {
+        Ok(self
+            .query(move |index| index.before(cutoff, None))?
+            .into_iter())
+    }
+
+    /// The live graves recorded since `pass` last [advanced](Record::advance),
+    /// or all of them the first time and once the record was compacted,
+    /// together with the cursor to advance to once they are dealt with
+    pub fn recorded_since(&self, pass: &str) -> Result<(Vec<RecordItem>, Cursor), Error> {
+        let record_file = self.open_shared()?;
+        let index = self.index(&record_file)?;
+        let len = record_file.metadata().map_err(|e| self.error(e))?.len();
+        let start = match self.cursors().remove(pass) {
+            Some(Cursor::Offset {
+                offset,
+                fingerprint,
+            }) if offset <= len
+                && index::fingerprint_of(&record_file, offset)
+                    .is_ok_and(|found| found == fingerprint) =>
+            {
+                offset
+            }
+            _ => 0,
         };
 
-        if FILE_LOCK {
-            record_file.lock_exclusive().unwrap();
+        let mut items = Vec::new();
+        for (offset, line) in index::read_lines(&record_file, start).map_err(|e| self.error(e))? {
+            if let Some(Entry::Bury(item)) = Entry::from_bytes(&line) {
+                if index
+                    .is_live(offset, &item.dest)
+                    .map_err(|e| self.error(e))?
+                {
+                    items.push(item);
+                }
+            }
         }
+        let fingerprint = index::fingerprint_of(&record_file, len).map_err(|e| self.error(e))?;
+        Ok((
+            items,
+            Cursor::Offset {
+                offset: len,
+                fingerprint,
+            },
+        ))
+    }
 
-        if !already_existed {
-            writeln!(record_file, "Time\tOriginal\tDestination")?;
-        }
+    /// The live graves buried before `cutoff`, but not before the cutoff
+    /// `pass` last [advanced](Record::advance) to, together with the cursor
+    /// to advance to once they are dealt with
+    pub fn buried_before_since(
+        &self,
+        pass: &str,
+        cutoff: chrono::DateTime<chrono::FixedOffset>,
+    ) -> Result<(Vec<RecordItem>, Cursor), Error> {
+        let since = match self.cursors().remove(pass) {
+            Some(Cursor::Time(time)) => chrono::DateTime::parse_from_rfc3339(&time).ok(),
+            _ => None,
+        };
+        let items = self.query(move |index| index.before(cutoff, since))?;
+        Ok((items, Cursor::Time(cutoff.to_rfc3339())))
+    }
 
-        writeln!(
-            record_file,
+    /// Remember that `pass` dealt with everything up to `cursor`
+    pub fn advance(&self, pass: &str, cursor: Cursor) -> Result<(), Error> {
+        let mut cursors = self.cursors();
+        cursors.insert(pass.to_string(), cursor);
+        let json = serde_json::to_vec(&cursors).map_err(io::Error::from);
+        json.and_then(|json| fs::write(self.path.with_file_name(CURSORS), json))
+            .map_err(|e| self.error(e))
+    }
+
+    /// A missing or damaged file only means starting over
+    fn cursors(&self) -> HashMap<String, Cursor> {
+        fs::read(self.path.with_file_name(CURSORS))
+            .ok()
+            .and_then(|json| serde_json::from_slice(&json).ok())
+            .unwrap_or_default()
+    }
+
+    fn query(
+        &self,
+        lookup: impl FnOnce(&Index) -> io::Result<Vec<(u64, RecordItem)>>,
+    ) -> Result<Vec<RecordItem>, Error> {
+        let record_file = self.open_shared()?;
+        let index = self.index(&record_file)?;
+        let items = lookup(&index).map_err(|e| self.error(e))?;
+        Ok(in_record_order(items))
+    }
+
+    /// Write deletion history to record
+    pub fn write_log(&self, source: impl AsRef<Path>, dest: impl AsRef<Path>) -> Result<(), Error> {
+        self.write_log_at(&Local::now().to_rfc3339(), source, dest)
+    }
+
+    /// Write deletion history to record with the given time. Graves with
+    /// the same time form a batch, which [`Record::get_last_batch`] returns
+    /// together.
+    pub fn write_log_at(
+        &self,
+        time: &str,
+        source: impl AsRef<Path>,
+        dest: impl AsRef<Path>,
+    ) -> Result<(), Error> {
+        let line = format!(
             "{}\t{}\t{}",
-            Local::now().to_rfc3339(),
-            source.display(),
-            dest.display()
-        )
-        .map_err(|e| {
-            Error::new(
-                e.kind(),
-                format!("Failed to write record at {}", &self.path.display()),
-            )
-        })?;
+            time,
+            source.as_ref().display(),
+            dest.as_ref().display()
+        );
+        self.append(iter::once(line))
+    }
 
-        Ok(())
+    fn append(&self, lines: impl Iterator<Item = String>) -> Result<(), Error> {
+        let deadline = Instant::now() + self.lock_timeout;
+        let record_file = loop {
+            let already_existed = self.path.exists();
+
+            // TODO: The tiny amount of time between the check and the open
+            //       could allow for a race condition. But maybe I'm being overkill.
+
+            let record_file = if already_existed {
+                fs::OpenOptions::new().append(true).open(&self.path)
+            } else {
+                fs::OpenOptions::new()
+                    .create(true)
+                    .truncate(true)
+                    .write(true)
+                    .open(&self.path)
+            }
+            .map_err(|e| self.error(e))?;
+
+            let Some(record_file) = self.lock(record_file, Access::Exclusive, deadline)? else {
+                continue;
+            };
+
+            if !already_existed {
+                writeln!(&record_file.file, "{}", HEADER).map_err(|e| self.error(e))?;
+            }
+            break record_file;
+        };
+
+        // Write all lines at once, so that they can't be torn apart
+        let mut buffer = Vec::new();
+        for line in lines {
+            buffer.extend_from_slice(line.as_bytes());
+            buffer.push(b'\n');
+        }
+        (&record_file.file)
+            .write_all(&buffer)
+            .map_err(|e| self.error(e))
     }
+
+    fn error(&self, source: io::Error) -> Error {
+        Error::Record {
+            path: self.path.clone(),
+            source,
+        }
+    }
+}
+
+#[cfg(unix)]
+fn is_same_file(file: &fs::File, path: &Path) -> io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+    let (open, current) = (file.metadata()?, fs::metadata(path)?);
+    Ok(open.dev() == current.dev() && open.ino() == current.ino())
+}
+
+#[cfg(not(unix))]
+fn is_same_file(_file: &fs::File, _path: &Path) -> io::Result<bool> {
+    Ok(true)
+}
+
+/// Sort entries found by offset, dropping any found twice
+fn in_record_order(mut items: Vec<(u64, RecordItem)>) -> Vec<RecordItem> {
+    items.sort_by_key(|(offset, _)| *offset);
+    items.dedup_by_key(|(offset, _)| *offset);
+    items.into_iter().map(|(_, item)| item).collect()
 }
 
 impl<const FILE_LOCK: bool> Clone for Record<FILE_LOCK> {
     fn clone(&self) -> Self {
         Record {
             path: self.path.clone(),
+            lock_timeout: self.lock_timeout,
         }
     }
 }
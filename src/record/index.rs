@@ -0,0 +1,500 @@
+//! A sidecar index over the record, so that lookups by grave, original
+//! path or time don't have to read the whole record.
+//!
+//! The index holds the byte offsets of the record's lines, sorted three
+//! ways, and covers the record up to a given length. Lines appended after
+//! that (the tail) are scanned, and the index is rebuilt once the tail
+//! grows too long, or if the record was rewritten behind its back.
+//! Tombstones are only indexed by grave.
+//!
+//! ```text
+//! magic | covered: u64 | fingerprint: u64 | entries: u64 | buries: u64
+//! entries offsets by grave | buries offsets by original path | buries offsets by time
+//! ```
+
+use chrono::{DateTime, FixedOffset};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::{Entry, RecordItem, HEADER};
+
+pub const INDEX: &str = ".record.idx";
+const MAGIC: &[u8; 8] = b"RIPIDX2\n";
+const HEADER_LEN: u64 = 40;
+/// How much of the end of the indexed region is hashed to notice rewrites
+const FINGERPRINT_LEN: u64 = 64;
+/// The tail is reindexed once it holds more than this many lines,
+/// or an eighth of the indexed lines
+const MAX_TAIL: usize = 64;
+
+/// Tells apart the temporary files of indexes saved by this process
+static SAVES: AtomicUsize = AtomicUsize::new(0);
+
+/// The orders in which the index holds the record's lines
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum Key {
+    Grave,
+    Orig,
+    Time,
+}
+
+enum Sections {
+    File(fs::File),
+    /// Used when the index can't be saved, e.g., in a read-only graveyard
+    Memory([Vec<u64>; 3]),
+}
+
+pub(super) struct Index<'a> {
+    record: &'a fs::File,
+    sections: Sections,
+    /// Indexed lines, including tombstones
+    entries: u64,
+    /// Indexed buries
+    buries: u64,
+    tail: Vec<(u64, Entry)>,
+}
+
+impl<'a> Index<'a> {
+    /// Open the index at `path` for the locked `record`, building it if it
+    /// is missing, stale, or lagging too far behind
+    pub fn load(path: &Path, record: &'a fs::File) -> io::Result<Index<'a>> {
+        let record_len = record.metadata()?.len();
+        if let Some((file, covered, entries, buries)) = open(path, record, record_len)? {
+            let tail = parse(read_lines(record, covered)?);
+            if tail.len() <= MAX_TAIL.max(entries as usize / 8) {
+                return Ok(Index {
+                    record,
+                    sections: Sections::File(file),
+                    entries,
+                    buries,
+                    tail,
+                });
+            }
+        }
+        Index::build(path, record)
+    }
+
+    /// Index every line of the record, saving the index if possible
+    pub fn build(path: &Path, record: &'a fs::File) -> io::Result<Index<'a>> {
+        let entries = parse(read_lines(record, 0)?);
+        let covered = record.metadata()?.len();
+        let sections = [Key::Grave, Key::Orig, Key::Time].map(|key| sort(&entries, key));
+        let (entries, buries) = (sections[0].len() as u64, sections[1].len() as u64);
+
+        let sections = match save(path, record, covered, &sections) {
+            Ok(file) => Sections::File(file),
+            Err(_) => Sections::Memory(sections),
+        };
+        Ok(Index {
+            record,
+            sections,
+            entries,
+            buries,
+            tail: Vec::new(),
+        })
+    }
+
+    /// The number of entries and of tombstones in the record, ignoring garbage
+    pub fn counts(&self) -> (u64, u64) {
+        let tombstones = self
+            .tail
+            .iter()
+            .filter(|(_, entry)| matches!(entry, Entry::Tombstone { .. }))
+            .count() as u64;
+        (
+            self.entries + self.tail.len() as u64,
+            self.entries - self.buries + tombstones,
+        )
+    }
+
+    /// The live entries whose `key` path is `prefix` or lies under it
+    pub fn under(&self, key: Key, prefix: &Path) -> io::Result<Vec<(u64, RecordItem)>> {
+        let found = self.range(key, |path| path < prefix, |path| path.starts_with(prefix))?;
+        match key {
+            // Tombstones are in the same range, so liveness can be worked out directly
+            Key::Grave => Ok(live(found)),
+            Key::Orig | Key::Time => self.only_live(found),
+        }
+    }
+
+    /// The live entries buried before `cutoff`, and not before `since` if
+    /// given. Entries with an unparseable time are left out.
+    pub fn before(
+        &self,
+        cutoff: DateTime<FixedOffset>,
+        since: Option<DateTime<FixedOffset>>,
+    ) -> io::Result<Vec<(u64, RecordItem)>> {
+        let start = match since {
+            Some(since) => self.partition_point(Key::Time, |entry| time_of(entry) < Some(since))?,
+            None => 0,
+        };
+        let end = self.partition_point(Key::Time, |entry| time_of(entry) < Some(cutoff))?;
+        let mut found = Vec::new();
+        for i in start..end {
+            let (offset, entry) = self.get(Key::Time, i)?;
+            if time_of(&entry).is_some() {
+                found.push((offset, entry));
+            }
+        }
+        found.extend(
+            self.tail
+                .iter()
+                .filter(|(_, entry)| {
+                    time_of(entry).is_some_and(|time| {
+                        time < cutoff && since.is_none_or(|since| time >= since)
+                    })
+                })
+                .cloned(),
+        );
+        self.only_live(found)
+    }
+
+    /// Every bury, live or not, newest first
+    pub fn newest(&self) -> Newest<'_, 'a> {
+        let mut tail: Vec<(u64, RecordItem)> = self
+            .tail
+            .iter()
+            .filter_map(|(offset, entry)| match entry {
+                Entry::Bury(item) => Some((*offset, item.clone())),
+                Entry::Tombstone { .. } => None,
+            })
+            .collect();
+        // Compressed graves are recorded again with the time they were
+        // buried, so the tail isn't always in time order
+        tail.sort_by_key(newest_key);
+        Newest {
+            index: self,
+            tail,
+            indexed: self.buries,
+            peeked: None,
+        }
+    }
+
+    /// Whether the bury at `offset` has not been followed by a tombstone
+    pub fn is_live(&self, offset: u64, grave: &Path) -> io::Result<bool> {
+        let entries = self.range(Key::Grave, |path| path < grave, |path| path == grave)?;
+        Ok(!entries.iter().any(|(tombstone_offset, entry)| {
+            matches!(entry, Entry::Tombstone { .. }) && *tombstone_offset > offset
+        }))
+    }
+
+    fn only_live(&self, found: Vec<(u64, Entry)>) -> io::Result<Vec<(u64, RecordItem)>> {
+        let mut items = Vec::new();
+        for (offset, entry) in found {
+            if let Entry::Bury(item) = entry {
+                if self.is_live(offset, &item.dest)? {
+                    items.push((offset, item));
+                }
+            }
+        }
+        Ok(items)
+    }
+
+    /// The entries in the `key` order from where `before` turns false,
+    /// for as long as `within` holds, followed by the matching tail
+    fn range(
+        &self,
+        key: Key,
+        before: impl Fn(&Path) -> bool,
+        within: impl Fn(&Path) -> bool,
+    ) -> io::Result<Vec<(u64, Entry)>> {
+        let mut found = Vec::new();
+        let mut i = self.partition_point(key, |entry| before(path_of(key, entry)))?;
+        while i < self.len(key) {
+            let (offset, entry) = self.get(key, i)?;
+            if !within(path_of(key, &entry)) {
+                break;
+            }
+            found.push((offset, entry));
+            i += 1;
+        }
+        found.extend(
+            self.tail
+                .iter()
+                .filter(|(_, entry)| {
+                    (key == Key::Grave || matches!(entry, Entry::Bury(_)))
+                        && within(path_of(key, entry))
+                })
+                .cloned(),
+        );
+        Ok(found)
+    }
+
+    /// The first position in the `key` order at which `pred` is false
+    fn partition_point(&self, key: Key, pred: impl Fn(&Entry) -> bool) -> io::Result<u64> {
+        let (mut low, mut high) = (0, self.len(key));
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if pred(&self.get(key, mid)?.1) {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        Ok(low)
+    }
+
+    fn len(&self, key: Key) -> u64 {
+        match key {
+            Key::Grave => self.entries,
+            Key::Orig | Key::Time => self.buries,
+        }
+    }
+
+    fn get(&self, key: Key, i: u64) -> io::Result<(u64, Entry)> {
+        let offset = match &self.sections {
+            Sections::File(file) => {
+                let position = match key {
+                    Key::Grave => i,
+                    Key::Orig => self.entries + i,
+                    Key::Time => self.entries + self.buries + i,
+                };
+                let mut bytes = [0; 8];
+                let mut file = file;
+                file.seek(SeekFrom::Start(HEADER_LEN + position * 8))?;
+                file.read_exact(&mut bytes)?;
+                u64::from_le_bytes(bytes)
+            }
+            Sections::Memory(sections) => sections[key as usize][i as usize],
+        };
+        let line = read_line_at(self.record, offset)?;
+        let entry = Entry::from_bytes(&line).ok_or_else(stale)?;
+        Ok((offset, entry))
+    }
+}
+
+/// The buries of an [`Index`], newest first, merging the tail into the
+/// indexed ones
+pub(super) struct Newest<'i, 'a> {
+    index: &'i Index<'a>,
+    /// Oldest first
+    tail: Vec<(u64, RecordItem)>,
+    /// How many indexed buries are left
+    indexed: u64,
+    /// The newest indexed bury that is left, once it has been read
+    peeked: Option<(u64, RecordItem)>,
+}
+
+impl Iterator for Newest<'_, '_> {
+    type Item = io::Result<(u64, RecordItem)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.peeked.is_none() && self.indexed > 0 {
+            self.indexed -= 1;
+            match self.index.get(Key::Time, self.indexed) {
+                Ok((offset, Entry::Bury(item))) => self.peeked = Some((offset, item)),
+                Ok((_, Entry::Tombstone { .. })) => return Some(Err(stale())),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        let tail_is_newer = match (self.tail.last(), &self.peeked) {
+            (Some(tail), Some(indexed)) => newest_key(tail) > newest_key(indexed),
+            (tail, _) => tail.is_some(),
+        };
+        if tail_is_newer {
+            self.tail.pop().map(Ok)
+        } else {
+            self.peeked.take().map(Ok)
+        }
+    }
+}
+
+/// Orders buries the way the index orders them by time
+fn newest_key((offset, item): &(u64, RecordItem)) -> (Option<DateTime<FixedOffset>>, u64) {
+    (DateTime::parse_from_rfc3339(&item.time).ok(), *offset)
+}
+
+/// Keep the buries that aren't followed by a tombstone for the same grave
+fn live(found: Vec<(u64, Entry)>) -> Vec<(u64, RecordItem)> {
+    let mut last_tombstone: HashMap<&Path, u64> = HashMap::new();
+    for (offset, entry) in &found {
+        if let Entry::Tombstone { dest, .. } = entry {
+            let last = last_tombstone.entry(dest).or_default();
+            *last = (*last).max(*offset);
+        }
+    }
+    found
+        .iter()
+        .filter_map(|(offset, entry)| match entry {
+            Entry::Bury(item)
+                if last_tombstone
+                    .get(item.dest.as_path())
+                    .is_none_or(|tombstone| tombstone < offset) =>
+            {
+                Some((*offset, item.clone()))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+fn stale() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "Stale record index")
+}
+
+/// Open a saved index, unless it is missing or doesn't match the record
+fn open(
+    path: &Path,
+    record: &fs::File,
+    record_len: u64,
+) -> io::Result<Option<(fs::File, u64, u64, u64)>> {
+    let mut file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut header = [0; HEADER_LEN as usize];
+    if file.read_exact(&mut header).is_err() || &header[..8] != MAGIC {
+        return Ok(None);
+    }
+    let field = |i: usize| {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&header[i * 8..(i + 1) * 8]);
+        u64::from_le_bytes(bytes)
+    };
+    let (covered, fingerprint, entries, buries) = (field(1), field(2), field(3), field(4));
+    let index_len = file.metadata()?.len();
+    if covered > record_len
+        || buries > entries
+        || index_len != HEADER_LEN + 8 * (entries + 2 * buries)
+        || fingerprint_of(record, covered)? != fingerprint
+    {
+        return Ok(None);
+    }
+    Ok(Some((file, covered, entries, buries)))
+}
+
+/// Write the index to a temporary file and move it into place
+fn save(
+    path: &Path,
+    record: &fs::File,
+    covered: u64,
+    sections: &[Vec<u64>; 3],
+) -> io::Result<fs::File> {
+    // Readers only share the lock on the record, so they may be saving
+    // the index at the same time
+    let tmp = path.with_extension(format!(
+        "idx.{}-{}.tmp",
+        process::id(),
+        SAVES.fetch_add(1, Ordering::Relaxed)
+    ));
+    let saved = write_index(&tmp, record, covered, sections).and_then(|()| fs::rename(&tmp, path));
+    if saved.is_err() {
+        fs::remove_file(&tmp).ok();
+    }
+    saved?;
+    fs::File::open(path)
+}
+
+fn write_index(
+    tmp: &Path,
+    record: &fs::File,
+    covered: u64,
+    sections: &[Vec<u64>; 3],
+) -> io::Result<()> {
+    let mut writer = io::BufWriter::new(fs::File::create(tmp)?);
+    writer.write_all(MAGIC)?;
+    for field in [
+        covered,
+        fingerprint_of(record, covered)?,
+        sections[0].len() as u64,
+        sections[1].len() as u64,
+    ] {
+        writer.write_all(&field.to_le_bytes())?;
+    }
+    for offset in sections.iter().flatten() {
+        writer.write_all(&offset.to_le_bytes())?;
+    }
+    writer.into_inner().map_err(|e| e.into_error())?.sync_all()
+}
+
+fn sort(entries: &[(u64, Entry)], key: Key) -> Vec<u64> {
+    let mut sorted: Vec<&(u64, Entry)> = entries
+        .iter()
+        .filter(|(_, entry)| key == Key::Grave || matches!(entry, Entry::Bury(_)))
+        .collect();
+    match key {
+        Key::Grave | Key::Orig => sorted.sort_by(|(a_offset, a), (b_offset, b)| {
+            (path_of(key, a), a_offset).cmp(&(path_of(key, b), b_offset))
+        }),
+        Key::Time => sorted.sort_by_key(|(offset, entry)| (time_of(entry), *offset)),
+    }
+    sorted.into_iter().map(|(offset, _)| *offset).collect()
+}
+
+fn path_of(key: Key, entry: &Entry) -> &Path {
+    match (key, entry) {
+        (Key::Orig, Entry::Bury(item)) => &item.orig,
+        _ => entry.dest(),
+    }
+}
+
+fn time_of(entry: &Entry) -> Option<DateTime<FixedOffset>> {
+    match entry {
+        Entry::Bury(item) => DateTime::parse_from_rfc3339(&item.time).ok(),
+        Entry::Tombstone { .. } => None,
+    }
+}
+
+pub(super) fn parse(lines: Vec<(u64, Vec<u8>)>) -> Vec<(u64, Entry)> {
+    lines
+        .into_iter()
+        .filter_map(|(offset, line)| Some((offset, Entry::from_bytes(&line)?)))
+        .collect()
+}
+
+/// Read the lines of the record from `start` on, with their offsets,
+/// skipping the header
+pub(super) fn read_lines(record: &fs::File, start: u64) -> io::Result<Vec<(u64, Vec<u8>)>> {
+    let mut record = record;
+    record.seek(SeekFrom::Start(start))?;
+    let mut reader = BufReader::new(record);
+    let mut lines = Vec::new();
+    let mut offset = start;
+    loop {
+        let mut line = Vec::new();
+        let len = reader.read_until(b'\n', &mut line)?;
+        if len == 0 {
+            return Ok(lines);
+        }
+        trim_newline(&mut line);
+        if line != HEADER.as_bytes() {
+            lines.push((offset, line));
+        }
+        offset += len as u64;
+    }
+}
+
+fn read_line_at(record: &fs::File, offset: u64) -> io::Result<Vec<u8>> {
+    let mut record = record;
+    record.seek(SeekFrom::Start(offset))?;
+    let mut line = Vec::new();
+    BufReader::with_capacity(512, record).read_until(b'\n', &mut line)?;
+    trim_newline(&mut line);
+    Ok(line)
+}
+
+fn trim_newline(line: &mut Vec<u8>) {
+    if line.last() == Some(&b'\n') {
+        line.pop();
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+}
+
+/// FNV-1a of the end of the indexed region
+pub(super) fn fingerprint_of(record: &fs::File, covered: u64) -> io::Result<u64> {
+    let start = covered.saturating_sub(FINGERPRINT_LEN);
+    let mut bytes = vec![0; (covered - start) as usize];
+    let mut record = record;
+    record.seek(SeekFrom::Start(start))?;
+    record.read_exact(&mut bytes)?;
+    Ok(bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+    }))
+}
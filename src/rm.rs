@@ -0,0 +1,204 @@
+//! Compatibility with rm's command line, so that rip can stand in for it:
+//! `alias rm=rip`, a link to rip named `rm`, or `rip --rm-compat`.
+
+use std::ffi::{OsStr, OsString};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::args::Args;
+use crate::event::TextRenderer;
+use crate::prompt::{AlwaysNo, Prompter};
+use crate::{util, Error};
+
+/// The flag that switches rip to rm's command line
+pub const RM_COMPAT_FLAG: &str = "--rm-compat";
+
+/// When rm asks before removing something
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Interactive {
+    #[default]
+    Never,
+    /// Once, before removing more than three targets or removing
+    /// recursively (`-I`)
+    Once,
+    /// Before every target (`-i`)
+    Always,
+}
+
+/// An rm command line, mapped onto burying
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RmArgs {
+    pub targets: Vec<PathBuf>,
+    /// Ignore targets that don't exist, and never ask (`-f`)
+    pub force: bool,
+    pub interactive: Interactive,
+    /// `-r`. rip always buries directories whole, so this only decides
+    /// whether `-I` asks.
+    pub recursive: bool,
+    pub verbose: bool,
+    pub one_file_system: bool,
+    /// rip's own `--force-protected`, which rm doesn't have
+    pub force_protected: bool,
+}
+
+/// Whether rip should take rm's command line, because it was run as `rm`
+/// or was passed `--rm-compat`
+pub fn requested(args: &[OsString]) -> bool {
+    let run_as_rm = args
+        .first()
+        .and_then(|arg0| Path::new(arg0).file_stem())
+        .is_some_and(|name| name == "rm");
+    run_as_rm
+        || args
+            .iter()
+            .skip(1)
+            .take_while(|arg| *arg != "--")
+            .any(|arg| arg == RM_COMPAT_FLAG)
+}
+
+/// Parse an rm command line, without the program name
+pub fn parse<I, S>(args: I) -> Result<RmArgs, Error>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let mut rm = RmArgs::default();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let arg = arg.as_ref();
+        let Some(flag) = arg
+            .to_str()
+            .filter(|arg| arg.starts_with('-') && *arg != "-")
+        else {
+            rm.targets.push(PathBuf::from(arg));
+            continue;
+        };
+        if flag == "--" {
+            rm.targets
+                .extend(args.map(|arg| PathBuf::from(arg.as_ref())));
+            break;
+        } else if let Some(long) = flag.strip_prefix("--") {
+            rm.long(long)?;
+        } else {
+            for short in flag.chars().skip(1) {
+                rm.short(short)?;
+            }
+        }
+    }
+    Ok(rm)
+}
+
+impl RmArgs {
+    fn long(&mut self, flag: &str) -> Result<(), Error> {
+        match flag.split_once('=') {
+            Some(("interactive", when)) => {
+                self.interactive = match when {
+                    "never" | "no" | "none" => Interactive::Never,
+                    "once" => Interactive::Once,
+                    "always" | "yes" => Interactive::Always,
+                    _ => {
+                        return Err(Error::InvalidInput(format!(
+                            "Invalid argument '{}' for '--interactive'",
+                            when
+                        )))
+                    }
+                };
+                Ok(())
+            }
+            Some(("preserve-root", "all")) => Ok(()),
+            _ => match flag {
+                "recursive" => self.short('r'),
+                "force" => self.short('f'),
+                "interactive" => self.short('i'),
+                "verbose" => self.short('v'),
+                "dir" => self.short('d'),
+                // rip never removes the root, nor anything else protected
+                "preserve-root" => Ok(()),
+                "one-file-system" => {
+                    self.one_file_system = true;
+                    Ok(())
+                }
+                "force-protected" => {
+                    self.force_protected = true;
+                    Ok(())
+                }
+                "rm-compat" => Ok(()),
+                _ => Err(Error::InvalidInput(format!(
+                    "Unrecognized option '--{}'",
+                    flag
+                ))),
+            },
+        }
+    }
+
+    fn short(&mut self, flag: char) -> Result<(), Error> {
+        match flag {
+            'r' | 'R' => self.recursive = true,
+            // Like in rm, the last of -f, -i and -I wins
+            'f' => {
+                self.force = true;
+                self.interactive = Interactive::Never;
+            }
+            'i' => {
+                self.force = false;
+                self.interactive = Interactive::Always;
+            }
+            'I' => {
+                self.force = false;
+                self.interactive = Interactive::Once;
+            }
+            'v' => self.verbose = true,
+            // rip buries empty directories like any other
+            'd' => {}
+            _ => return Err(Error::InvalidInput(format!("Invalid option -- '{}'", flag))),
+        }
+        Ok(())
+    }
+}
+
+/// Bury the targets of an rm command line
+pub fn run(rm: RmArgs, prompter: impl Prompter, stream: &mut impl Write) -> Result<(), Error> {
+    let targets: Vec<PathBuf> = if rm.force {
+        rm.targets
+            .into_iter()
+            .filter(|target| util::symlink_exists(target))
+            .collect()
+    } else {
+        rm.targets
+    };
+    if targets.is_empty() {
+        return if rm.force {
+            Ok(())
+        } else {
+            Err(Error::InvalidInput("Missing operand".to_string()))
+        };
+    }
+
+    if rm.interactive == Interactive::Once && (targets.len() > 3 || rm.recursive) {
+        let question = format!(
+            "Remove {} argument{}{}?",
+            targets.len(),
+            if targets.len() == 1 { "" } else { "s" },
+            if rm.recursive { " recursively" } else { "" }
+        );
+        if !util::prompt_yes(question, &prompter, &mut TextRenderer::new(&mut *stream))? {
+            return Ok(());
+        }
+    }
+
+    let cli = Args {
+        targets,
+        inspect: rm.interactive == Interactive::Always,
+        one_file_system: rm.one_file_system,
+        force_protected: rm.force_protected,
+        verbose: rm.verbose,
+        ..Args::default()
+    };
+    if rm.force {
+        // rip's own questions, e.g., about big files, get the answer of
+        // an empty stdin
+        crate::run(cli, AlwaysNo, stream)
+    } else {
+        crate::run(cli, prompter, stream)
+    }
+}
@@ -0,0 +1,172 @@
+//! Choosing what to bury: expanding globs that the shell left alone, and
+//! leaving parts of directories in place with `--exclude` and `--include`.
+
+use glob::Pattern;
+use std::fs;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Component, Path, PathBuf};
+
+use crate::error::IoContext;
+use crate::{util, Error};
+
+/// Expand targets that don't exist but look like globs, for shells that
+/// don't expand them (e.g., cmd on Windows) and scripts that pass quoted
+/// patterns. Globs that match nothing are kept as they are, so that they
+/// are reported like any other missing target.
+pub fn expand_globs(targets: &[PathBuf]) -> Vec<PathBuf> {
+    let mut expanded = Vec::new();
+    for target in targets {
+        let matches = match target.to_str() {
+            Some(glob) if !util::symlink_exists(target) && glob.contains(['*', '?', '[']) => {
+                glob::glob_with(glob, util::MATCH_OPTIONS)
+                    .map(|paths| paths.filter_map(Result::ok).collect())
+                    .unwrap_or_default()
+            }
+            _ => Vec::new(),
+        };
+        if matches.is_empty() {
+            expanded.push(target.clone());
+        } else {
+            expanded.extend(matches);
+        }
+    }
+    expanded
+}
+
+/// Read a list of targets, one per line or, with `null`, separated by NUL
+/// bytes as printed by `find -print0`. Empty entries are skipped.
+pub fn read_targets(reader: impl Read, null: bool) -> Result<Vec<PathBuf>, Error> {
+    let separator = if null { b'\0' } else { b'\n' };
+    let mut targets = Vec::new();
+    for entry in BufReader::new(reader).split(separator) {
+        let mut entry = entry.context(|| "Failed to read the list of targets".to_string())?;
+        if !null && entry.last() == Some(&b'\r') {
+            entry.pop();
+        }
+        if !entry.is_empty() {
+            targets.push(util::path_from_bytes(entry)?);
+        }
+    }
+    Ok(targets)
+}
+
+/// Which parts of the targets to bury, and which to leave in place
+///
+/// A path that matches an `--include` glob is buried, one that matches an
+/// `--exclude` glob is left in place, and one that matches neither does
+/// what its directory does. Targets are buried unless only includes were
+/// given, in which case they are left in place apart from what matches.
+#[derive(Debug, Default)]
+pub struct Selection {
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+    cwd: PathBuf,
+}
+
+impl Selection {
+    /// Globs with a path separator are matched against the path relative to
+    /// `cwd` (or the absolute path, if they are absolute), others only
+    /// against the file name.
+    pub fn new<S: AsRef<str>>(
+        include: &[S],
+        exclude: &[S],
+        cwd: &Path,
+    ) -> Result<Selection, Error> {
+        Ok(Selection {
+            include: parse_patterns(include)?,
+            exclude: parse_patterns(exclude)?,
+            cwd: cwd.to_path_buf(),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    /// The paths to bury for `target`: the target itself if all of it is
+    /// selected, otherwise the largest parts of it that are
+    pub fn select(&self, target: &Path) -> Result<Vec<PathBuf>, Error> {
+        // Missing targets are left for burying to report
+        if self.is_empty() || !util::symlink_exists(target) {
+            return Ok(vec![target.to_path_buf()]);
+        }
+        let default = self.include.is_empty() || !self.exclude.is_empty();
+        let mut paths = Vec::new();
+        if self.collect(target, default, &mut paths)? {
+            paths = vec![target.to_path_buf()];
+        }
+        Ok(paths)
+    }
+
+    /// Push the selected parts of `path` onto `paths`, unless all of it is
+    /// selected, in which case return true and leave `paths` alone
+    fn collect(
+        &self,
+        path: &Path,
+        inherited: bool,
+        paths: &mut Vec<PathBuf>,
+    ) -> Result<bool, Error> {
+        let buried = if self.include.iter().any(|p| self.matches(p, path)) {
+            true
+        } else if self.exclude.iter().any(|p| self.matches(p, path)) {
+            false
+        } else {
+            inherited
+        };
+        let context = || format!("Failed to read {}", path.display());
+        if !fs::symlink_metadata(path).context(context)?.is_dir() {
+            return Ok(buried);
+        }
+
+        let mut children = fs::read_dir(path)
+            .context(context)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, _>>()
+            .context(context)?;
+        children.sort();
+        let start = paths.len();
+        let mut whole = buried;
+        for child in children {
+            if self.collect(&child, buried, paths)? {
+                paths.push(child);
+            } else {
+                whole = false;
+            }
+        }
+        if whole {
+            paths.truncate(start);
+        }
+        Ok(whole)
+    }
+
+    fn matches(&self, pattern: &Pattern, path: &Path) -> bool {
+        if !util::is_anchored(pattern) {
+            return util::glob_matches(pattern, path, false);
+        }
+        let absolute = normalize(&self.cwd.join(path));
+        let path = if Path::new(pattern.as_str()).is_absolute() {
+            absolute.as_path()
+        } else {
+            absolute.strip_prefix(&self.cwd).unwrap_or(&absolute)
+        };
+        util::glob_matches(pattern, path, true)
+    }
+}
+
+/// Drop `.` from a path, so that `./build` matches `build/**`
+fn normalize(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|component| *component != Component::CurDir)
+        .collect()
+}
+
+fn parse_patterns<S: AsRef<str>>(globs: &[S]) -> Result<Vec<Pattern>, Error> {
+    globs
+        .iter()
+        .map(|glob| {
+            let glob = glob.as_ref();
+            Pattern::new(glob)
+                .map_err(|e| Error::InvalidInput(format!("Invalid pattern {}: {}", glob, e)))
+        })
+        .collect()
+}
@@ -0,0 +1,210 @@
+//! `rip sweep`: bury stale files and empty directories, as an undoable
+//! replacement for `find ... -delete`.
+
+use glob::Pattern;
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use walkdir::WalkDir;
+
+use crate::args::{Args, OutputFormat};
+use crate::config::Config;
+use crate::error::IoContext;
+use crate::prompt::Prompter;
+use crate::{util, Error};
+
+/// Which files and directories `rip sweep` buries
+///
+/// A file is swept if it matches every criterion that is given. Empty
+/// directories are only swept with `empty_dirs`, including those that are
+/// left empty once their files are swept. The criteria don't apply to them,
+/// since a directory's modification time only tells when an entry was last
+/// added or removed.
+#[derive(Clone, Debug, Default)]
+pub struct SweepOptions {
+    pub empty_dirs: bool,
+    /// Files last modified longer ago than this
+    pub older_than: Option<Duration>,
+    /// Files bigger than this many bytes
+    pub larger_than: Option<u64>,
+    /// Files matching one of these globs. Globs with a path separator are
+    /// matched against the path relative to the swept directory, others
+    /// only against the file name.
+    pub patterns: Vec<String>,
+}
+
+impl SweepOptions {
+    fn selects_files(&self) -> bool {
+        self.older_than.is_some() || self.larger_than.is_some() || !self.patterns.is_empty()
+    }
+}
+
+/// What [`find`] found to sweep
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Sweep {
+    /// The largest files and directories that are swept whole
+    pub targets: Vec<PathBuf>,
+    pub files: usize,
+    pub dirs: usize,
+    pub bytes: u64,
+}
+
+/// Bury what `opts` selects under `dir` in a single batch, and print a
+/// summary. With `dry_run`, only print what would be buried.
+pub fn run(
+    cli: &Args,
+    dir: &Path,
+    opts: &SweepOptions,
+    dry_run: bool,
+    prompter: impl Prompter,
+    stream: &mut impl Write,
+) -> Result<(), Error> {
+    let cwd = &env::current_dir()?;
+    let config = Config::load(cli)?;
+    let sweep = find(&cwd.join(dir), opts, &config.graveyard.value)?;
+    let targets: Vec<PathBuf> = sweep
+        .targets
+        .iter()
+        .map(|target| target.strip_prefix(cwd).unwrap_or(target).to_path_buf())
+        .collect();
+
+    if dry_run {
+        for target in &targets {
+            writeln!(stream, "Would bury {}", target.display())?;
+        }
+    } else if !targets.is_empty() {
+        crate::bury_batch(cli, &targets, prompter, stream)?;
+    }
+    if config.format.value == OutputFormat::Text {
+        writeln!(
+            stream,
+            "{} {} files and {} directories ({})",
+            if dry_run { "Would sweep" } else { "Swept" },
+            sweep.files,
+            sweep.dirs,
+            util::humanize_bytes(sweep.bytes)
+        )?;
+    }
+    Ok(())
+}
+
+/// Find what `opts` selects under `dir`, leaving out the graveyard
+pub fn find(dir: &Path, opts: &SweepOptions, graveyard: &Path) -> Result<Sweep, Error> {
+    if !opts.empty_dirs && !opts.selects_files() {
+        return Err(Error::InvalidInput(
+            "Nothing to sweep: pass --empty-dirs, --older-than, --larger-than or --pattern"
+                .to_string(),
+        ));
+    }
+    let patterns = opts
+        .patterns
+        .iter()
+        .map(|glob| {
+            Pattern::new(glob)
+                .map_err(|e| Error::InvalidInput(format!("Invalid pattern {}: {}", glob, e)))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let sweeper = Sweeper {
+        root: &dunce::canonicalize(dir).context(|| format!("Failed to read {}", dir.display()))?,
+        opts,
+        patterns,
+        graveyard: dunce::canonicalize(graveyard).unwrap_or_else(|_| graveyard.to_path_buf()),
+        now: SystemTime::now(),
+    };
+
+    let mut sweep = Sweep::default();
+    sweeper.visit(sweeper.root, &mut sweep.targets)?;
+    for target in &sweep.targets {
+        for entry in WalkDir::new(target) {
+            let entry = entry
+                .map_err(io::Error::from)
+                .context(|| format!("Failed to read {}", target.display()))?;
+            if entry.file_type().is_dir() {
+                sweep.dirs += 1;
+            } else {
+                sweep.files += 1;
+                sweep.bytes += entry
+                    .metadata()
+                    .map_err(io::Error::from)
+                    .context(|| format!("Failed to read {}", entry.path().display()))?
+                    .len();
+            }
+        }
+    }
+    Ok(sweep)
+}
+
+struct Sweeper<'a> {
+    root: &'a Path,
+    opts: &'a SweepOptions,
+    patterns: Vec<Pattern>,
+    graveyard: PathBuf,
+    now: SystemTime,
+}
+
+impl Sweeper<'_> {
+    /// Push the swept parts of `dir` onto `targets`, and return whether `dir`
+    /// is swept whole instead. Only directories below the root are, and only
+    /// with `empty_dirs`, since they are left empty otherwise.
+    fn visit(&self, dir: &Path, targets: &mut Vec<PathBuf>) -> Result<bool, Error> {
+        let context = || format!("Failed to read {}", dir.display());
+        let mut children = fs::read_dir(dir)
+            .context(context)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, _>>()
+            .context(context)?;
+        children.sort();
+
+        let start = targets.len();
+        let mut whole = true;
+        for child in children {
+            let metadata = fs::symlink_metadata(&child)
+                .context(|| format!("Failed to read {}", child.display()))?;
+            let swept = if child == self.graveyard || self.graveyard.starts_with(&child) {
+                // Burying the graveyard, or anything in it, makes no sense
+                false
+            } else if metadata.is_dir() {
+                self.visit(&child, targets)?
+            } else {
+                self.selects(&child, &metadata)
+            };
+            if swept {
+                targets.push(child);
+            } else {
+                whole = false;
+            }
+        }
+        let swept = whole && self.opts.empty_dirs && dir != self.root;
+        if swept {
+            targets.truncate(start);
+        }
+        Ok(swept)
+    }
+
+    fn selects(&self, file: &Path, metadata: &fs::Metadata) -> bool {
+        if !self.opts.selects_files() {
+            return false;
+        }
+        let old_enough = self.opts.older_than.is_none_or(|age| {
+            metadata
+                .modified()
+                .ok()
+                .and_then(|modified| self.now.duration_since(modified).ok())
+                .is_some_and(|elapsed| elapsed > age)
+        });
+        let big_enough = self
+            .opts
+            .larger_than
+            .is_none_or(|size| metadata.len() > size);
+        let matches =
+            self.patterns.is_empty() || self.patterns.iter().any(|p| self.matches(p, file));
+        old_enough && big_enough && matches
+    }
+
+    fn matches(&self, pattern: &Pattern, file: &Path) -> bool {
+        let relative = file.strip_prefix(self.root).unwrap_or(file);
+        util::glob_matches(pattern, relative, util::is_anchored(pattern))
+    }
+}
@@ -1,11 +1,17 @@
+use glob::{MatchOptions, Pattern};
 use std::collections::hash_map::DefaultHasher;
 use std::env;
 use std::fs;
 use std::hash::{Hash, Hasher};
-use std::io::{self, BufReader, Error, Read, Write};
+use std::io::{BufReader, Read};
 use std::path::Prefix::Disk;
 use std::path::{Component, Path, PathBuf};
 use std::str::from_utf8;
+use std::time::Duration;
+
+use crate::event::{Event, Observer};
+use crate::prompt::Prompter;
+use crate::Error;
 
 fn hash_component(c: &Component) -> String {
     let mut hasher = DefaultHasher::new();
@@ -44,10 +50,122 @@ pub fn join_absolute<A: AsRef<Path>, B: AsRef<Path>>(left: A, right: B) -> PathB
     result
 }
 
+/// The inverse of [`join_absolute`]: the absolute path that was joined to
+/// `left` to make `joined`. Returns `None` if `joined` isn't under `left`,
+/// or, on Windows, if its prefix was hashed.
+pub fn split_absolute<A: AsRef<Path>, B: AsRef<Path>>(left: A, joined: B) -> Option<PathBuf> {
+    let relative = joined.as_ref().strip_prefix(left).ok()?;
+    #[cfg(target_os = "windows")]
+    {
+        let mut components = relative.components();
+        let prefix = components.next()?.as_os_str().to_str()?;
+        let disk = prefix.strip_prefix("DISK_")?;
+        Some(PathBuf::from(format!("{}:\\", disk)).join(components.as_path()))
+    }
+    #[cfg(not(target_os = "windows"))]
+    Some(Path::new("/").join(relative))
+}
+
 pub fn symlink_exists<P: AsRef<Path>>(path: P) -> bool {
     fs::symlink_metadata(path).is_ok()
 }
 
+/// A path from raw bytes, which on Windows have to be UTF-8
+#[cfg(unix)]
+pub fn path_from_bytes(bytes: Vec<u8>) -> Result<PathBuf, Error> {
+    use std::ffi::OsString;
+    use std::os::unix::ffi::OsStringExt;
+    Ok(PathBuf::from(OsString::from_vec(bytes)))
+}
+
+/// A path from raw bytes, which on Windows have to be UTF-8
+#[cfg(not(unix))]
+pub fn path_from_bytes(bytes: Vec<u8>) -> Result<PathBuf, Error> {
+    String::from_utf8(bytes)
+        .map(PathBuf::from)
+        .map_err(|e| Error::InvalidInput(format!("Path is not valid UTF-8: {}", e)))
+}
+
+/// The raw bytes of a path, as taken by [`path_from_bytes`]
+#[cfg(unix)]
+pub fn path_to_bytes(path: &Path) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    path.as_os_str().as_bytes().to_vec()
+}
+
+/// The raw bytes of a path, as taken by [`path_from_bytes`]
+#[cfg(not(unix))]
+pub fn path_to_bytes(path: &Path) -> Vec<u8> {
+    path.to_string_lossy().into_owned().into_bytes()
+}
+
+/// Serde for paths that might not be valid UTF-8, which JSON can't hold as
+/// strings. Paths that are valid UTF-8 stay plain strings, and the others
+/// become `{"base64": ...}` of their bytes.
+pub mod serde_path {
+    use base64ct::{Base64, Encoding};
+    use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+    use std::path::{Path, PathBuf};
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(untagged)]
+    enum Repr {
+        Text(String),
+        Bytes { base64: String },
+    }
+
+    impl Repr {
+        fn new(path: &Path) -> Repr {
+            match path.to_str() {
+                Some(text) => Repr::Text(text.to_string()),
+                None => Repr::Bytes {
+                    base64: Base64::encode_string(&super::path_to_bytes(path)),
+                },
+            }
+        }
+
+        fn into_path<E: de::Error>(self) -> Result<PathBuf, E> {
+            match self {
+                Repr::Text(text) => Ok(PathBuf::from(text)),
+                Repr::Bytes { base64 } => {
+                    let bytes = Base64::decode_vec(&base64).map_err(E::custom)?;
+                    super::path_from_bytes(bytes).map_err(E::custom)
+                }
+            }
+        }
+    }
+
+    pub fn serialize<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+        Repr::new(path).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+        Repr::deserialize(deserializer)?.into_path()
+    }
+
+    /// The same for optional paths
+    pub mod option {
+        use super::Repr;
+        use serde::{Deserialize, Deserializer, Serialize, Serializer};
+        use std::path::PathBuf;
+
+        pub fn serialize<S: Serializer>(
+            path: &Option<PathBuf>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            path.as_deref().map(Repr::new).serialize(serializer)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<PathBuf>, D::Error> {
+            Option::<Repr>::deserialize(deserializer)?
+                .map(Repr::into_path)
+                .transpose()
+        }
+    }
+}
+
 pub fn get_user() -> String {
     #[cfg(unix)]
     {
@@ -59,23 +177,21 @@ pub fn get_user() -> String {
     }
 }
 
-// Allows injection of test-specific behavior
-pub trait TestingMode {
-    fn is_test(&self) -> bool;
-}
-
-pub struct ProductionMode;
-pub struct TestMode;
-
-impl TestingMode for ProductionMode {
-    fn is_test(&self) -> bool {
-        false
+/// The name of this machine, or "unknown"
+pub fn get_host() -> String {
+    let host = gethostname::gethostname();
+    match host.to_string_lossy() {
+        host if host.is_empty() => String::from("unknown"),
+        host => host.into_owned(),
     }
 }
-impl TestingMode for TestMode {
-    fn is_test(&self) -> bool {
-        true
-    }
+
+pub fn home_dir() -> Option<PathBuf> {
+    #[cfg(unix)]
+    let home = env::var_os("HOME");
+    #[cfg(target_os = "windows")]
+    let home = env::var_os("USERPROFILE");
+    home.filter(|h| !h.is_empty()).map(PathBuf::from)
 }
 
 pub fn allow_rename() -> bool {
@@ -83,25 +199,19 @@ pub fn allow_rename() -> bool {
     env::var_os("__RIP_ALLOW_RENAME").map_or(true, |v| v != "false")
 }
 
-/// Prompt for user input, returning True if the first character is 'y' or 'Y'
-/// Will create an error if given a 'q' or 'Q', equivalent to if the user
-/// had passed a SIGINT.
+/// Tell the observer about the prompt and let the prompter answer it.
+/// With the default prompter, the answer is true if the first character is
+/// 'y' or 'Y', and an error is returned if it is 'q' or 'Q', equivalent to
+/// if the user had passed a SIGINT.
 pub fn prompt_yes(
     prompt: impl AsRef<str>,
-    source: &impl TestingMode,
-    stream: &mut impl Write,
+    prompter: &impl Prompter,
+    observer: &mut impl Observer,
 ) -> Result<bool, Error> {
-    write!(stream, "{} (y/N) ", prompt.as_ref())?;
-    if stream.flush().is_err() {
-        // If stdout wasn't flushed properly, fallback to println
-        writeln!(stream, "{} (y/N)", prompt.as_ref())?;
-    }
-
-    if source.is_test() {
-        return Ok(true);
-    }
-
-    yes_no_quit(io::stdin())
+    observer.notify(&Event::Prompted {
+        question: prompt.as_ref().to_string(),
+    })?;
+    prompter.ask(prompt.as_ref())
 }
 
 pub fn yes_no_quit(in_stream: impl Read) -> Result<bool, Error> {
@@ -115,11 +225,8 @@ pub fn yes_no_quit(in_stream: impl Read) -> Result<bool, Error> {
     match char_result {
         Some('y') | Some('Y') => Ok(true),
         Some('n') | Some('N') | Some('\n') | None => Ok(false),
-        Some('q') | Some('Q') => Err(Error::new(
-            io::ErrorKind::Interrupted,
-            "User requested to quit",
-        )),
-        _ => Err(Error::new(io::ErrorKind::InvalidInput, "Invalid input")),
+        Some('q') | Some('Q') => Err(Error::UserAborted),
+        _ => Err(Error::InvalidInput("Invalid input".to_string())),
     }
 }
 
@@ -133,6 +240,47 @@ pub fn rename_grave(grave: impl AsRef<Path>) -> PathBuf {
         .expect("Failed to rename duplicate file or directory")
 }
 
+/// The name for `grave` in another form, such as an archive: the grave with
+/// `suffix` added, or numbered like [`rename_grave`] if that is taken
+pub fn repacked_name(grave: &Path, suffix: &str) -> PathBuf {
+    (0_u64..)
+        .map(|i| {
+            let mut name = grave.as_os_str().to_owned();
+            if i > 0 {
+                name.push(format!("~{}", i));
+            }
+            name.push(suffix);
+            PathBuf::from(name)
+        })
+        .find(|name| !symlink_exists(name))
+        .expect("Failed to name the repacked grave")
+}
+
+/// How rip matches globs: `*` doesn't cross a separator, and does match a
+/// leading dot
+pub const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// Whether `pattern` has a separator, and so is matched against a path
+/// rather than a file name
+pub fn is_anchored(pattern: &Pattern) -> bool {
+    pattern.as_str().contains(['/', std::path::MAIN_SEPARATOR])
+}
+
+/// Match `pattern` against all of `path` if `anchored`, or else only against
+/// its file name
+pub fn glob_matches(pattern: &Pattern, path: &Path, anchored: bool) -> bool {
+    if anchored {
+        pattern.matches_path_with(path, MATCH_OPTIONS)
+    } else {
+        path.file_name()
+            .is_some_and(|name| pattern.matches_with(&name.to_string_lossy(), MATCH_OPTIONS))
+    }
+}
+
 const UNITS: [(&str, u64); 4] = [
     ("KiB", 1_u64 << 10),
     ("MiB", 1_u64 << 20),
@@ -140,6 +288,54 @@ const UNITS: [(&str, u64); 4] = [
     ("TiB", 1_u64 << 40),
 ];
 
+/// Parse a size like `1048576`, `512KiB`, `10G` or `1.5 GB`.
+/// Units without an `i` are still binary, except for `KB`, `MB`, `GB` and `TB`.
+pub fn parse_size(size: &str) -> Result<u64, Error> {
+    let invalid = || Error::InvalidInput(format!("Invalid size: {}", size));
+    let size = size.trim();
+    let split = size
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+    let number: f64 = number.parse().map_err(|_| invalid())?;
+    let multiplier: u64 = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KIB" => 1 << 10,
+        "M" | "MIB" => 1 << 20,
+        "G" | "GIB" => 1 << 30,
+        "T" | "TIB" => 1 << 40,
+        "KB" => 1_000,
+        "MB" => 1_000_000,
+        "GB" => 1_000_000_000,
+        "TB" => 1_000_000_000_000,
+        _ => return Err(invalid()),
+    };
+    Ok((number * multiplier as f64) as u64)
+}
+
+/// Parse a duration like `90s`, `15m`, `12h`, `30d` or `2w`
+pub fn parse_duration(duration: &str) -> Result<Duration, Error> {
+    let invalid = || Error::InvalidInput(format!("Invalid duration: {}", duration));
+    let duration = duration.trim();
+    let split = duration
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(invalid)?;
+    let (number, unit) = duration.split_at(split);
+    let number: u64 = number.parse().map_err(|_| invalid())?;
+    let seconds = match unit.trim() {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+    number
+        .checked_mul(seconds)
+        .map(Duration::from_secs)
+        .ok_or_else(invalid)
+}
+
 pub fn humanize_bytes(bytes: u64) -> String {
     for (unit, size) in UNITS.iter().rev() {
         if bytes >= *size {
@@ -2,17 +2,19 @@ use lazy_static::lazy_static;
 use predicates::str::is_match;
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
-use rip2::args::Args;
-use rip2::record;
-use rip2::util::TestMode;
+use rip2::args::{Args, BigFilePolicy};
+use rip2::prompt::{AlwaysYes, Answer, ScriptedPrompter};
 use rip2::{self, util};
+use rip2::{history, protect, record};
+use rip2::{BuryOptions, BuryOutcome, CompressPolicy, Event, Graveyard, ListFilter, PurgePolicy};
 use rstest::rstest;
 use std::fs;
 use std::hash::{DefaultHasher, Hash, Hasher};
-use std::io::{BufReader, ErrorKind, Read, Write};
+use std::io::{BufReader, Read, Write};
 use std::path::PathBuf;
 use std::sync::{Arc, Barrier, Mutex, MutexGuard};
-use std::{env, ffi, iter};
+use std::time::Duration;
+use std::{env, ffi, iter, process};
 use tempfile::{tempdir, TempDir};
 use walkdir::WalkDir;
 
@@ -75,7 +77,7 @@ impl TestData {
 }
 
 /// Test that a file is buried and unburied correctly
-/// Also checks that the graveyard is deleted when decompose is true
+/// Also checks that the graveyard is emptied when decompose is true
 #[rstest]
 fn test_bury_unbury(#[values(false, true)] decompose: bool, #[values(false, true)] inspect: bool) {
     let _env_lock = aquire_lock();
@@ -96,7 +98,7 @@ fn test_bury_unbury(#[values(false, true)] decompose: bool, #[values(false, true
             inspect,
             ..Args::default()
         },
-        TestMode,
+        AlwaysYes,
         &mut log,
     )
     .unwrap();
@@ -126,7 +128,7 @@ fn test_bury_unbury(#[values(false, true)] decompose: bool, #[values(false, true
             unbury: if decompose { None } else { Some(Vec::new()) },
             ..Args::default()
         },
-        TestMode,
+        AlwaysYes,
         &mut log,
     )
     .unwrap();
@@ -138,8 +140,12 @@ fn test_bury_unbury(#[values(false, true)] decompose: bool, #[values(false, true
     }
 
     if decompose {
-        // Verify that the graveyard is completely deleted
-        assert!(!test_env.graveyard.exists());
+        // Verify that only the history is left in the graveyard
+        let left: Vec<_> = fs::read_dir(&test_env.graveyard)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(left, [".history"]);
         // And that the file was not restored
         assert!(!test_data.path.exists());
     } else {
@@ -206,7 +212,7 @@ fn test_env(#[values("RIP_GRAVEYARD", "XDG_DATA_HOME")] env_var: &str) {
             // We don't set the graveyard here!
             ..Args::default()
         },
-        TestMode,
+        AlwaysYes,
         &mut log,
     )
     .unwrap();
@@ -254,7 +260,7 @@ fn test_duplicate_file(
             inspect,
             ..Args::default()
         },
-        TestMode,
+        AlwaysYes,
         &mut log,
     )
     .unwrap();
@@ -306,7 +312,7 @@ fn test_duplicate_file(
             graveyard: Some(test_env.graveyard.clone()),
             ..Args::default()
         },
-        TestMode,
+        AlwaysYes,
         &mut log,
     )
     .unwrap();
@@ -326,7 +332,7 @@ fn test_duplicate_file(
             seance: true,
             ..Args::default()
         },
-        TestMode,
+        AlwaysYes,
         &mut log,
     )
     .unwrap();
@@ -372,7 +378,7 @@ fn test_big_file() {
             graveyard: Some(test_env.graveyard.clone()),
             ..Args::default()
         },
-        TestMode,
+        AlwaysYes,
         &mut log,
     )
     .unwrap();
@@ -384,6 +390,239 @@ fn test_big_file() {
     assert!(!expected_graveyard_path.exists());
 }
 
+/// Test that the big file policy is applied to whole directories
+#[rstest]
+fn test_big_file_policy(
+    #[values(
+        BigFilePolicy::Prompt,
+        BigFilePolicy::AlwaysCopy,
+        BigFilePolicy::AlwaysDelete,
+        BigFilePolicy::Refuse
+    )]
+    policy: BigFilePolicy,
+) {
+    let _env_lock = aquire_lock();
+
+    let test_env = TestEnv::new();
+    let dir = test_env.src.join("dir");
+    fs::create_dir(&dir).unwrap();
+    TestData::new(&test_env, Some(&PathBuf::from("dir").join("a.txt")));
+    TestData::new(&test_env, Some(&PathBuf::from("dir").join("b.txt")));
+    let expected_graveyard_path =
+        util::join_absolute(&test_env.graveyard, dunce::canonicalize(&dir).unwrap());
+
+    // Force a copy so that the policy kicks in
+    env::set_var("__RIP_ALLOW_RENAME", "false");
+    let mut log = Vec::new();
+    let result = rip2::run(
+        Args {
+            targets: [dir.clone()].to_vec(),
+            graveyard: Some(test_env.graveyard.clone()),
+            // Each file holds 100 bytes
+            big_file_threshold: Some(150),
+            big_file_policy: Some(policy),
+            ..Args::default()
+        },
+        AlwaysYes,
+        &mut log,
+    );
+    env::remove_var("__RIP_ALLOW_RENAME");
+    let log_s = String::from_utf8(log).unwrap();
+
+    match policy {
+        BigFilePolicy::Prompt => {
+            result.unwrap();
+            assert!(log_s.contains("About to copy a big directory"));
+            assert!(log_s.contains("Permanently delete this directory instead?"));
+            assert!(!dir.exists());
+            assert!(!expected_graveyard_path.exists());
+        }
+        BigFilePolicy::AlwaysCopy => {
+            result.unwrap();
+            assert!(log_s.is_empty());
+            assert!(!dir.exists());
+            assert!(expected_graveyard_path.join("a.txt").exists());
+            assert!(expected_graveyard_path.join("b.txt").exists());
+        }
+        BigFilePolicy::AlwaysDelete => {
+            result.unwrap();
+            assert!(log_s.is_empty());
+            assert!(!dir.exists());
+            assert!(!expected_graveyard_path.exists());
+        }
+        BigFilePolicy::Refuse => {
+            let err = result.unwrap_err();
+            assert!(err.to_string().contains("Refusing to copy big directory"));
+            assert!(dir.join("a.txt").exists());
+            assert!(dir.join("b.txt").exists());
+            assert!(!expected_graveyard_path.exists());
+        }
+    }
+}
+
+/// Test that protected paths are refused unless forced
+#[rstest]
+fn test_protected_paths(
+    #[values("graveyard_parent", "home", "repository", "pattern")] scenario: &str,
+    #[values(false, true)] force_protected: bool,
+) {
+    // Forcing rip to bury the graveyard into itself can't work
+    if force_protected && scenario == "graveyard_parent" {
+        return;
+    }
+    let _env_lock = aquire_lock();
+
+    let test_env = TestEnv::new();
+    let target = match scenario {
+        "graveyard_parent" => test_env.graveyard.parent().unwrap().join("parent"),
+        "home" | "repository" | "pattern" => test_env.src.join("dir"),
+        _ => unreachable!(),
+    };
+    let graveyard = match scenario {
+        "graveyard_parent" => target.join("graveyard"),
+        _ => test_env.graveyard.clone(),
+    };
+    fs::create_dir_all(&target).unwrap();
+    fs::write(target.join("file.txt"), "data").unwrap();
+
+    let old_home = env::var_os("HOME");
+    let old_cwd = env::current_dir().unwrap();
+    match scenario {
+        "home" => env::set_var("HOME", &target),
+        "repository" => {
+            fs::create_dir(target.join(".git")).unwrap();
+            env::set_current_dir(&target).unwrap();
+        }
+        "pattern" => env::set_var(protect::PROTECTED_ENV, "*.txt:di?"),
+        _ => {}
+    }
+
+    let mut log = Vec::new();
+    let result = rip2::run(
+        Args {
+            targets: [target.clone()].to_vec(),
+            graveyard: Some(graveyard),
+            force_protected,
+            ..Args::default()
+        },
+        AlwaysYes,
+        &mut log,
+    );
+
+    env::remove_var(protect::PROTECTED_ENV);
+    if let Some(home) = old_home {
+        env::set_var("HOME", home);
+    }
+    env::set_current_dir(old_cwd).unwrap();
+
+    if force_protected {
+        result.unwrap();
+        assert!(!target.join("file.txt").exists());
+    } else {
+        let err = result.unwrap_err();
+        assert!(matches!(err, rip2::Error::Protected { .. }));
+        assert_eq!(err.exit_code(), 4);
+        assert!(err.to_string().contains("--force-protected"));
+        assert!(target.join("file.txt").exists());
+    }
+}
+
+/// Test that only the repository rip is run in is protected, and that rm
+/// mode can override that
+#[rstest]
+fn test_protected_repository() {
+    let test_env = TestEnv::new();
+    let clone = test_env.src.join("oldclone");
+    let make_clone = || {
+        fs::create_dir_all(clone.join(".git")).unwrap();
+        fs::write(clone.join("file.txt"), "data").unwrap();
+    };
+    let rm = |args: &[&str], cwd: &PathBuf| {
+        let mut cmd = cli_runner([&["--rm-compat"], args].concat(), Some(cwd));
+        cmd.env("RIP_GRAVEYARD", &test_env.graveyard);
+        cmd
+    };
+
+    make_clone();
+    rm(&["-rf", "oldclone"], &test_env.src).assert().success();
+    assert!(!clone.exists());
+
+    make_clone();
+    rm(&["-rf", "."], &clone)
+        .assert()
+        .code(4)
+        .stderr(predicates::str::contains("current repository"));
+    rm(&["-rf", "--force-protected", "."], &clone)
+        .assert()
+        .success();
+    assert!(!clone.exists());
+}
+
+/// Test the yes, no and quit answers to the inspect and graveyard prompts
+#[rstest]
+fn test_prompt_answers(
+    #[values(Answer::Yes, Answer::No, Answer::Quit)] answer: Answer,
+    #[values(false, true)] in_graveyard: bool,
+) {
+    let _env_lock = aquire_lock();
+
+    let test_env = TestEnv::new();
+    let test_data = TestData::new(&test_env, None);
+    let target = if in_graveyard {
+        // Bury it first, then try to remove the grave itself
+        let graveyard = Graveyard::open(&test_env.graveyard).unwrap();
+        graveyard
+            .bury(
+                &[&test_data.path],
+                &BuryOptions::default(),
+                &AlwaysYes,
+                &mut |_: &Event| {},
+            )
+            .unwrap();
+        graveyard.last_bury().unwrap()
+    } else {
+        test_data.path.clone()
+    };
+
+    let prompter = ScriptedPrompter::new([answer]);
+    let mut log = Vec::new();
+    let result = rip2::run(
+        Args {
+            targets: vec![target.clone()],
+            graveyard: Some(test_env.graveyard.clone()),
+            inspect: !in_graveyard,
+            ..Args::default()
+        },
+        &prompter,
+        &mut log,
+    );
+    let log_s = String::from_utf8(log).unwrap();
+
+    let question = if in_graveyard {
+        "Permanently unlink it?".to_string()
+    } else {
+        format!("Send {} to the graveyard?", target.display())
+    };
+    assert_eq!(prompter.questions(), vec![question]);
+    match answer {
+        Answer::Yes => {
+            result.unwrap();
+            assert!(!target.exists());
+        }
+        Answer::No => {
+            result.unwrap();
+            assert!(target.exists());
+            if in_graveyard {
+                assert!(log_s.contains("Skipping"));
+            }
+        }
+        Answer::Quit => {
+            assert!(matches!(result, Err(rip2::Error::UserAborted)));
+            assert!(target.exists());
+        }
+    }
+}
+
 /// Test that running rip on the same file twice
 /// throws an error
 #[rstest]
@@ -400,18 +639,27 @@ fn test_same_file_twice() {
             graveyard: Some(test_env.graveyard.clone()),
             ..Args::default()
         },
-        TestMode,
+        AlwaysYes,
         &mut log,
     );
 
     // Check the first use triggered the removal:
     assert!(!test_data.path.exists());
 
-    // Check the type of error
+    // Check the type of error: the second one is reported as failed
     let err = result.unwrap_err();
-    assert_eq!(err.kind(), ErrorKind::NotFound);
+    match &err {
+        rip2::Error::TargetsFailed { total, failures } => {
+            assert_eq!(*total, 2);
+            assert_eq!(failures.len(), 1);
+            assert!(matches!(failures[0].1, rip2::Error::NotFound { .. }));
+        }
+        other => panic!("Expected a failure report, got {:?}", other),
+    }
+    assert_eq!(err.exit_code(), 3);
 
     let err_msg = err.to_string();
+    assert!(err_msg.starts_with("Failed to remove 1 of 2 targets:"));
     assert!(err_msg.contains("Cannot remove"));
     assert!(err_msg.contains("no such file or directory"));
 }
@@ -564,6 +812,34 @@ fn test_cli(
     }
 }
 
+/// Test that each kind of error gets its own exit code
+#[rstest]
+fn test_exit_codes(#[values("missing", "invalid", "graveyard", "quit")] scenario: &str) {
+    let _env_lock = aquire_lock();
+    let test_env = TestEnv::new();
+    TestData::new(&test_env, None);
+    fs::create_dir_all(&test_env.graveyard).unwrap();
+
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let (args, expected_code) = match scenario {
+        "missing" => (vec!["--graveyard", graveyard, "no_such_file.txt"], 3),
+        "invalid" => (vec!["--graveyard", graveyard, "-d", "-s"], 2),
+        "graveyard" => (vec!["--graveyard", graveyard, graveyard], 4),
+        "quit" => (vec!["--graveyard", graveyard, "-i", "test_file.txt"], 130),
+        _ => unreachable!(),
+    };
+
+    cli_runner(args, Some(&test_env.src))
+        .write_stdin("q\n")
+        .assert()
+        .code(expected_code)
+        .stderr(is_match("^rip: ").unwrap());
+
+    // Nothing should have been touched
+    assert!(test_env.src.join("test_file.txt").exists());
+    assert!(test_env.graveyard.exists());
+}
+
 #[rstest]
 fn issue_0018() {
     let _env_lock = aquire_lock();
@@ -638,7 +914,8 @@ fn issue_0018() {
         assert!(!record_contents.contains("gnu_meta.zip"));
 
         // And give this for the last bury
-        let record = record::Record::<{ record::DEFAULT_FILE_LOCK }>::new(&test_env.graveyard);
+        let record =
+            record::Record::<{ record::DEFAULT_FILE_LOCK }>::new(&test_env.graveyard).unwrap();
         let last_bury = record.get_last_bury().unwrap();
         assert!(last_bury.ends_with("uu_meta.zip"));
     }
@@ -708,7 +985,7 @@ fn read_empty_record() {
     let test_env = TestEnv::new();
     let cwd = env::current_dir().unwrap();
     fs::create_dir(&test_env.graveyard).unwrap();
-    let record = record::Record::<{ record::DEFAULT_FILE_LOCK }>::new(&test_env.graveyard);
+    let record = record::Record::<{ record::DEFAULT_FILE_LOCK }>::new(&test_env.graveyard).unwrap();
     let gravepath = &util::join_absolute(&test_env.graveyard, dunce::canonicalize(cwd).unwrap());
     let result = record.seance(gravepath);
     assert!(result.is_ok());
@@ -847,7 +1124,7 @@ fn many_nest() {
             inspect: true,
             ..Args::default()
         },
-        TestMode,
+        AlwaysYes,
         &mut log,
     );
     assert!(result.is_ok());
@@ -867,7 +1144,7 @@ fn many_nest() {
             unbury: Some(Vec::new()),
             ..Args::default()
         },
-        TestMode,
+        AlwaysYes,
         &mut log,
     );
     assert!(result.is_ok());
@@ -898,7 +1175,7 @@ fn test_bury_unbury_bury_unbury() {
             graveyard: Some(test_env.graveyard.clone()),
             ..Args::default()
         },
-        TestMode,
+        AlwaysYes,
         &mut log,
     )
     .unwrap();
@@ -923,7 +1200,7 @@ fn test_bury_unbury_bury_unbury() {
             unbury: Some(Vec::new()),
             ..Args::default()
         },
-        TestMode,
+        AlwaysYes,
         &mut log,
     )
     .unwrap();
@@ -951,7 +1228,7 @@ fn test_bury_unbury_bury_unbury() {
             graveyard: Some(test_env.graveyard.clone()),
             ..Args::default()
         },
-        TestMode,
+        AlwaysYes,
         &mut log,
     )
     .unwrap();
@@ -978,7 +1255,7 @@ fn test_bury_unbury_bury_unbury() {
             unbury: Some(Vec::new()),
             ..Args::default()
         },
-        TestMode,
+        AlwaysYes,
         &mut log,
     )
     .unwrap();
@@ -989,6 +1266,118 @@ fn test_bury_unbury_bury_unbury() {
     assert_eq!(restored_data, test_data.data);
 }
 
+/// Test the library API without going through `Args`
+#[rstest]
+fn test_graveyard_api() {
+    let _env_lock = aquire_lock();
+
+    let test_env = TestEnv::new();
+    let first = TestData::new(&test_env, Some(&PathBuf::from("first.txt")));
+    let second = TestData::new(&test_env, Some(&PathBuf::from("second.txt")));
+    let graveyard = Graveyard::open(&test_env.graveyard).unwrap();
+    assert_eq!(graveyard.path(), test_env.graveyard);
+
+    let mut events = Vec::new();
+    let mut observer = |event: &Event| events.push(event.clone());
+    let outcomes = graveyard
+        .bury(
+            &[&first.path, &second.path],
+            &BuryOptions::default(),
+            &AlwaysYes,
+            &mut observer,
+        )
+        .unwrap();
+    let graves: Vec<PathBuf> = outcomes
+        .iter()
+        .map(|outcome| match outcome {
+            BuryOutcome::Buried { grave, .. } => grave.clone(),
+            other => panic!("Expected a burial, got {:?}", other),
+        })
+        .collect();
+    assert!(!first.path.exists() && !second.path.exists());
+    assert!(graves.iter().all(|grave| grave.exists()));
+
+    // Both are listed under the source directory
+    let listed = graveyard.list(&ListFilter::under(&test_env.src)).unwrap();
+    assert_eq!(
+        listed.iter().map(|item| &item.dest).collect::<Vec<_>>(),
+        graves.iter().collect::<Vec<_>>()
+    );
+    assert_eq!(graveyard.last_bury().unwrap(), graves[1]);
+
+    let unburied = graveyard
+        .unbury(&graves[1..], &AlwaysYes, &mut observer)
+        .unwrap();
+    assert_eq!(unburied.len(), 1);
+    assert_eq!(unburied[0].dest, dunce::canonicalize(&second.path).unwrap());
+    assert_eq!(fs::read_to_string(&second.path).unwrap(), second.data);
+    assert_eq!(graveyard.last_bury().unwrap(), graves[0]);
+
+    // Nothing is old enough to be purged yet
+    let purged = graveyard
+        .purge(
+            PurgePolicy::OlderThan(Duration::from_secs(3600)),
+            &mut observer,
+        )
+        .unwrap();
+    assert!(purged.is_empty());
+    assert!(graves[0].exists());
+
+    // Nor is anything older than time can go back
+    for age in [Duration::from_secs(1_000_000_000 * 604_800), Duration::MAX] {
+        let purged = graveyard
+            .purge(PurgePolicy::OlderThan(age), &mut observer)
+            .unwrap();
+        assert!(purged.is_empty());
+    }
+    assert!(graves[0].exists());
+
+    let purged = graveyard
+        .purge(PurgePolicy::OlderThan(Duration::ZERO), &mut observer)
+        .unwrap();
+    assert_eq!(purged, graves[..1]);
+    assert!(!graves[0].exists());
+    assert!(graveyard.list(&ListFilter::default()).unwrap().is_empty());
+
+    graveyard.purge(PurgePolicy::All, &mut observer).unwrap();
+    assert!(!test_env.graveyard.join(record::RECORD).exists());
+    assert!(test_env.graveyard.join(history::HISTORY).exists());
+
+    // Every step was reported. Copies depend on whether a rename was possible.
+    events.retain(|event| {
+        !matches!(
+            event,
+            Event::CopyStarted { .. } | Event::Copying { .. } | Event::Copied { .. }
+        )
+    });
+    let sources = [&first.path, &second.path].map(|path| {
+        dunce::canonicalize(path.parent().unwrap())
+            .unwrap()
+            .join(path.file_name().unwrap())
+    });
+    assert_eq!(
+        events,
+        vec![
+            Event::Buried {
+                source: sources[0].clone(),
+                grave: graves[0].clone()
+            },
+            Event::Buried {
+                source: sources[1].clone(),
+                grave: graves[1].clone()
+            },
+            Event::Unburied {
+                grave: graves[1].clone(),
+                dest: sources[1].clone()
+            },
+            Event::Purged {
+                path: graves[0].clone(),
+                size: first.data.len() as u64
+            },
+        ]
+    );
+}
+
 /// Test concurrent writes to the pre-existing record file
 #[cfg(not(target_os = "windows"))]
 #[rstest]
@@ -1013,7 +1402,7 @@ fn _test_concurrent_writes<const FILE_LOCK: bool>() {
     let _env_lock = aquire_lock();
     let test_env = TestEnv::new();
     fs::create_dir(&test_env.graveyard).unwrap();
-    let record = record::Record::<FILE_LOCK>::new(&test_env.graveyard);
+    let record = record::Record::<FILE_LOCK>::new(&test_env.graveyard).unwrap();
     let record_path = test_env.graveyard.join(record::RECORD);
 
     // Create two threads that will write to the record simultaneously
@@ -1077,3 +1466,1442 @@ fn _test_concurrent_writes<const FILE_LOCK: bool>() {
         assert!(corrupted_lines > 0);
     }
 }
+
+/// Settings come from the config file unless a flag overrides them
+#[rstest]
+fn test_config_file(#[values(false, true)] with_flags: bool) {
+    let _env_lock = aquire_lock();
+    let default_env_vars = cache_and_remove_env_vars();
+
+    let test_env = TestEnv::new();
+    let test_data = TestData::new(&test_env, None);
+    let config_dir = tempdir().unwrap();
+    let config_path = config_dir.path().join("config.toml");
+    let config_graveyard = config_dir.path().join("config_graveyard");
+    fs::write(
+        &config_path,
+        format!(
+            "graveyard = {:?}\nformat = \"json\"\nbig-file-threshold = \"1KiB\"\nretention = \"30d\"\nquota = 1000000\n",
+            config_graveyard.display().to_string()
+        ),
+    )
+    .unwrap();
+    env::set_var(rip2::config::CONFIG_ENV, &config_path);
+
+    let mut log = Vec::new();
+    rip2::run(
+        Args {
+            targets: vec![test_data.path.clone()],
+            graveyard: with_flags.then(|| test_env.graveyard.clone()),
+            format: with_flags.then_some(rip2::args::OutputFormat::Text),
+            ..Args::default()
+        },
+        AlwaysYes,
+        &mut log,
+    )
+    .unwrap();
+    env::remove_var(rip2::config::CONFIG_ENV);
+    restore_env_vars(default_env_vars);
+
+    let used_graveyard = if with_flags {
+        &test_env.graveyard
+    } else {
+        &config_graveyard
+    };
+    let expected_grave =
+        util::join_absolute(used_graveyard, dunce::canonicalize(&test_env.src).unwrap())
+            .join("test_file.txt");
+    assert!(!test_data.path.exists());
+    assert_eq!(fs::read_to_string(expected_grave).unwrap(), test_data.data);
+
+    let log_s = String::from_utf8(log).unwrap();
+    if with_flags {
+        assert!(log_s.is_empty());
+    } else {
+        // The JSON renderer reports every event
+        assert!(log_s.contains(r#""event":"buried""#));
+    }
+}
+
+#[rstest]
+fn test_config_show() {
+    let config_dir = tempdir().unwrap();
+    let config_path = config_dir.path().join("config.toml");
+    fs::write(
+        &config_path,
+        "big-file-policy = \"refuse\"\nretention = \"2w\"\nprotected = [\"*.keep\"]\ncompress-after = \"48h\"\n",
+    )
+    .unwrap();
+
+    let output = quick_cmd_output(
+        cli_runner(["--inspect", "--jobs", "4", "config", "show"], None)
+            .env(rip2::config::CONFIG_ENV, &config_path)
+            .env("RIP_GRAVEYARD", "/tmp/show_graveyard")
+            .env(rip2::config::LOCK_TIMEOUT_ENV, "1m")
+            .env_remove(protect::PROTECTED_ENV),
+    );
+    let source = format!("# config file {}", config_path.display());
+    let expected_lines = [
+        format!("# Config file: {}", config_path.display()),
+        "graveyard = \"/tmp/show_graveyard\"  # environment variable $RIP_GRAVEYARD".to_string(),
+        format!(
+            "big-file-threshold = {}  # default",
+            rip2::BIG_FILE_THRESHOLD
+        ),
+        format!("big-file-policy = \"refuse\"  {}", source),
+        format!("retention = \"1209600s\"  {}", source),
+        "quota = none  # default".to_string(),
+        "format = \"text\"  # default".to_string(),
+        format!("protected = [\"*.keep\"]  {}", source),
+        "inspect = true  # flag --inspect".to_string(),
+        "lock-timeout = \"60s\"  # environment variable $RIP_LOCK_TIMEOUT".to_string(),
+        "copy-jobs = 4  # flag --jobs".to_string(),
+        format!("compress-after = \"172800s\"  {}", source),
+        "compress-above = none  # default".to_string(),
+        "dedup = false  # default".to_string(),
+        "encrypt = false  # default".to_string(),
+        "key-file = none  # default".to_string(),
+        "trusted-projects = []  # default".to_string(),
+    ];
+    assert_eq!(output.lines().collect::<Vec<_>>(), expected_lines);
+}
+
+/// A broken config file is reported instead of being ignored
+#[rstest]
+fn test_config_invalid(
+    #[values("graveyard = [", "unknown-key = 1", "retention = \"soon\"")] contents: &str,
+) {
+    let config_dir = tempdir().unwrap();
+    let config_path = config_dir.path().join("config.toml");
+    fs::write(&config_path, contents).unwrap();
+
+    cli_runner(["config", "show"], None)
+        .env(rip2::config::CONFIG_ENV, &config_path)
+        .assert()
+        .failure()
+        .code(2)
+        .stderr(predicates::str::starts_with("rip: "));
+}
+
+/// A `.riprc` picks the graveyard and what to delete or refuse
+#[rstest]
+fn test_project_rules(#[values(false, true)] force_protected: bool) {
+    let _env_lock = aquire_lock();
+    let default_env_vars = cache_and_remove_env_vars();
+
+    let project_dir = tempdir().unwrap();
+    let root = dunce::canonicalize(project_dir.path()).unwrap();
+    fs::write(
+        root.join(rip2::project::PROJECT_FILE),
+        "graveyard = \".graveyard\"\nhard-delete = [\"node_modules\"]\nrefuse = [\"/target/\"]\n",
+    )
+    .unwrap();
+    for dir in ["node_modules/pkg", "src", "target/debug", "sub/target"] {
+        fs::create_dir_all(root.join(dir)).unwrap();
+    }
+    fs::write(root.join("node_modules/pkg/index.js"), "index").unwrap();
+    fs::write(root.join("src/main.rs"), "main").unwrap();
+    fs::write(root.join("target/debug/rip"), "rip").unwrap();
+    let config_dir = tempdir().unwrap();
+    let config_path = config_dir.path().join("config.toml");
+    fs::write(
+        &config_path,
+        format!("trusted-projects = [{:?}]\n", root.display().to_string()),
+    )
+    .unwrap();
+    env::set_var(rip2::config::CONFIG_ENV, &config_path);
+
+    let mut log = Vec::new();
+    let result = rip2::run(
+        Args {
+            targets: vec![
+                root.join("node_modules/pkg"),
+                root.join("src/main.rs"),
+                // Only the top-level target directory is refused
+                root.join("sub/target"),
+                root.join("target/debug"),
+            ],
+            force_protected,
+            ..Args::default()
+        },
+        AlwaysYes,
+        &mut log,
+    );
+    env::remove_var(rip2::config::CONFIG_ENV);
+    restore_env_vars(default_env_vars);
+
+    let graveyard = root.join(".graveyard");
+    // Hard-deleted targets skip the graveyard
+    assert!(!root.join("node_modules/pkg").exists());
+    assert!(!util::join_absolute(&graveyard, root.join("node_modules/pkg")).exists());
+    assert_eq!(
+        fs::read_to_string(util::join_absolute(&graveyard, root.join("src/main.rs"))).unwrap(),
+        "main"
+    );
+    assert!(util::join_absolute(&graveyard, root.join("sub/target")).exists());
+
+    if force_protected {
+        result.unwrap();
+        assert!(!root.join("target/debug").exists());
+    } else {
+        let err = result.unwrap_err();
+        assert_eq!(err.exit_code(), 4);
+        assert!(err.to_string().contains("refused pattern /target/"));
+        assert!(root.join("target/debug/rip").exists());
+    }
+
+    let output = quick_cmd_output(
+        cli_runner(["config", "show"], Some(&root.join("src")))
+            .env_remove("RIP_GRAVEYARD")
+            .env(rip2::config::CONFIG_ENV, &config_path),
+    );
+    let project_file = root.join(rip2::project::PROJECT_FILE);
+    assert!(output.contains(&format!("# Project file: {}\n", project_file.display())));
+    assert!(output.contains(&format!(
+        "graveyard = {:?}  # config file {}",
+        graveyard.display().to_string(),
+        project_file.display()
+    )));
+    assert!(output.contains("hard-delete = [\"node_modules\"]"));
+}
+
+/// A `.riprc` that isn't trusted can only refuse paths, and a hard delete
+/// still asks with --inspect
+#[rstest]
+fn test_project_trust() {
+    let test_env = TestEnv::new();
+    let root = dunce::canonicalize(&test_env.src).unwrap();
+    fs::write(
+        root.join(rip2::project::PROJECT_FILE),
+        "graveyard = \".graveyard\"\nhard-delete = [\"node_modules\"]\n",
+    )
+    .unwrap();
+    let config_dir = tempdir().unwrap();
+    let config_path = config_dir.path().join("config.toml");
+    let rip = |args: &[&str]| {
+        let mut cmd = cli_runner(args, Some(&root));
+        cmd.env_remove("RIP_GRAVEYARD")
+            .env(rip2::config::CONFIG_ENV, &config_path);
+        cmd
+    };
+    let node_modules = root.join("node_modules");
+    fs::create_dir_all(&node_modules).unwrap();
+
+    let output = quick_cmd_output(&mut rip(&["config", "show"]));
+    assert!(output.contains("(not trusted, so only refuse applies)"));
+    assert!(!output.contains(".graveyard"));
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    rip(&["--graveyard", graveyard, "node_modules"])
+        .assert()
+        .success();
+    assert!(util::join_absolute(&test_env.graveyard, &node_modules).exists());
+
+    fs::write(
+        &config_path,
+        format!("trusted-projects = [{:?}]\n", root.display().to_string()),
+    )
+    .unwrap();
+    fs::create_dir_all(&node_modules).unwrap();
+    rip(&["-i", "node_modules"])
+        .write_stdin("n\n")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains(
+            "Permanently delete node_modules?",
+        ));
+    assert!(node_modules.exists());
+    rip(&["-i", "node_modules"])
+        .write_stdin("y\n")
+        .assert()
+        .success();
+    assert!(!node_modules.exists());
+    assert!(!root.join(".graveyard").join("node_modules").exists());
+}
+
+#[rstest]
+fn test_record_compact() {
+    let test_env = TestEnv::new();
+    let first = TestData::new(&test_env, Some(&PathBuf::from("first.txt")));
+    let second = TestData::new(&test_env, Some(&PathBuf::from("second.txt")));
+    let graveyard = test_env.graveyard.to_str().unwrap();
+
+    cli_runner(
+        ["--graveyard", graveyard, "first.txt", "second.txt"],
+        Some(&test_env.src),
+    )
+    .assert()
+    .success();
+    cli_runner(["--graveyard", graveyard, "-u"], Some(&test_env.src))
+        .assert()
+        .success();
+    assert!(second.path.exists());
+
+    // The unbury left a tombstone behind
+    let record_path = test_env.graveyard.join(record::RECORD);
+    assert_eq!(fs::read_to_string(&record_path).unwrap().lines().count(), 4);
+
+    cli_runner(["--graveyard", graveyard, "record", "compact"], None)
+        .assert()
+        .success()
+        .stdout("Compacted the record from 3 to 1 lines\n");
+    let record_contents = fs::read_to_string(&record_path).unwrap();
+    assert_eq!(record_contents.lines().count(), 2);
+    assert!(record_contents.contains(
+        &first
+            .path
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .into_owned()
+    ));
+    assert!(!record_contents.contains("second.txt"));
+}
+
+/// Test that the history keeps every operation, even after a decompose
+#[rstest]
+fn test_log() {
+    let _env_lock = aquire_lock();
+
+    let test_env = TestEnv::new();
+    let first = TestData::new(&test_env, Some(&PathBuf::from("first.txt")));
+    let second = TestData::new(&test_env, Some(&PathBuf::from("second.txt")));
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let sources = [&first.path, &second.path]
+        .map(|path| dunce::canonicalize(path).unwrap().display().to_string());
+
+    cli_runner(
+        ["--graveyard", graveyard, "first.txt", "second.txt"],
+        Some(&test_env.src),
+    )
+    .assert()
+    .success();
+    cli_runner(["--graveyard", graveyard, "-u"], Some(&test_env.src))
+        .assert()
+        .success();
+    rip2::run(
+        Args {
+            graveyard: Some(test_env.graveyard.clone()),
+            decompose: true,
+            ..Args::default()
+        },
+        AlwaysYes,
+        &mut Vec::new(),
+    )
+    .unwrap();
+
+    let output = cli_runner(["--graveyard", graveyard, "--format", "json", "log"], None)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let entries: Vec<serde_json::Value> = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let operations: Vec<&str> = entries
+        .iter()
+        .map(|entry| entry["operation"].as_str().unwrap())
+        .collect();
+    assert_eq!(operations, ["bury", "bury", "unbury", "purge", "decompose"]);
+    let origs: Vec<Option<&str>> = entries.iter().map(|entry| entry["orig"].as_str()).collect();
+    assert_eq!(
+        origs,
+        [
+            Some(sources[0].as_str()),
+            Some(sources[1].as_str()),
+            Some(sources[1].as_str()),
+            Some(sources[0].as_str()),
+            None
+        ]
+    );
+    assert!(entries
+        .iter()
+        .all(|entry| entry["user"] == util::get_user() && entry["host"] == util::get_host()));
+    assert_eq!(entries[4]["grave"], graveyard);
+
+    cli_runner(["--graveyard", graveyard, "log"], None)
+        .assert()
+        .success()
+        .stdout(
+            is_match(format!(
+                "^time +\toperation\tuser\tpath\n.*\tbury\t.*@.*\t{} -> ",
+                regex::escape(&sources[0])
+            ))
+            .unwrap(),
+        );
+}
+
+/// Test that the history keeps paths that aren't valid UTF-8
+#[cfg(unix)]
+#[rstest]
+fn test_log_non_utf8() {
+    use std::os::unix::ffi::OsStrExt;
+
+    let test_env = TestEnv::new();
+    let path = dunce::canonicalize(&test_env.src)
+        .unwrap()
+        .join(ffi::OsStr::from_bytes(b"caf\xe9.txt"));
+    fs::write(&path, "latin-1").unwrap();
+    let graveyard = Graveyard::open(&test_env.graveyard).unwrap();
+    let mut events = Vec::new();
+
+    let outcomes = graveyard
+        .bury(
+            &[&path],
+            &BuryOptions::default(),
+            &AlwaysYes,
+            &mut |event: &Event| events.push(event.clone()),
+        )
+        .unwrap();
+
+    let [BuryOutcome::Buried { grave, .. }] = &outcomes[..] else {
+        panic!("Expected a single grave, got {:?}", outcomes);
+    };
+    assert!(grave.exists());
+    assert!(!events
+        .iter()
+        .any(|event| matches!(event, Event::Warning { .. })));
+    let entries = graveyard.history().read().unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].orig.as_ref(), Some(&path));
+    assert_eq!(entries[0].grave.as_ref(), Some(grave));
+}
+
+/// Test that failing to write the history doesn't fail a bury or unbury
+#[rstest]
+fn test_log_write_failure() {
+    let test_env = TestEnv::new();
+    let test_data = TestData::new(&test_env, None);
+    fs::create_dir_all(test_env.graveyard.join(history::HISTORY)).unwrap();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let target = test_data.path.to_str().unwrap();
+
+    cli_runner(["--graveyard", graveyard, target], Some(&test_env.src))
+        .assert()
+        .success()
+        .stdout(predicates::str::contains(
+            "Warning: Failed to write history",
+        ));
+    assert!(!test_data.path.exists());
+    cli_runner(["--graveyard", graveyard, "-u"], Some(&test_env.src))
+        .assert()
+        .success()
+        .stdout(predicates::str::contains(
+            "Warning: Failed to write history",
+        ));
+    assert!(test_data.path.exists());
+}
+
+/// Test that fsck finds and repairs drift between the record and the graveyard
+#[rstest]
+fn test_fsck() {
+    let test_env = TestEnv::new();
+    let kept = TestData::new(&test_env, Some(&PathBuf::from("kept.txt")));
+    let lost = TestData::new(&test_env, Some(&PathBuf::from("lost.txt")));
+    fs::create_dir(test_env.src.join("sub")).unwrap();
+    TestData::new(&test_env, Some(&PathBuf::from("sub/inner.txt")));
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let src = dunce::canonicalize(&test_env.src).unwrap();
+
+    cli_runner(
+        [
+            "--graveyard",
+            graveyard,
+            "kept.txt",
+            "lost.txt",
+            "sub/inner.txt",
+        ],
+        Some(&test_env.src),
+    )
+    .assert()
+    .success();
+    // Unburying leaves an empty directory behind, which is fine
+    cli_runner(["--graveyard", graveyard, "-u"], Some(&test_env.src))
+        .assert()
+        .success();
+    cli_runner(["--graveyard", graveyard, "fsck"], None)
+        .assert()
+        .success()
+        .stdout("No problems found\n");
+
+    // A grave that vanished, one the record doesn't know about,
+    // a line that was written twice, and some garbage
+    let kept_grave = util::join_absolute(&test_env.graveyard, src.join("kept.txt"));
+    let lost_grave = util::join_absolute(&test_env.graveyard, src.join("lost.txt"));
+    let orphan_grave = util::join_absolute(&test_env.graveyard, src.join("orphan.txt"));
+    fs::remove_file(&lost_grave).unwrap();
+    fs::write(&orphan_grave, "orphan").unwrap();
+    let record_path = test_env.graveyard.join(record::RECORD);
+    let duplicate = fs::read_to_string(&record_path)
+        .unwrap()
+        .lines()
+        .nth(1)
+        .unwrap()
+        .to_string();
+    let mut record_file = fs::OpenOptions::new()
+        .append(true)
+        .open(&record_path)
+        .unwrap();
+    writeln!(record_file, "{}\ngarbage", duplicate).unwrap();
+    drop(record_file);
+
+    let expected = format!(
+        "orphan\t{} (from {})\n\
+         dangling\t{} (from {})\n\
+         duplicate\t{}\n\
+         malformed\t\"garbage\"\n",
+        orphan_grave.display(),
+        src.join("orphan.txt").display(),
+        lost_grave.display(),
+        src.join("lost.txt").display(),
+        kept_grave.display(),
+    );
+    cli_runner(["--graveyard", graveyard, "fsck"], None)
+        .assert()
+        .failure()
+        .stdout(format!(
+            "{}Found 4 problems (use --repair to adopt orphans and prune dangling entries)\n",
+            expected
+        ));
+    cli_runner(["--graveyard", graveyard, "fsck", "--repair"], None)
+        .assert()
+        .success()
+        .stdout(format!(
+            "{}Adopted 1 orphans and pruned 1 dangling entries\n",
+            expected
+        ));
+    cli_runner(["--graveyard", graveyard, "fsck"], None)
+        .assert()
+        .failure()
+        .stdout(format!(
+            "duplicate\t{}\nmalformed\t\"garbage\"\n\
+             Found 2 problems (use --repair to adopt orphans and prune dangling entries)\n",
+            kept_grave.display()
+        ));
+
+    // The adopted orphan can be unburied like any other grave
+    cli_runner(["--graveyard", graveyard, "-u"], Some(&test_env.src))
+        .assert()
+        .success();
+    assert_eq!(
+        fs::read_to_string(test_env.src.join("orphan.txt")).unwrap(),
+        "orphan"
+    );
+    assert!(kept_grave.exists() && !kept.path.exists() && !lost.path.exists());
+}
+
+/// Test that fsck leaves rip's scratch files alone, and adopts packed
+/// graves under the name they were buried from
+#[rstest]
+fn test_fsck_packed() {
+    let test_env = TestEnv::new();
+    let config_dir = tempdir().unwrap();
+    let config_path = config_dir.path().join("config.toml");
+    fs::write(test_env.src.join("a.txt"), "a").unwrap();
+    fs::write(test_env.src.join("b.txt"), "b").unwrap();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let src = dunce::canonicalize(&test_env.src).unwrap();
+    let rip = |args: &[&str]| {
+        let mut cmd = cli_runner(
+            [&["--graveyard", graveyard], args].concat(),
+            Some(&test_env.src),
+        );
+        cmd.env(rip2::config::CONFIG_ENV, &config_path);
+        cmd
+    };
+
+    rip(&["a.txt"]).assert().success();
+    fs::write(&config_path, "compress-above = 0\n").unwrap();
+    rip(&["b.txt"]).assert().success();
+    let grave_dir = util::join_absolute(&test_env.graveyard, &src);
+    let archive = grave_dir.join(format!("a.txt{}", rip2::compress::SUFFIX));
+    assert!(archive.is_file());
+
+    // An archive the record doesn't know about, which had to be numbered,
+    // and what an interrupted unbury and dedup leave behind
+    let orphan = grave_dir.join(format!("c.txt~2{}", rip2::compress::SUFFIX));
+    fs::copy(&archive, &orphan).unwrap();
+    let scratch = grave_dir.join(format!("a.txt{}.unpack", rip2::compress::SUFFIX));
+    fs::create_dir(&scratch).unwrap();
+    fs::write(scratch.join("a.txt"), "a").unwrap();
+    fs::write(
+        grave_dir.join(format!("d.txt{}.tmp", rip2::dedup::SUFFIX)),
+        "",
+    )
+    .unwrap();
+
+    rip(&["fsck", "--repair"])
+        .assert()
+        .success()
+        .stdout(format!(
+            "orphan\t{} (from {})\nAdopted 1 orphans and pruned 0 dangling entries\n",
+            orphan.display(),
+            src.join("c.txt").display()
+        ));
+    rip(&["-u"]).assert().success();
+    assert_eq!(fs::read_to_string(test_env.src.join("c.txt")).unwrap(), "a");
+}
+
+/// A rip that can't get at the record gives up and says who is holding it
+#[cfg(not(target_os = "windows"))]
+#[rstest]
+fn test_graveyard_busy() {
+    let test_env = TestEnv::new();
+    let graveyard = Graveyard::open(&test_env.graveyard).unwrap();
+    let _writer = graveyard.record().open().unwrap();
+
+    cli_runner(
+        ["--graveyard", test_env.graveyard.to_str().unwrap(), "-s"],
+        Some(&test_env.src),
+    )
+    .env(rip2::config::LOCK_TIMEOUT_ENV, "0s")
+    .assert()
+    .code(7)
+    .stderr(format!(
+        "rip: Graveyard busy (held by PID {}): gave up waiting for a lock on {}\n",
+        std::process::id(),
+        test_env.graveyard.join(record::RECORD).display()
+    ));
+}
+
+/// Test that a failing target doesn't stop the others, unless --atomic
+/// is passed, in which case the ones already buried are put back
+#[rstest]
+fn test_partial_failure(#[values(false, true)] atomic: bool) {
+    let test_env = TestEnv::new();
+    let first = TestData::new(&test_env, Some(&PathBuf::from("first.txt")));
+    let second = TestData::new(&test_env, Some(&PathBuf::from("second.txt")));
+    let graveyard = test_env.graveyard.to_str().unwrap();
+
+    let mut args = vec!["--graveyard", graveyard];
+    if atomic {
+        args.push("--atomic");
+    }
+    args.extend(["first.txt", "missing.txt", "second.txt"]);
+    let assert = cli_runner(args, Some(&test_env.src)).assert().code(3);
+
+    let missing = "Cannot remove missing.txt: no such file or directory";
+    if atomic {
+        assert
+            .stdout(predicates::str::contains("Returned"))
+            .stderr(format!("rip: {}\n", missing));
+        assert!(first.path.exists() && second.path.exists());
+        assert!(Graveyard::open(&test_env.graveyard)
+            .unwrap()
+            .list(&ListFilter::default())
+            .unwrap()
+            .is_empty());
+    } else {
+        assert.stderr(format!(
+            "rip: Failed to remove 1 of 3 targets:\n  {}\n",
+            missing
+        ));
+        assert!(!first.path.exists() && !second.path.exists());
+        assert_eq!(
+            Graveyard::open(&test_env.graveyard)
+                .unwrap()
+                .list(&ListFilter::default())
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+}
+
+/// Test rm's command line, through `--rm-compat` and a link named `rm`
+#[rstest]
+fn test_rm_compat() {
+    let test_env = TestEnv::new();
+    let dir = test_env.src.join("dir");
+    fs::create_dir(&dir).unwrap();
+    fs::write(dir.join("file.txt"), "data").unwrap();
+    let rm = |args: &[&str]| {
+        let mut cmd = cli_runner([&["--rm-compat"], args].concat(), Some(&test_env.src));
+        cmd.env("RIP_GRAVEYARD", &test_env.graveyard);
+        cmd
+    };
+
+    // -f ignores missing targets, even when that leaves nothing to do
+    rm(&["-rfv", "dir", "missing.txt"])
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("Buried"));
+    assert!(!dir.exists());
+    assert!(util::join_absolute(
+        &test_env.graveyard,
+        dunce::canonicalize(&test_env.src).unwrap()
+    )
+    .join("dir/file.txt")
+    .exists());
+    rm(&["-f", "missing.txt"]).assert().success().stdout("");
+    rm(&["missing.txt"]).assert().code(3);
+
+    // -I asks once before removing more than three targets
+    let names = ["a", "b", "c", "d"];
+    for name in names {
+        TestData::new(&test_env, Some(&PathBuf::from(name)));
+    }
+    rm(&[&["-I"], &names[..]].concat())
+        .write_stdin("n\n")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("Remove 4 arguments?"));
+    assert!(names.iter().all(|name| test_env.src.join(name).exists()));
+
+    #[cfg(unix)]
+    {
+        let link = test_env.src.join("rm");
+        std::os::unix::fs::symlink(assert_cmd::cargo::cargo_bin("rip"), &link).unwrap();
+        assert_cmd::Command::new(&link)
+            .current_dir(&test_env.src)
+            .env("RIP_GRAVEYARD", &test_env.graveyard)
+            .env("__RIP_ALLOW_RENAME", "false")
+            .args(["-f", "a", "missing.txt"])
+            .assert()
+            .success();
+        assert!(!test_env.src.join("a").exists());
+    }
+}
+
+/// Test that rm's -f answers rip's own questions without reading stdin
+#[rstest]
+fn test_rm_compat_force_prompts() {
+    let test_env = TestEnv::new();
+    let config_dir = tempdir().unwrap();
+    let config_path = config_dir.path().join("config.toml");
+    fs::write(&config_path, "big-file-threshold = 1\n").unwrap();
+    let big = TestData::new(&test_env, Some(&PathBuf::from("big.txt")));
+    let rm = |args: &[&str]| {
+        let mut cmd = cli_runner([&["--rm-compat"], args].concat(), Some(&test_env.src));
+        cmd.env("RIP_GRAVEYARD", &test_env.graveyard)
+            .env(rip2::config::CONFIG_ENV, &config_path)
+            .write_stdin("y\n");
+        cmd
+    };
+
+    // The big file is copied rather than deleted
+    rm(&["-f", "big.txt"]).assert().success();
+    let grave = util::join_absolute(
+        &test_env.graveyard,
+        dunce::canonicalize(&test_env.src).unwrap(),
+    )
+    .join("big.txt");
+    assert_eq!(fs::read_to_string(&grave).unwrap(), big.data);
+
+    // And a grave isn't unlinked
+    rm(&["-f", grave.to_str().unwrap()]).assert().success();
+    assert!(grave.exists());
+}
+
+/// Test that --exclude leaves parts of a directory in place, and that
+/// --include picks them back out
+#[rstest]
+#[case(&["--exclude", "build/cache/**"], &["build/cache/x", "build/cache/y/z"])]
+#[case(&["--exclude", "cache"], &["build/cache/x", "build/cache/y/z"])]
+#[case(&["--exclude", "cache", "--include", "y"], &["build/cache/x"])]
+#[case(&["--include", "*.o"], &["build/a.txt", "build/cache/x", "build/cache/y/z", "build/src/main.c"])]
+fn test_exclude_include(#[case] args: &[&str], #[case] kept: &[&str]) {
+    let test_env = TestEnv::new();
+    let files = [
+        "build/a.txt",
+        "build/cache/x",
+        "build/cache/y/z",
+        "build/src/main.c",
+        "build/src/main.o",
+    ];
+    for file in files {
+        let path = test_env.src.join(file);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, file).unwrap();
+    }
+
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    cli_runner(
+        [&["--graveyard", graveyard, "build"], args].concat(),
+        Some(&test_env.src),
+    )
+    .assert()
+    .success();
+
+    let buried = util::join_absolute(
+        &test_env.graveyard,
+        dunce::canonicalize(&test_env.src).unwrap(),
+    );
+    for file in files {
+        let kept = kept.contains(&file);
+        assert_eq!(test_env.src.join(file).exists(), kept, "{}", file);
+        assert_eq!(buried.join(file).exists(), !kept, "{}", file);
+    }
+}
+
+/// Test that globs are expanded when the shell doesn't
+#[rstest]
+fn test_expand_globs() {
+    let test_env = TestEnv::new();
+    let first = TestData::new(&test_env, Some(&PathBuf::from("first.txt")));
+    let second = TestData::new(&test_env, Some(&PathBuf::from("second.txt")));
+    let other = TestData::new(&test_env, Some(&PathBuf::from("other.md")));
+    let graveyard = test_env.graveyard.to_str().unwrap();
+
+    cli_runner(["--graveyard", graveyard, "*.txt"], Some(&test_env.src))
+        .assert()
+        .success();
+    assert!(!first.path.exists() && !second.path.exists());
+    assert!(other.path.exists());
+
+    // A glob that matches nothing is a missing target
+    cli_runner(["--graveyard", graveyard, "*.txt"], Some(&test_env.src))
+        .assert()
+        .code(3)
+        .stderr(predicates::str::contains("*.txt"));
+}
+
+/// Test reading targets from stdin and from a file list
+#[rstest]
+fn test_targets_from_list(
+    #[values("--stdin", "--files-from")] source: &str,
+    #[values(false, true)] null: bool,
+) {
+    let test_env = TestEnv::new();
+    let first = TestData::new(&test_env, Some(&PathBuf::from("first.txt")));
+    // Only NUL-separated lists can hold names with newlines
+    let second_name = if null && cfg!(unix) {
+        "second\n.txt"
+    } else {
+        "second.txt"
+    };
+    let second = TestData::new(&test_env, Some(&PathBuf::from(second_name)));
+    let other = TestData::new(&test_env, Some(&PathBuf::from("other.txt")));
+    let graveyard = test_env.graveyard.to_str().unwrap();
+
+    let list = if null {
+        format!("first.txt\0{}\0", second_name)
+    } else {
+        format!("first.txt\r\n\n{}\n", second.path.display())
+    };
+    let mut args = vec!["--graveyard", graveyard, source];
+    let list_path = test_env.src.join("list");
+    if source == "--files-from" {
+        fs::write(&list_path, &list).unwrap();
+        args.push(list_path.to_str().unwrap());
+    }
+    if null {
+        args.push("-0");
+    }
+    let mut cmd = cli_runner(args, Some(&test_env.src));
+    if source == "--stdin" {
+        cmd.write_stdin(list);
+    }
+    cmd.assert().success();
+    assert!(!first.path.exists() && !second.path.exists());
+    assert!(other.path.exists());
+}
+
+/// Test that rip clean buries untracked and ignored files in one batch
+#[rstest]
+fn test_clean(#[values("", "--ignored", "--untracked")] mode: &str) {
+    let test_env = TestEnv::new();
+    let git = |args: &[&str]| {
+        process::Command::new("git")
+            .args(args)
+            .current_dir(&test_env.src)
+            .env("GIT_CONFIG_GLOBAL", test_env.src.join(".gitconfig-none"))
+            .output()
+    };
+    if !git(&["init", "-q"]).is_ok_and(|output| output.status.success()) {
+        println!("Warning: skipping clean test because git is not available");
+        return;
+    }
+    let files = [
+        ".gitignore",
+        "src/main.rs",
+        "src/notes.txt",
+        "src/main.o",
+        "build/out/bin",
+        "scratch/todo.txt",
+    ];
+    for file in files {
+        let path = test_env.src.join(file);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, file).unwrap();
+    }
+    fs::write(test_env.src.join(".gitignore"), "*.o\n/build/\n").unwrap();
+    git(&["add", ".gitignore", "src/main.rs"]).unwrap();
+
+    let untracked = ["src/notes.txt", "scratch/todo.txt"];
+    let ignored = ["src/main.o", "build/out/bin"];
+    let buried: Vec<&str> = match mode {
+        "--ignored" => ignored.to_vec(),
+        "--untracked" => untracked.to_vec(),
+        _ => [untracked, ignored].concat(),
+    };
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let mut args = vec!["--graveyard", graveyard, "clean"];
+    if !mode.is_empty() {
+        args.push(mode);
+    }
+
+    // A dry run leaves everything in place
+    cli_runner([&args[..], &["-n"]].concat(), Some(&test_env.src))
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("Would bury"));
+    assert!(files.iter().all(|file| test_env.src.join(file).exists()));
+
+    cli_runner(&args, Some(&test_env.src)).assert().success();
+    for file in files {
+        assert_eq!(
+            test_env.src.join(file).exists(),
+            !buried.contains(&file),
+            "{}",
+            file
+        );
+    }
+    // Untracked directories are buried whole
+    if mode != "--ignored" {
+        assert!(!test_env.src.join("scratch").exists());
+    }
+
+    // A single restore brings everything back
+    cli_runner(["--graveyard", graveyard, "-u"], Some(&test_env.src))
+        .assert()
+        .success();
+    assert!(files.iter().all(|file| test_env.src.join(file).exists()));
+
+    cli_runner(
+        ["--graveyard", graveyard, "clean", "-n"],
+        Some(&test_env.graveyard),
+    )
+    .assert()
+    .code(2)
+    .stderr(predicates::str::contains("not in a git repository"));
+}
+
+/// Test that the global flags apply to what is swept
+#[rstest]
+fn test_sweep_global_flags() {
+    let test_env = TestEnv::new();
+    fs::write(test_env.src.join("a.log"), "a").unwrap();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let sweep = |flags: &[&str]| {
+        let mut cmd = cli_runner(
+            [
+                &["--graveyard", graveyard],
+                flags,
+                &["sweep", "--pattern", "*.log"],
+            ]
+            .concat(),
+            Some(&test_env.src),
+        );
+        cmd.env(protect::PROTECTED_ENV, "*.log");
+        cmd
+    };
+
+    sweep(&[]).assert().code(4);
+    assert!(test_env.src.join("a.log").exists());
+    sweep(&["--force-protected", "--quiet", "--jobs", "1", "--atomic"])
+        .assert()
+        .success();
+    assert!(!test_env.src.join("a.log").exists());
+}
+
+/// Test that the files of a directory that is swept whole are swept without
+/// --empty-dirs, which leaves the directory in place
+#[rstest]
+fn test_sweep_whole_dir() {
+    let test_env = TestEnv::new();
+    for file in ["logs/a.log", "logs/b.log", "keep.txt"] {
+        let path = test_env.src.join(file);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, file).unwrap();
+    }
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let args = ["--graveyard", graveyard, "sweep", "--pattern", "*.log"];
+
+    cli_runner([&args[..], &["-n"]].concat(), Some(&test_env.src))
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("Would bury logs/a.log\n"))
+        .stdout(predicates::str::contains(
+            "Would sweep 2 files and 0 directories",
+        ));
+
+    cli_runner(args, Some(&test_env.src)).assert().success();
+    assert!(test_env.src.join("logs").is_dir());
+    assert!(!test_env.src.join("logs/a.log").exists());
+    assert!(!test_env.src.join("logs/b.log").exists());
+    assert!(test_env.src.join("keep.txt").exists());
+
+    // Nor is the swept directory itself buried when all of it matches
+    let logs = test_env.src.join("logs");
+    fs::write(logs.join("c.log"), "c").unwrap();
+    cli_runner(
+        [
+            "--graveyard",
+            graveyard,
+            "sweep",
+            "--pattern",
+            "*.log",
+            "--empty-dirs",
+        ],
+        Some(&logs),
+    )
+    .assert()
+    .success()
+    .stdout(predicates::str::contains("Swept 1 files and 0 directories"));
+    assert!(logs.is_dir());
+    assert!(!logs.join("c.log").exists());
+}
+
+/// Test that rip sweep buries stale files and empty directories in one batch
+#[rstest]
+fn test_sweep() {
+    let test_env = TestEnv::new();
+    let long_ago = std::time::SystemTime::now() - Duration::from_secs(60 * 86400);
+    let files = [
+        ("old.log", true),
+        ("new.log", false),
+        ("old.txt", true),
+        ("logs/old.log", true),
+        ("keep/old.log", true),
+        ("keep/new.log", false),
+    ];
+    for (file, old) in files {
+        let path = test_env.src.join(file);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, file).unwrap();
+        if old {
+            fs::File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(long_ago)
+                .unwrap();
+        }
+    }
+    fs::create_dir_all(test_env.src.join("empty/nested")).unwrap();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let args = [
+        "--graveyard",
+        graveyard,
+        "sweep",
+        "--older-than",
+        "30d",
+        "--pattern",
+        "*.log",
+        "--empty-dirs",
+    ];
+
+    cli_runner([&args[..], &["--dry-run"]].concat(), Some(&test_env.src))
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("Would bury empty\n"))
+        .stdout(predicates::str::contains(
+            "Would sweep 3 files and 3 directories",
+        ));
+    assert!(test_env.src.join("old.log").exists());
+
+    cli_runner(args, Some(&test_env.src))
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("Swept 3 files and 3 directories"));
+    for gone in ["old.log", "logs", "keep/old.log", "empty"] {
+        assert!(!test_env.src.join(gone).exists(), "{}", gone);
+    }
+    for kept in ["new.log", "old.txt", "keep/new.log"] {
+        assert!(test_env.src.join(kept).exists(), "{}", kept);
+    }
+
+    // A single restore brings everything back
+    cli_runner(["--graveyard", graveyard, "-u"], Some(&test_env.src))
+        .assert()
+        .success();
+    assert!(test_env.src.join("old.log").exists());
+    assert!(test_env.src.join("logs/old.log").exists());
+    assert!(test_env.src.join("empty/nested").exists());
+
+    cli_runner(["--graveyard", graveyard, "sweep"], Some(&test_env.src))
+        .assert()
+        .code(2)
+        .stderr(predicates::str::contains("Nothing to sweep"));
+}
+
+/// Test that big graves are compressed, and can still be read and unburied
+#[rstest]
+fn test_compress() {
+    let test_env = TestEnv::new();
+    let config_dir = tempdir().unwrap();
+    let config_path = config_dir.path().join("config.toml");
+    fs::create_dir_all(test_env.src.join("dir/sub")).unwrap();
+    fs::write(test_env.src.join("dir/sub/inner.txt"), "inner").unwrap();
+    fs::write(test_env.src.join("old.txt"), "old").unwrap();
+    fs::write(test_env.src.join("new.txt"), "new").unwrap();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let rip = |args: &[&str]| {
+        let mut cmd = cli_runner(
+            [&["--graveyard", graveyard], args].concat(),
+            Some(&test_env.src),
+        );
+        cmd.env(rip2::config::CONFIG_ENV, &config_path);
+        cmd
+    };
+
+    rip(&["dir"]).assert().success();
+    rip(&["old.txt"]).assert().success();
+    // Graves are compressed the next time something is buried
+    fs::write(&config_path, "compress-above = 4\n").unwrap();
+    rip(&["-v", "new.txt"])
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("Compressed "));
+    let grave_dir = util::join_absolute(
+        &test_env.graveyard,
+        dunce::canonicalize(&test_env.src).unwrap(),
+    );
+    let archive = grave_dir.join(format!("dir{}", rip2::compress::SUFFIX));
+    assert!(archive.is_file());
+    assert!(!grave_dir.join("dir").exists());
+    assert!(grave_dir.join("old.txt").is_file());
+    assert!(grave_dir.join("new.txt").is_file());
+
+    rip(&["cat", "old.txt"]).assert().success().stdout("old");
+    rip(&["cat", "dir/sub/inner.txt"])
+        .assert()
+        .success()
+        .stdout("inner");
+    rip(&["cat", "new.txt"]).assert().success().stdout("new");
+    rip(&["cat", "dir/missing.txt"])
+        .assert()
+        .code(3)
+        .stderr(predicates::str::contains("Nothing buried at"));
+    // Seance reports what the graves unpack to
+    rip(&["-s"])
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("5 B\t"));
+
+    // The compressed grave keeps its place in the order of burial
+    for (restored, content) in [
+        ("new.txt", "new"),
+        ("old.txt", "old"),
+        ("dir/sub/inner.txt", "inner"),
+    ] {
+        rip(&["-u"]).assert().success();
+        assert_eq!(
+            fs::read_to_string(test_env.src.join(restored)).unwrap(),
+            content
+        );
+    }
+    assert!(!archive.exists());
+}
+
+/// Test that an age reaching back past any representable time compresses
+/// nothing, rather than panicking
+#[rstest]
+fn test_compress_huge_age() {
+    let test_env = TestEnv::new();
+    let test_data = TestData::new(&test_env, None);
+    let graveyard = Graveyard::open(&test_env.graveyard).unwrap();
+    graveyard
+        .bury(
+            &[&test_data.path],
+            &BuryOptions::default(),
+            &AlwaysYes,
+            &mut |_: &Event| {},
+        )
+        .unwrap();
+
+    for age in [Duration::from_secs(1_000_000_000 * 604_800), Duration::MAX] {
+        let archives = graveyard
+            .compress(CompressPolicy::OlderThan(age), &mut |_: &Event| {})
+            .unwrap();
+        assert!(archives.is_empty());
+    }
+    assert!(graveyard.last_bury().unwrap().is_file());
+}
+
+/// Test that files buried several times are stored once, and that graves
+/// still come back whole
+#[rstest]
+fn test_dedup() {
+    let test_env = TestEnv::new();
+    let config_dir = tempdir().unwrap();
+    let config_path = config_dir.path().join("config.toml");
+    fs::write(&config_path, "dedup = true\n").unwrap();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let rip = |args: &[&str]| {
+        let mut cmd = cli_runner(
+            [&["--graveyard", graveyard], args].concat(),
+            Some(&test_env.src),
+        );
+        cmd.env(rip2::config::CONFIG_ENV, &config_path);
+        cmd
+    };
+    let build = |changed: &str| {
+        fs::create_dir_all(test_env.src.join("build/out")).unwrap();
+        fs::write(test_env.src.join("build/same.txt"), "same").unwrap();
+        fs::write(test_env.src.join("build/out/changed.txt"), changed).unwrap();
+    };
+    let blobs = || {
+        WalkDir::new(test_env.graveyard.join(rip2::dedup::STORE))
+            .min_depth(2)
+            .into_iter()
+            .filter(|entry| entry.as_ref().unwrap().file_type().is_file())
+            .count()
+    };
+
+    build("first");
+    rip(&["build"]).assert().success();
+    build("second");
+    rip(&["build"]).assert().success();
+    fs::write(test_env.src.join("notes.txt"), "same").unwrap();
+    fs::hard_link(
+        test_env.src.join("notes.txt"),
+        test_env.src.join("link.txt"),
+    )
+    .unwrap();
+    rip(&["notes.txt"]).assert().success();
+    fs::write(test_env.src.join("last.txt"), "last").unwrap();
+    // Graves are deduplicated the next time something is buried
+    rip(&["-v", "last.txt"])
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("Deduplicated "));
+    let grave_dir = util::join_absolute(
+        &test_env.graveyard,
+        dunce::canonicalize(&test_env.src).unwrap(),
+    );
+    assert!(grave_dir
+        .join(format!("build{}", rip2::dedup::SUFFIX))
+        .is_file());
+    assert!(!grave_dir.join("build").exists());
+    assert!(grave_dir
+        .join(format!("notes.txt{}", rip2::dedup::SUFFIX))
+        .is_file());
+    assert_eq!(blobs(), 3);
+    for entry in WalkDir::new(test_env.graveyard.join(rip2::dedup::STORE)).min_depth(2) {
+        let metadata = entry.unwrap().metadata().unwrap();
+        assert!(metadata.is_dir() || metadata.permissions().readonly());
+    }
+    // A link to a buried file outside the graveyard doesn't reach the blob
+    fs::write(test_env.src.join("link.txt"), "edited").unwrap();
+
+    rip(&["cat", "build/out/changed.txt"])
+        .assert()
+        .success()
+        .stdout("second");
+    rip(&["cat", "notes.txt"]).assert().success().stdout("same");
+    rip(&["cat", "build/out"])
+        .assert()
+        .code(2)
+        .stderr(predicates::str::contains("is not a file"));
+    rip(&["-s"])
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("10 B\t"));
+
+    rip(&["-u"]).assert().success();
+    rip(&["-u"]).assert().success();
+    assert_eq!(
+        fs::read_to_string(test_env.src.join("notes.txt")).unwrap(),
+        "same"
+    );
+    rip(&["-u"]).assert().success();
+    assert_eq!(
+        fs::read_to_string(test_env.src.join("build/out/changed.txt")).unwrap(),
+        "second"
+    );
+    assert_eq!(
+        fs::read_to_string(test_env.src.join("build/same.txt")).unwrap(),
+        "same"
+    );
+    // Only the blob that no other grave refers to is gone
+    assert_eq!(blobs(), 2);
+
+    fs::remove_dir_all(test_env.src.join("build")).unwrap();
+    rip(&["-u"]).assert().success();
+    assert_eq!(
+        fs::read_to_string(test_env.src.join("build/out/changed.txt")).unwrap(),
+        "first"
+    );
+    assert_eq!(blobs(), 0);
+}
+
+/// Test that names that aren't valid UTF-8 survive deduplication
+#[cfg(unix)]
+#[rstest]
+fn test_dedup_non_utf8() {
+    use std::os::unix::ffi::OsStrExt;
+
+    let test_env = TestEnv::new();
+    let config_dir = tempdir().unwrap();
+    let config_path = config_dir.path().join("config.toml");
+    fs::write(&config_path, "dedup = true\n").unwrap();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let rip = |args: &[&str]| {
+        let mut cmd = cli_runner(
+            [&["--graveyard", graveyard], args].concat(),
+            Some(&test_env.src),
+        );
+        cmd.env(rip2::config::CONFIG_ENV, &config_path);
+        cmd
+    };
+    let name = ffi::OsStr::from_bytes(b"caf\xe9.txt");
+    fs::create_dir_all(test_env.src.join("names")).unwrap();
+    fs::write(test_env.src.join("names").join(name), "latin-1").unwrap();
+
+    rip(&["names"]).assert().success();
+    fs::write(test_env.src.join("last.txt"), "last").unwrap();
+    rip(&["-v", "last.txt"])
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("Deduplicated "));
+    let grave_dir = util::join_absolute(
+        &test_env.graveyard,
+        dunce::canonicalize(&test_env.src).unwrap(),
+    );
+    assert!(grave_dir
+        .join(format!("names{}", rip2::dedup::SUFFIX))
+        .is_file());
+
+    rip(&["-u"]).assert().success();
+    rip(&["-u"]).assert().success();
+    assert_eq!(
+        fs::read_to_string(test_env.src.join("names").join(name)).unwrap(),
+        "latin-1"
+    );
+}
+
+/// Test that graves are encrypted at rest, and can only be read and
+/// unburied with the key
+#[rstest]
+fn test_encrypt() {
+    let test_env = TestEnv::new();
+    let config_dir = tempdir().unwrap();
+    let config_path = config_dir.path().join("config.toml");
+    let key_path = config_dir.path().join("key");
+    fs::write(&key_path, "correct horse battery staple\n").unwrap();
+    fs::write(
+        &config_path,
+        format!(
+            "encrypt = true\nkey-file = {:?}\n",
+            key_path.display().to_string()
+        ),
+    )
+    .unwrap();
+    fs::write(test_env.src.join("secret.env"), "TOKEN=1").unwrap();
+    fs::create_dir(test_env.src.join("keys")).unwrap();
+    fs::write(test_env.src.join("keys/id"), "key").unwrap();
+    fs::write(test_env.src.join("other.txt"), "other").unwrap();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let rip = |args: &[&str]| {
+        let mut cmd = cli_runner(
+            [&["--graveyard", graveyard], args].concat(),
+            Some(&test_env.src),
+        );
+        cmd.env(rip2::config::CONFIG_ENV, &config_path)
+            .env_remove(rip2::crypt::PASSPHRASE_ENV);
+        cmd
+    };
+
+    // Graves are encrypted as soon as they are buried
+    rip(&["-v", "secret.env"])
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("Encrypted "));
+    rip(&["keys"]).assert().success();
+    rip(&["other.txt"]).assert().success();
+    let grave_dir = util::join_absolute(
+        &test_env.graveyard,
+        dunce::canonicalize(&test_env.src).unwrap(),
+    );
+    let sealed = grave_dir.join(format!("secret.env{}", rip2::crypt::SUFFIX));
+    assert!(!grave_dir.join("secret.env").exists());
+    assert!(!String::from_utf8_lossy(&fs::read(&sealed).unwrap()).contains("TOKEN"));
+    assert!(grave_dir.join("keys.rip.enc").is_file());
+
+    // The record, and the sizes, are readable without the key
+    let mut cmd = rip(&["-s"]);
+    cmd.env(
+        rip2::config::CONFIG_ENV,
+        config_dir.path().join("none.toml"),
+    );
+    cmd.assert()
+        .success()
+        .stdout(predicates::str::contains("7 B\t"));
+    let mut cmd = rip(&["cat", "secret.env"]);
+    cmd.env(
+        rip2::config::CONFIG_ENV,
+        config_dir.path().join("none.toml"),
+    );
+    cmd.assert()
+        .code(2)
+        .stderr(predicates::str::contains("is encrypted"));
+    let mut cmd = rip(&["cat", "secret.env"]);
+    cmd.env(rip2::crypt::PASSPHRASE_ENV, "wrong");
+    cmd.assert()
+        .failure()
+        .stderr(predicates::str::contains("wrong passphrase or key file"));
+    rip(&["cat", "secret.env"])
+        .assert()
+        .success()
+        .stdout("TOKEN=1");
+    rip(&["cat", "keys/id"]).assert().success().stdout("key");
+
+    for (restored, content) in [
+        ("other.txt", "other"),
+        ("keys/id", "key"),
+        ("secret.env", "TOKEN=1"),
+    ] {
+        rip(&["-u"]).assert().success();
+        assert_eq!(
+            fs::read_to_string(test_env.src.join(restored)).unwrap(),
+            content
+        );
+    }
+    assert!(!sealed.exists());
+}
+
+/// Test that what was buried before quitting at a prompt is encrypted
+#[rstest]
+fn test_encrypt_after_quit() {
+    let _env_lock = aquire_lock();
+
+    let test_env = TestEnv::new();
+    let config_dir = tempdir().unwrap();
+    let config_path = config_dir.path().join("config.toml");
+    fs::write(&config_path, "encrypt = true\n").unwrap();
+    let first = test_env.src.join("first.env");
+    let second = test_env.src.join("second.env");
+    fs::write(&first, "TOKEN=1").unwrap();
+    fs::write(&second, "TOKEN=2").unwrap();
+    env::set_var(rip2::config::CONFIG_ENV, &config_path);
+    env::set_var(rip2::crypt::PASSPHRASE_ENV, "correct horse battery staple");
+
+    let prompter = ScriptedPrompter::new([Answer::Yes, Answer::Quit]);
+    let result = rip2::run(
+        Args {
+            targets: vec![first.clone(), second.clone()],
+            graveyard: Some(test_env.graveyard.clone()),
+            inspect: true,
+            ..Args::default()
+        },
+        &prompter,
+        &mut Vec::new(),
+    );
+
+    env::remove_var(rip2::config::CONFIG_ENV);
+    env::remove_var(rip2::crypt::PASSPHRASE_ENV);
+
+    assert!(matches!(result, Err(rip2::Error::UserAborted)));
+    assert!(!first.exists());
+    assert!(second.exists());
+    let grave_dir = util::join_absolute(
+        &test_env.graveyard,
+        dunce::canonicalize(&test_env.src).unwrap(),
+    );
+    assert!(!grave_dir.join("first.env").exists());
+    assert!(grave_dir
+        .join(format!("first.env{}", rip2::crypt::SUFFIX))
+        .is_file());
+}
@@ -0,0 +1,538 @@
+use rip2::args::Args;
+use rip2::prompt::AlwaysYes;
+use rip2::record::{Cursor, Record, RecordItem, DEFAULT_FILE_LOCK, INDEX, RECORD};
+use rstest::rstest;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::{process, thread};
+use tempfile::{tempdir, TempDir};
+
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+
+const HEADER: &str = "Time\tOriginal\tDestination";
+const TIME: &str = "2024-10-22T12:00:00+00:00";
+
+/// A graveyard holding a single grave, with a hand-written record
+struct TestGraveyard {
+    _tmpdir: TempDir,
+    graveyard: PathBuf,
+    grave: PathBuf,
+}
+
+impl TestGraveyard {
+    fn new(record_contents: impl FnOnce(&Path) -> Vec<u8>) -> TestGraveyard {
+        let _tmpdir = tempdir().unwrap();
+        let graveyard = _tmpdir.path().join("graveyard");
+        let grave = graveyard.join("data").join("file.txt");
+        fs::create_dir_all(grave.parent().unwrap()).unwrap();
+        fs::write(&grave, "data").unwrap();
+        fs::write(graveyard.join(RECORD), record_contents(&grave)).unwrap();
+        TestGraveyard {
+            _tmpdir,
+            graveyard,
+            grave,
+        }
+    }
+
+    fn record(&self) -> Record<DEFAULT_FILE_LOCK> {
+        Record::new(&self.graveyard).unwrap()
+    }
+
+    fn record_contents(&self) -> Vec<u8> {
+        fs::read(self.graveyard.join(RECORD)).unwrap()
+    }
+}
+
+fn line(orig: &str, grave: &Path) -> String {
+    format!("{}\t{}\t{}\n", TIME, orig, grave.display())
+}
+
+/// Make a directory or file read-only, returning false if we can
+/// still write to it anyway (e.g., when running as root)
+#[cfg(unix)]
+fn make_read_only(path: &Path) -> bool {
+    let mut permissions = fs::metadata(path).unwrap().permissions();
+    permissions.set_mode(if path.is_dir() { 0o555 } else { 0o444 });
+    fs::set_permissions(path, permissions).unwrap();
+
+    let probe = if path.is_dir() {
+        fs::write(path.join("probe"), "").is_err()
+    } else {
+        fs::OpenOptions::new().append(true).open(path).is_err()
+    };
+    if !probe {
+        println!("Warning: skipping read-only checks because permissions are not enforced");
+    }
+    probe
+}
+
+#[rstest]
+fn parse_record_item(
+    #[values("", "garbage", "time\t/orig", "time\t/orig\t", "\t/orig\t/dest")] line: &str,
+) {
+    let err = RecordItem::new(line).unwrap_err();
+    assert!(matches!(err, rip2::Error::RecordCorrupt { .. }));
+    assert_eq!(err.exit_code(), 5);
+
+    let item = RecordItem::new("time\t/orig\t/dest").unwrap();
+    assert_eq!(item.orig, PathBuf::from("/orig"));
+    assert_eq!(item.dest, PathBuf::from("/dest"));
+}
+
+#[cfg(unix)]
+#[rstest]
+fn read_only_graveyard() {
+    let tmpdir = tempdir().unwrap();
+    let graveyard = tmpdir.path().join("graveyard");
+    fs::create_dir(&graveyard).unwrap();
+    if !make_read_only(&graveyard) {
+        return;
+    }
+
+    let err = Record::<DEFAULT_FILE_LOCK>::new(&graveyard).unwrap_err();
+    assert!(matches!(err, rip2::Error::Record { .. }));
+
+    // The same error should come out of a bury, rather than a panic
+    let target = tmpdir.path().join("file.txt");
+    fs::write(&target, "data").unwrap();
+    let mut log = Vec::new();
+    let err = rip2::run(
+        Args {
+            targets: vec![target.clone()],
+            graveyard: Some(graveyard.clone()),
+            ..Args::default()
+        },
+        AlwaysYes,
+        &mut log,
+    )
+    .unwrap_err();
+    assert!(matches!(err, rip2::Error::Record { .. }));
+    assert!(target.exists());
+
+    let mut permissions = fs::metadata(&graveyard).unwrap().permissions();
+    permissions.set_mode(0o755);
+    fs::set_permissions(&graveyard, permissions).unwrap();
+}
+
+#[cfg(unix)]
+#[rstest]
+fn read_only_record() {
+    let test_graveyard =
+        TestGraveyard::new(|grave| format!("{}\n{}", HEADER, line("/data/file.txt", grave)).into());
+    let record = test_graveyard.record();
+    if !make_read_only(&test_graveyard.graveyard.join(RECORD)) {
+        return;
+    }
+
+    // Reading still works
+    assert_eq!(record.get_last_bury().unwrap(), test_graveyard.grave);
+    assert_eq!(record.seance(&test_graveyard.graveyard).unwrap().count(), 1);
+
+    // But writing is an error
+    let err = record.write_log("/data/other.txt", "/grave").unwrap_err();
+    assert!(matches!(err, rip2::Error::Record { .. }));
+    let err = record
+        .log_exhumed_graves(std::slice::from_ref(&test_graveyard.grave))
+        .unwrap_err();
+    assert!(matches!(err, rip2::Error::Record { .. }));
+}
+
+#[rstest]
+fn truncated_record(#[values("header", "entry", "empty")] truncated_at: &str) {
+    let test_graveyard = TestGraveyard::new(|grave| {
+        let full = format!("{}\n{}", HEADER, line("/data/file.txt", grave));
+        match truncated_at {
+            // The header itself is cut short
+            "header" => full[..4].into(),
+            // A second entry is cut short after its original path
+            "entry" => format!("{}{}\t/data/other.txt", full, TIME).into(),
+            "empty" => Vec::new(),
+            _ => unreachable!(),
+        }
+    });
+    let record = test_graveyard.record();
+
+    match truncated_at {
+        "entry" => {
+            assert_eq!(record.get_last_bury().unwrap(), test_graveyard.grave);
+            assert_eq!(record.seance(&test_graveyard.graveyard).unwrap().count(), 1);
+        }
+        _ => {
+            let err = record.get_last_bury().unwrap_err();
+            assert!(matches!(err, rip2::Error::NothingToUnbury));
+            assert_eq!(record.seance(&test_graveyard.graveyard).unwrap().count(), 0);
+        }
+    }
+
+    // New entries can still be written
+    record.write_log("/data/new.txt", "/grave/new.txt").unwrap();
+    let contents = String::from_utf8(test_graveyard.record_contents()).unwrap();
+    assert!(contents.contains("/data/new.txt\t/grave/new.txt"));
+}
+
+#[rstest]
+fn garbage_lines() {
+    let garbage: &[&[u8]] = &[b"not a record line", b"\xff\xfe\tbinary\tjunk", b"\t\t"];
+    let test_graveyard = TestGraveyard::new(|grave| {
+        let mut contents = format!("{}\n", HEADER).into_bytes();
+        for junk in garbage {
+            contents.extend_from_slice(junk);
+            contents.push(b'\n');
+        }
+        contents.extend_from_slice(line("/data/file.txt", grave).as_bytes());
+        contents
+    });
+    let record = test_graveyard.record();
+
+    assert_eq!(record.get_last_bury().unwrap(), test_graveyard.grave);
+    let graves: Vec<_> = record
+        .lines_of_graves(std::slice::from_ref(&test_graveyard.grave))
+        .unwrap()
+        .collect();
+    assert_eq!(graves.len(), 1);
+    assert_eq!(graves[0].orig, PathBuf::from("/data/file.txt"));
+
+    // Removing the grave appends a tombstone, and compacting drops both
+    // while keeping the header and the garbage as it was
+    let before = test_graveyard.record_contents();
+    record
+        .log_exhumed_graves(std::slice::from_ref(&test_graveyard.grave))
+        .unwrap();
+    let contents = test_graveyard.record_contents();
+    assert!(contents.starts_with(&before));
+    assert!(String::from_utf8_lossy(&contents[before.len()..]).ends_with("\tunburied\n"));
+    assert_eq!(
+        record
+            .lines_of_graves(std::slice::from_ref(&test_graveyard.grave))
+            .unwrap()
+            .count(),
+        0
+    );
+
+    let compaction = record.compact().unwrap();
+    assert_eq!(compaction.lines_before, garbage.len() + 2);
+    assert_eq!(compaction.lines_after, garbage.len());
+    let contents = test_graveyard.record_contents();
+    let mut expected = format!("{}\n", HEADER).into_bytes();
+    for junk in garbage {
+        expected.extend_from_slice(junk);
+        expected.push(b'\n');
+    }
+    assert_eq!(contents, expected);
+}
+
+#[rstest]
+fn missing_header() {
+    let test_graveyard = TestGraveyard::new(|grave| line("/data/file.txt", grave).into());
+    let record = test_graveyard.record();
+    assert_eq!(record.get_last_bury().unwrap(), test_graveyard.grave);
+}
+
+#[rstest]
+fn seance_with_bad_time() {
+    let test_graveyard = TestGraveyard::new(|grave| {
+        format!(
+            "{}\nyesterday\t/data/file.txt\t{}\n",
+            HEADER,
+            grave.display()
+        )
+        .into()
+    });
+
+    // Seance needs the graves to be under the current directory
+    let gravepath = test_graveyard.graveyard.join("data");
+    let record = test_graveyard.record();
+    let graves: Vec<_> = record.seance(&gravepath).unwrap().collect();
+    assert_eq!(graves.len(), 1);
+    assert_eq!(graves[0].time, "yesterday");
+}
+
+/// Lookups go through the index, which follows appends and rewrites
+#[rstest]
+fn indexed_lookups(#[values(10, 1000)] entries: usize) {
+    let test_graveyard = TestGraveyard::new(|grave| {
+        let mut contents = format!("{}\n", HEADER);
+        for i in 0..entries {
+            // Older entries first, from two different directories
+            let time = format!("2024-10-{:02}T12:00:00+00:00", 1 + i % 20);
+            let dir = if i % 2 == 0 { "even" } else { "odd" };
+            contents.push_str(&format!("{}\t/{}/{}\t/grave/{}/{}\n", time, dir, i, dir, i));
+        }
+        contents.push_str(&line("/data/file.txt", grave));
+        contents.into_bytes()
+    });
+    let record = test_graveyard.record();
+
+    // The index is built from the text record on first use
+    assert_eq!(record.get_last_bury().unwrap(), test_graveyard.grave);
+    assert!(test_graveyard.graveyard.join(INDEX).exists());
+
+    let grave = PathBuf::from("/grave/odd/7");
+    let found: Vec<_> = record
+        .lines_of_graves(std::slice::from_ref(&grave))
+        .unwrap()
+        .collect();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].orig, PathBuf::from("/odd/7"));
+
+    // Prefix lookups come back in record order
+    let even: Vec<_> = record
+        .seance(Path::new("/grave/even"))
+        .unwrap()
+        .map(|item| item.orig)
+        .collect();
+    let expected: Vec<_> = (0..entries)
+        .step_by(2)
+        .map(|i| PathBuf::from(format!("/even/{}", i)))
+        .collect();
+    assert_eq!(even, expected);
+    assert_eq!(
+        record.buried_from(Path::new("/odd")).unwrap().count(),
+        entries / 2
+    );
+    let cutoff = chrono::DateTime::parse_from_rfc3339("2024-10-03T00:00:00+00:00").unwrap();
+    assert_eq!(
+        record.buried_before(cutoff).unwrap().count(),
+        (0..entries).filter(|i| i % 20 < 2).count()
+    );
+
+    // Appended entries are found before the index catches up
+    record.write_log("/odd/new", "/grave/odd/new").unwrap();
+    assert_eq!(
+        record.buried_from(Path::new("/odd")).unwrap().count(),
+        entries / 2 + 1
+    );
+
+    // Rewriting the record behind rip's back invalidates the index
+    fs::write(
+        test_graveyard.graveyard.join(RECORD),
+        format!(
+            "{}\n{}",
+            HEADER,
+            line("/data/file.txt", &test_graveyard.grave)
+        ),
+    )
+    .unwrap();
+    assert_eq!(record.buried_from(Path::new("/odd")).unwrap().count(), 0);
+    assert_eq!(record.seance(Path::new("/")).unwrap().count(), 1);
+}
+
+/// Graves are tombstoned rather than removed, and the same grave path
+/// can be reused afterwards
+#[rstest]
+fn tombstones() {
+    let test_graveyard = TestGraveyard::new(|grave| {
+        format!(
+            "{}\n{}{}",
+            HEADER,
+            line("/data/file.txt", grave),
+            line("/data/other.txt", Path::new("/grave/other.txt"))
+        )
+        .into()
+    });
+    let record = test_graveyard.record();
+    let grave = std::slice::from_ref(&test_graveyard.grave);
+
+    record.log_exhumed_graves(grave).unwrap();
+    assert_eq!(record.lines_of_graves(grave).unwrap().count(), 0);
+    assert_eq!(record.seance(Path::new("/")).unwrap().count(), 1);
+    assert_eq!(record.buried_from(Path::new("/data")).unwrap().count(), 1);
+
+    // Bury something at the same grave again
+    record
+        .write_log("/data/again.txt", &test_graveyard.grave)
+        .unwrap();
+    let found: Vec<_> = record.lines_of_graves(grave).unwrap().collect();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].orig, PathBuf::from("/data/again.txt"));
+    assert_eq!(record.get_last_bury().unwrap(), test_graveyard.grave);
+
+    // /grave/other.txt doesn't exist, so looking for the last bury marks it as missing
+    record.log_purged_graves(grave).unwrap();
+    assert!(matches!(
+        record.get_last_bury(),
+        Err(rip2::Error::NothingToUnbury)
+    ));
+    let contents = String::from_utf8(test_graveyard.record_contents()).unwrap();
+    assert!(contents.contains("\t\t/grave/other.txt\tmissing\n"));
+    assert!(contents.contains("\tpurged\n"));
+    assert_eq!(record.seance(Path::new("/")).unwrap().count(), 0);
+
+    let compaction = record.compact().unwrap();
+    assert_eq!(compaction.lines_before, 6);
+    assert_eq!(compaction.lines_after, 0);
+    assert_eq!(
+        String::from_utf8(test_graveyard.record_contents()).unwrap(),
+        format!("{}\n", HEADER)
+    );
+    // Nothing is left behind by the compaction
+    assert!(!test_graveyard.graveyard.join(".record.tmp").exists());
+}
+
+/// A pass only sees what was recorded since it last advanced, and starts
+/// over once the record was rewritten
+#[rstest]
+fn cursors() {
+    let test_graveyard =
+        TestGraveyard::new(|grave| format!("{}\n{}", HEADER, line("/data/file.txt", grave)).into());
+    let record = test_graveyard.record();
+
+    let (graves, cursor) = record.recorded_since("pass").unwrap();
+    assert_eq!(graves.len(), 1);
+    record.advance("pass", cursor).unwrap();
+    assert!(record.recorded_since("pass").unwrap().0.is_empty());
+    // Other passes keep their own place
+    assert_eq!(record.recorded_since("other").unwrap().0.len(), 1);
+
+    record.write_log("/data/new.txt", "/grave/new.txt").unwrap();
+    let (graves, cursor) = record.recorded_since("pass").unwrap();
+    assert_eq!(graves.len(), 1);
+    assert_eq!(graves[0].dest, PathBuf::from("/grave/new.txt"));
+    record.advance("pass", cursor).unwrap();
+
+    record
+        .log_exhumed_graves(&[PathBuf::from("/grave/new.txt")])
+        .unwrap();
+    record.compact().unwrap();
+    let (graves, _) = record.recorded_since("pass").unwrap();
+    assert_eq!(graves.len(), 1);
+    assert_eq!(graves[0].dest, test_graveyard.grave);
+
+    // Passes by age pick up where the last cutoff was
+    let cutoff = chrono::DateTime::parse_from_rfc3339(TIME).unwrap();
+    let (graves, cursor) = record
+        .buried_before_since("age", cutoff + chrono::Duration::seconds(1))
+        .unwrap();
+    assert_eq!(graves.len(), 1);
+    assert!(matches!(cursor, Cursor::Time(_)));
+    record.advance("age", cursor).unwrap();
+    let later = cutoff + chrono::Duration::days(1);
+    assert!(record
+        .buried_before_since("age", later)
+        .unwrap()
+        .0
+        .is_empty());
+}
+
+/// Records that are mostly tombstones are compacted without being asked
+#[rstest]
+fn auto_compact() {
+    let test_graveyard = TestGraveyard::new(|grave| {
+        let mut contents = format!("{}\n", HEADER);
+        for i in 0..1200 {
+            contents.push_str(&line(
+                &format!("/data/{}", i),
+                Path::new(&format!("/grave/{}", i)),
+            ));
+        }
+        contents.push_str(&line("/data/file.txt", grave));
+        contents.into_bytes()
+    });
+    let record = test_graveyard.record();
+
+    let graves: Vec<PathBuf> = (0..200)
+        .map(|i| PathBuf::from(format!("/grave/{}", i)))
+        .collect();
+    record.log_exhumed_graves(&graves).unwrap();
+    // Not enough tombstones yet
+    let lines = String::from_utf8(test_graveyard.record_contents())
+        .unwrap()
+        .lines()
+        .count();
+    assert_eq!(lines, 1 + 1201 + 200);
+
+    let graves: Vec<PathBuf> = (200..600)
+        .map(|i| PathBuf::from(format!("/grave/{}", i)))
+        .collect();
+    record.log_exhumed_graves(&graves).unwrap();
+    let lines = String::from_utf8(test_graveyard.record_contents())
+        .unwrap()
+        .lines()
+        .count();
+    assert_eq!(lines, 1 + 601);
+    assert_eq!(record.get_last_bury().unwrap(), test_graveyard.grave);
+}
+
+/// Readers share the record, while writers wait for everyone else,
+/// and either give up after the lock timeout
+#[cfg(not(target_os = "windows"))]
+#[rstest]
+fn lock_contention() {
+    let test_graveyard =
+        TestGraveyard::new(|grave| format!("{}\n{}", HEADER, line("/data/file.txt", grave)).into());
+    let record = Record::<true>::new(&test_graveyard.graveyard)
+        .unwrap()
+        .with_lock_timeout(Duration::from_millis(50));
+
+    // A reader gives up while a writer holds the record, and names it
+    let writer = record.open().unwrap();
+    let err = record.open_shared().unwrap_err();
+    assert!(
+        matches!(err, rip2::Error::GraveyardBusy { pid: Some(pid), .. } if pid == process::id())
+    );
+    assert!(err
+        .to_string()
+        .starts_with(&format!("Graveyard busy (held by PID {})", process::id())));
+    assert_eq!(err.exit_code(), 7);
+
+    // One that is more patient gets in once the writer is done
+    let patient = record.clone().with_lock_timeout(Duration::from_secs(10));
+    let waiting = thread::spawn(move || patient.open_shared().map(drop));
+    thread::sleep(Duration::from_millis(50));
+    drop(writer);
+    waiting.join().unwrap().unwrap();
+
+    // Readers don't block each other
+    let reader = record.open_shared().unwrap();
+    assert_eq!(record.seance(&test_graveyard.graveyard).unwrap().count(), 1);
+
+    // But writers wait for them, and the last writer is no longer named
+    let err = record
+        .write_log(
+            "/data/other.txt",
+            test_graveyard.graveyard.join("other.txt"),
+        )
+        .unwrap_err();
+    assert!(matches!(err, rip2::Error::GraveyardBusy { pid: None, .. }));
+    drop(reader);
+    record
+        .write_log(
+            "/data/other.txt",
+            test_graveyard.graveyard.join("other.txt"),
+        )
+        .unwrap();
+}
+
+/// Graves recorded with the same time are returned together as the last batch
+#[rstest]
+fn batches() {
+    let test_graveyard =
+        TestGraveyard::new(|grave| format!("{}\n{}", HEADER, line("/data/file.txt", grave)).into());
+    let record = test_graveyard.record();
+    let batch: Vec<PathBuf> = ["a", "b", "c"]
+        .iter()
+        .map(|name| test_graveyard.graveyard.join("data").join(name))
+        .collect();
+    let time = "2024-10-23T12:00:00+00:00";
+    for grave in &batch {
+        fs::write(grave, "data").unwrap();
+        record.write_log_at(time, "/data/batch", grave).unwrap();
+    }
+    // The batch is returned newest first, without anything buried before it
+    let last: Vec<PathBuf> = batch.iter().rev().cloned().collect();
+    assert_eq!(record.get_last_batch().unwrap(), last);
+    assert_eq!(record.get_last_bury().unwrap(), batch[2]);
+
+    // Graves that were unburied or are gone are left out
+    record.log_exhumed_graves(&batch[2..]).unwrap();
+    fs::remove_file(&batch[0]).unwrap();
+    assert_eq!(record.get_last_batch().unwrap(), vec![batch[1].clone()]);
+
+    record.log_exhumed_graves(&batch[1..2]).unwrap();
+    assert_eq!(
+        record.get_last_batch().unwrap(),
+        vec![test_graveyard.grave.clone()]
+    );
+}
@@ -1,13 +1,18 @@
 use lazy_static::lazy_static;
 use rip2::args::{validate_args, Args, Commands};
 use rip2::completions;
-use rip2::util::{humanize_bytes, TestMode};
+use rip2::prompt::{AlwaysNo, AlwaysYes, Answer, CallbackPrompter, Prompter, ScriptedPrompter};
+use rip2::rm::{self, Interactive, RmArgs};
+use rip2::util::{humanize_bytes, join_absolute, parse_duration, parse_size, split_absolute};
+use rip2::{Observer, TextRenderer};
 use rstest::rstest;
+use std::env;
 use std::fs;
-use std::io::{Cursor, ErrorKind};
+use std::io::Cursor;
 use std::path::PathBuf;
 use std::process;
 use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
 use tempfile::tempdir;
 
 #[cfg(unix)]
@@ -54,7 +59,11 @@ fn test_filetypes(
     #[values("regular", "big", "fifo", "symlink", "socket")] file_type: &str,
     #[values(false, true)] copy: bool,
 ) {
-    if ["big", "socket"].contains(&file_type) && !copy {
+    if file_type == "socket" && !copy {
+        return;
+    }
+    // The big file rule is applied to whole targets by `move_target`
+    if file_type == "big" && copy {
         return;
     }
 
@@ -99,12 +108,29 @@ fn test_filetypes(
     }
 
     let mut log = Vec::new();
-    let mode = TestMode;
+    let prompter = AlwaysYes;
 
     if copy {
-        rip2::copy_file(&source_path, &dest_path, &mode, &mut log).unwrap();
+        rip2::copy_file(
+            &source_path,
+            &dest_path,
+            &prompter,
+            &mut TextRenderer::new(&mut log),
+        )
+        .unwrap();
     } else {
-        rip2::move_target(&source_path, &dest_path, true, &mode, &mut log).unwrap();
+        // Big files must not be renamed, so they have to be copied
+        let allow_rename = file_type != "big";
+        rip2::move_target(
+            &source_path,
+            &dest_path,
+            allow_rename,
+            Some(rip2::BigFileRule::default()),
+            1,
+            &prompter,
+            &mut TextRenderer::new(&mut log),
+        )
+        .unwrap();
     }
 
     let log_s = String::from_utf8(log).unwrap();
@@ -164,18 +190,73 @@ fn test_prompt_read(#[values("y", "Y", "n", "N", "", "\n", "q", "Q", "k")] key:
         "n" | "N" | "" | "\n" => assert!(!result.unwrap()),
         "q" | "Q" => {
             let err = result.unwrap_err();
-            assert_eq!(err.kind(), ErrorKind::Interrupted);
+            assert!(matches!(err, rip2::Error::UserAborted));
             assert_eq!(err.to_string(), "User requested to quit");
         }
         "k" => {
             let err = result.unwrap_err();
-            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+            assert!(matches!(err, rip2::Error::InvalidInput(_)));
             assert_eq!(err.to_string(), "Invalid input");
         }
         _ => {}
     }
 }
 
+#[rstest]
+fn test_scripted_prompter() {
+    let prompter = ScriptedPrompter::new([Answer::Yes, Answer::No, Answer::Quit]);
+    assert!(prompter.ask("first").unwrap());
+    assert!(!prompter.ask("second").unwrap());
+    assert!(matches!(
+        prompter.ask("third"),
+        Err(rip2::Error::UserAborted)
+    ));
+    // Running out of answers is an error rather than a silent default
+    assert!(matches!(
+        prompter.ask("fourth"),
+        Err(rip2::Error::InvalidInput(_))
+    ));
+    assert_eq!(
+        prompter.questions(),
+        vec!["first", "second", "third", "fourth"]
+    );
+    assert_eq!(prompter.remaining(), 0);
+
+    let callback = CallbackPrompter(|question: &str| Ok(question.starts_with("Send")));
+    assert!(callback.ask("Send it?").unwrap());
+    assert!(!callback.ask("Delete it?").unwrap());
+    assert!(!AlwaysNo.ask("Anything?").unwrap());
+}
+
+/// Copying a socket fails, so the user is asked to delete it instead
+#[cfg(unix)]
+#[rstest]
+fn test_copy_special_file_prompt(#[values(Answer::Yes, Answer::No, Answer::Quit)] answer: Answer) {
+    let tmpdir = tempdir().unwrap();
+    let source_path = tmpdir.path().join("socket");
+    let dest_path = tmpdir.path().join("dest");
+    let _listener = UnixListener::bind(&source_path).unwrap();
+
+    let prompter = ScriptedPrompter::new([answer]);
+    let mut log = Vec::new();
+    let result = rip2::copy_file(
+        &source_path,
+        &dest_path,
+        &prompter,
+        &mut TextRenderer::new(&mut log),
+    );
+    assert_eq!(prompter.questions(), vec!["Permanently delete the file?"]);
+    match answer {
+        Answer::Yes => assert!(!result.unwrap()),
+        Answer::No => assert!(matches!(
+            result,
+            Err(rip2::Error::CrossDeviceCopyFailed { .. })
+        )),
+        Answer::Quit => assert!(matches!(result, Err(rip2::Error::UserAborted))),
+    }
+    assert!(!dest_path.exists());
+}
+
 #[rstest]
 fn test_completions(
     #[values("bash", "elvish", "fish", "powershell", "zsh", "nushell", "fake")] shell: &str,
@@ -251,9 +332,228 @@ fn fail_move_dir() {
     let dest = path_dest.join("foo");
     let target = path_target.join("bar");
     let mut log = Vec::new();
-    let results = rip2::move_dir(&target, &dest, &TestMode, &mut log);
+    let results = rip2::move_dir(
+        &target,
+        &dest,
+        1,
+        &AlwaysYes,
+        &mut TextRenderer::new(&mut log),
+    );
     assert!(results.is_err());
     if let Err(e) = results {
         assert!(e.to_string().contains("Failed to remove dir"));
     }
 }
+
+#[rstest]
+fn parallel_move_dir(#[values(1, 4)] jobs: usize) {
+    let tmpdir = tempdir().unwrap();
+    let target = tmpdir.path().join("target");
+    let dest = tmpdir.path().join("dest");
+    let mut expected = Vec::new();
+    for i in 0..50 {
+        let file = PathBuf::from(format!("dir{}/sub{}/file{}.txt", i % 3, i % 5, i));
+        fs::create_dir_all(target.join(&file).parent().unwrap()).unwrap();
+        fs::write(target.join(&file), i.to_string()).unwrap();
+        expected.push((file, i.to_string()));
+    }
+    fs::create_dir_all(target.join("empty")).unwrap();
+    #[cfg(unix)]
+    symlink("dir0/sub0/file0.txt", target.join("link")).unwrap();
+
+    let mut copied = 0;
+    let moved = rip2::move_dir(
+        &target,
+        &dest,
+        jobs,
+        &AlwaysYes,
+        &mut |event: &rip2::Event| {
+            if matches!(event, rip2::Event::Copied { .. }) {
+                copied += 1;
+            }
+        },
+    )
+    .unwrap();
+    assert!(moved);
+    assert_eq!(copied, 50);
+    assert!(!target.exists());
+    for (file, contents) in expected {
+        assert_eq!(fs::read_to_string(dest.join(file)).unwrap(), contents);
+    }
+    assert!(dest.join("empty").is_dir());
+    #[cfg(unix)]
+    assert_eq!(
+        fs::read_link(dest.join("link")).unwrap(),
+        PathBuf::from("dir0/sub0/file0.txt")
+    );
+}
+
+#[rstest]
+fn test_parse_size() {
+    assert_eq!(parse_size("0").unwrap(), 0);
+    assert_eq!(parse_size("512").unwrap(), 512);
+    assert_eq!(parse_size("1K").unwrap(), 1024);
+    assert_eq!(parse_size("10GiB").unwrap(), 10 * 1024 * 1024 * 1024);
+    assert_eq!(parse_size("2 MB").unwrap(), 2_000_000);
+    assert!(matches!(
+        parse_size("lots"),
+        Err(rip2::Error::InvalidInput(_))
+    ));
+    assert!(parse_size("5 parsecs").is_err());
+}
+
+#[rstest]
+fn test_parse_duration() {
+    assert_eq!(parse_duration("90s").unwrap(), Duration::from_secs(90));
+    assert_eq!(parse_duration("1h").unwrap(), Duration::from_secs(3600));
+    assert_eq!(
+        parse_duration("30d").unwrap(),
+        Duration::from_secs(30 * 86400)
+    );
+    assert_eq!(
+        parse_duration("2w").unwrap(),
+        Duration::from_secs(14 * 86400)
+    );
+    assert!(matches!(
+        parse_duration("soon"),
+        Err(rip2::Error::InvalidInput(_))
+    ));
+    assert!(parse_duration("3y").is_err());
+    assert!(matches!(
+        parse_duration("99999999999999999w"),
+        Err(rip2::Error::InvalidInput(_))
+    ));
+}
+
+#[rstest]
+fn test_split_absolute() {
+    let graveyard = env::temp_dir().join("graveyard");
+    let path = dunce::canonicalize(env::temp_dir())
+        .unwrap()
+        .join("dir/file.txt");
+    let grave = join_absolute(&graveyard, &path);
+    assert_eq!(split_absolute(&graveyard, &grave), Some(path));
+    assert_eq!(split_absolute(&graveyard, env::temp_dir()), None);
+}
+
+#[rstest]
+fn test_rm_parse() {
+    assert_eq!(
+        rm::parse(["-rf", "dir", "--", "-file"]).unwrap(),
+        RmArgs {
+            targets: vec![PathBuf::from("dir"), PathBuf::from("-file")],
+            force: true,
+            recursive: true,
+            ..RmArgs::default()
+        }
+    );
+    // The last of -f, -i and -I wins
+    let rm_args = rm::parse(["-f", "-i", "file"]).unwrap();
+    assert!(!rm_args.force);
+    assert_eq!(rm_args.interactive, Interactive::Always);
+    let rm_args = rm::parse(["-i", "--force", "file"]).unwrap();
+    assert!(rm_args.force);
+    assert_eq!(rm_args.interactive, Interactive::Never);
+    assert_eq!(
+        rm::parse(["--interactive=once", "file"])
+            .unwrap()
+            .interactive,
+        Interactive::Once
+    );
+    assert_eq!(rm::parse(["-I"]).unwrap().interactive, Interactive::Once);
+
+    let rm_args = rm::parse([
+        "-Rv",
+        "--preserve-root",
+        "--one-file-system",
+        "--force-protected",
+        "-",
+    ])
+    .unwrap();
+    assert!(rm_args.recursive && rm_args.verbose && rm_args.one_file_system);
+    assert!(rm_args.force_protected);
+    assert_eq!(rm_args.targets, vec![PathBuf::from("-")]);
+
+    for bad in ["-z", "--no-preserve-root", "--interactive=sometimes"] {
+        assert!(matches!(
+            rm::parse([bad]),
+            Err(rip2::Error::InvalidInput(_))
+        ));
+    }
+}
+
+#[rstest]
+#[case(&["rm", "file"], true)]
+#[case(&["/usr/bin/rm", "-rf", "dir"], true)]
+#[case(&["rip", "--rm-compat", "-rf", "dir"], true)]
+#[case(&["rip", "--", "--rm-compat"], false)]
+#[case(&["rip", "file"], false)]
+fn test_rm_requested(#[case] argv: &[&str], #[case] expected: bool) {
+    let argv: Vec<std::ffi::OsString> = argv.iter().map(Into::into).collect();
+    assert_eq!(rm::requested(&argv), expected);
+}
+
+#[rstest]
+fn test_read_targets() {
+    assert_eq!(
+        rip2::select::read_targets(Cursor::new("a\r\n\nb c\n"), false).unwrap(),
+        vec![PathBuf::from("a"), PathBuf::from("b c")]
+    );
+    assert_eq!(
+        rip2::select::read_targets(Cursor::new("a\nb\0\0c"), true).unwrap(),
+        vec![PathBuf::from("a\nb"), PathBuf::from("c")]
+    );
+    let cli = Args {
+        null: true,
+        ..Args::default()
+    };
+    assert!(validate_args(&cli).is_err());
+}
+
+#[rstest]
+fn test_progress_renderer(#[values(false, true)] shown: bool) {
+    let mut progress = Vec::new();
+    let mut events = Vec::new();
+    let copied = |name: &str| rip2::Event::Copied {
+        from: PathBuf::from(name),
+        to: PathBuf::from("grave").join(name),
+        size: 1024,
+    };
+    {
+        let mut renderer = rip2::ProgressRenderer::new(
+            |event: &rip2::Event| events.push(event.clone()),
+            shown.then_some(&mut progress),
+        );
+        let started = rip2::Event::CopyStarted {
+            from: PathBuf::from("dir"),
+            to: PathBuf::from("grave/dir"),
+            files: Some(2),
+            bytes: Some(2048),
+        };
+        let copying = rip2::Event::Copying {
+            from: PathBuf::from("dir/b"),
+            to: PathBuf::from("grave/dir/b"),
+            bytes: 512,
+        };
+        assert_eq!(renderer.shows_progress(), shown);
+        renderer.notify(&started).unwrap();
+        renderer.notify(&copied("dir/a")).unwrap();
+        // Let the line be redrawn
+        std::thread::sleep(Duration::from_millis(150));
+        renderer.notify(&copying).unwrap();
+        renderer.notify(&copied("dir/b")).unwrap();
+    }
+    // Every event is passed on, whether progress is shown or not
+    assert_eq!(events.len(), 4);
+
+    let progress = String::from_utf8(progress).unwrap();
+    if shown {
+        assert!(progress.contains("Copying 1/2 files, 1.0 KiB/2.0 KiB"));
+        // Files still being copied count with what's done of them
+        assert!(progress.contains("Copying 1/2 files, 1.5 KiB/2.0 KiB"));
+        // The line is erased once the copy is done
+        assert!(progress.ends_with("\r\x1b[K"));
+    } else {
+        assert!(progress.is_empty());
+    }
+}